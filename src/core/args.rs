@@ -1,5 +1,9 @@
+use std::ptr::addr_of_mut;
+
 use wasi::{Errno, Size};
 
+use crate::core::mem::Strings;
+
 // Types
 
 pub type ArgsGetFn = fn(argv: *mut *mut u8, argv_buf: *mut u8) -> Errno;
@@ -31,24 +35,45 @@ pub mod set {
     }
 }
 
+// Backends
+
+static mut STRINGS: Strings = Strings::Static(Vec::new());
+
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn set_static(args: &[&str]) {
+    install(Strings::Static(
+        args.iter().map(|s| s.to_string()).collect(),
+    ));
+}
+
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn set_dynamic(f: impl Fn() -> Vec<String> + 'static) {
+    install(Strings::Dynamic(Box::new(f), None));
+}
+
+unsafe fn install(ss: Strings) {
+    STRINGS = ss;
+
+    set::args_get(strings_get);
+    set::args_sizes_get(strings_sizes_get);
+}
+
+fn strings_get(argv: *mut *mut u8, argv_buf: *mut u8) -> Errno {
+    unsafe { (*addr_of_mut!(STRINGS)).get(argv, argv_buf) }
+}
+
+fn strings_sizes_get(rp0: *mut Size, rp1: *mut Size) -> Errno {
+    unsafe { (*addr_of_mut!(STRINGS)).sizes_get(rp0, rp1) }
+}
+
 // Shims
 
 pub mod shims {
     use super::*;
+    use crate::core::layer::shims;
 
-    #[no_mangle]
-    unsafe extern "C" fn __shim_args_get(argv: *mut *mut u8, argv_buf: *mut u8) -> Errno {
-        match POLYFILLS.get {
-            Some(f) => f(argv, argv_buf),
-            None => unimplemented!("args_get"),
-        }
-    }
-
-    #[no_mangle]
-    unsafe extern "C" fn __shim_args_sizes_get(rp0: *mut Size, rp1: *mut Size) -> Errno {
-        match POLYFILLS.sizes_get {
-            Some(f) => f(rp0, rp1),
-            None => unimplemented!("args_sizes_get"),
-        }
+    shims! {
+        __shim_args_get => ArgsGet { argv: *mut *mut u8, argv_buf: *mut u8 } => get;
+        __shim_args_sizes_get => ArgsSizesGet { rp0: *mut Size, rp1: *mut Size } => sizes_get;
     }
 }
@@ -1,5 +1,9 @@
+use std::ptr::addr_of_mut;
+
 use wasi::{Clockid, Errno, Timestamp};
 
+use crate::core::mem;
+
 // Types
 
 pub type ClockResGetFn = fn(id: Clockid, rp0: *mut Timestamp) -> Errno;
@@ -31,28 +35,270 @@ pub mod set {
     }
 }
 
+// Backends
+
+pub type HostTimeFn = fn(id: Clockid) -> Timestamp;
+
+enum Mode {
+    Fixed,
+    Manual,
+    AutoIncrement(Timestamp),
+    Host(HostTimeFn),
+}
+
+#[derive(Clone, Copy)]
+pub enum Cputime {
+    // Time elapsed on the monotonic clock since the backend was set
+    Emulated,
+    Inval,
+}
+
+struct VirtualClock {
+    mode: Mode,
+    now: Timestamp,
+    start: Timestamp,
+    last_monotonic: Timestamp,
+    cputime: Cputime,
+}
+
+impl VirtualClock {
+    fn new(mode: Mode, now: Timestamp) -> Self {
+        let mut c = Self {
+            mode,
+            now,
+            start: 0,
+            last_monotonic: 0,
+            cputime: Cputime::Emulated,
+        };
+        c.start = c.monotonic();
+
+        c
+    }
+
+    fn monotonic(&mut self) -> Timestamp {
+        let t = match self.mode {
+            Mode::Host(f) => f(wasi::CLOCKID_MONOTONIC),
+            _ => self.now,
+        };
+
+        // Host clocks are not trusted to never go backwards
+        self.last_monotonic = self.last_monotonic.max(t);
+        self.last_monotonic
+    }
+
+    fn time_get(&mut self, id: Clockid) -> Result<Timestamp, Errno> {
+        let t = match id {
+            wasi::CLOCKID_REALTIME => match self.mode {
+                Mode::Host(f) => f(wasi::CLOCKID_REALTIME),
+                _ => self.now,
+            },
+            wasi::CLOCKID_MONOTONIC => self.monotonic(),
+            wasi::CLOCKID_PROCESS_CPUTIME_ID | wasi::CLOCKID_THREAD_CPUTIME_ID => {
+                match self.cputime {
+                    Cputime::Emulated => self.monotonic() - self.start,
+                    Cputime::Inval => return Err(wasi::ERRNO_INVAL),
+                }
+            }
+            _ => return Err(wasi::ERRNO_INVAL),
+        };
+
+        if let Mode::AutoIncrement(step) = self.mode {
+            self.now = self.now.saturating_add(step);
+        }
+
+        Ok(t)
+    }
+
+    fn advance(&mut self, delta: Timestamp) {
+        if let Mode::Manual | Mode::AutoIncrement(_) = self.mode {
+            self.now = self.now.saturating_add(delta);
+        }
+    }
+
+    fn res_get(&self, id: Clockid) -> Result<Timestamp, Errno> {
+        match id {
+            wasi::CLOCKID_REALTIME | wasi::CLOCKID_MONOTONIC => Ok(1),
+            wasi::CLOCKID_PROCESS_CPUTIME_ID | wasi::CLOCKID_THREAD_CPUTIME_ID => {
+                match self.cputime {
+                    Cputime::Emulated => Ok(1),
+                    Cputime::Inval => Err(wasi::ERRNO_INVAL),
+                }
+            }
+            _ => Err(wasi::ERRNO_INVAL),
+        }
+    }
+}
+
+static mut CLOCK: Option<VirtualClock> = None;
+static mut CPUTIME: Cputime = Cputime::Emulated;
+
+/// Every clock reads `t` forever.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn set_fixed(t: Timestamp) {
+    install(VirtualClock::new(Mode::Fixed, t));
+}
+
+/// Every clock reads `start` until moved on with [`advance`].
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn set_manual(start: Timestamp) {
+    install(VirtualClock::new(Mode::Manual, start));
+}
+
+/// Every clock starts at `start` and moves on by `step` each time it is read.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn set_auto_increment(start: Timestamp, step: Timestamp) {
+    install(VirtualClock::new(Mode::AutoIncrement(step), start));
+}
+
+/// Clocks are read from the host, with the monotonic clock held back whenever the host reports
+/// an earlier time than it did before.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn set_host(f: HostTimeFn) {
+    install(VirtualClock::new(Mode::Host(f), 0));
+}
+
+/// How the CPU-time clocks read, with the current backend and any set after it.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn set_cputime(cputime: Cputime) {
+    CPUTIME = cputime;
+    if let Some(c) = &mut *addr_of_mut!(CLOCK) {
+        c.cputime = cputime;
+    }
+}
+
+/// Moves the virtual time on by `delta`. Has no effect on fixed or host time.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn advance(delta: Timestamp) {
+    if let Some(c) = &mut *addr_of_mut!(CLOCK) {
+        c.advance(delta);
+    }
+}
+
+unsafe fn install(mut c: VirtualClock) {
+    c.cputime = CPUTIME;
+    CLOCK = Some(c);
+
+    set::clock_res_get(virtual_res_get);
+    set::clock_time_get(virtual_time_get);
+}
+
+fn virtual_res_get(id: Clockid, rp0: *mut Timestamp) -> Errno {
+    let c = unsafe { (*addr_of_mut!(CLOCK)).as_mut().unwrap() };
+
+    mem::errno(c.res_get(id).map(|t| unsafe { *rp0 = t }))
+}
+
+fn virtual_time_get(id: Clockid, _precision: Timestamp, rp0: *mut Timestamp) -> Errno {
+    let c = unsafe { (*addr_of_mut!(CLOCK)).as_mut().unwrap() };
+
+    mem::errno(c.time_get(id).map(|t| unsafe { *rp0 = t }))
+}
+
+// Helpers
+
+pub(crate) fn now(id: Clockid) -> Result<Timestamp, Errno> {
+    let mut t = 0;
+    match unsafe { POLYFILLS.time_get } {
+        Some(f) => match f(id, 1, &mut t) {
+            wasi::ERRNO_SUCCESS => Ok(t),
+            err => Err(err),
+        },
+        None => Err(wasi::ERRNO_NOSYS),
+    }
+}
+
+pub(crate) fn realtime() -> Timestamp {
+    now(wasi::CLOCKID_REALTIME).unwrap_or(0)
+}
+
 // Shims
 
 pub mod shims {
     use super::*;
+    use crate::core::layer::shims;
+
+    shims! {
+        __shim_clock_res_get => ClockResGet { id: Clockid, rp0: *mut Timestamp } => res_get;
+        __shim_clock_time_get => ClockTimeGet {
+            id: Clockid,
+            precision: Timestamp,
+            rp0: *mut Timestamp,
+        } => time_get;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::core::testing;
+
+    #[test]
+    fn test_virtual_modes() -> Result<(), Errno> {
+        let mut c = VirtualClock::new(Mode::Fixed, 5);
+        assert_eq!(c.time_get(wasi::CLOCKID_REALTIME)?, 5);
+        assert_eq!(c.time_get(wasi::CLOCKID_MONOTONIC)?, 5);
+
+        let mut c = VirtualClock::new(Mode::AutoIncrement(10), 100);
+        assert_eq!(c.time_get(wasi::CLOCKID_MONOTONIC)?, 100);
+        assert_eq!(c.time_get(wasi::CLOCKID_MONOTONIC)?, 110);
+        assert_eq!(c.time_get(wasi::CLOCKID_PROCESS_CPUTIME_ID)?, 20);
+
+        c.cputime = Cputime::Inval;
+        assert_eq!(
+            c.time_get(wasi::CLOCKID_THREAD_CPUTIME_ID).err(),
+            Some(wasi::ERRNO_INVAL)
+        );
+        assert_eq!(
+            c.res_get(wasi::CLOCKID_PROCESS_CPUTIME_ID).err(),
+            Some(wasi::ERRNO_INVAL)
+        );
 
-    #[no_mangle]
-    unsafe extern "C" fn __shim_clock_res_get(id: Clockid, rp0: *mut Timestamp) -> Errno {
-        match POLYFILLS.res_get {
-            Some(f) => f(id, rp0),
-            None => unimplemented!("clock_res_get"),
+        Ok(())
+    }
+
+    #[test]
+    fn test_host_monotonic() -> Result<(), Errno> {
+        thread_local! {
+            static NOW: Cell<Timestamp> = const { Cell::new(50) };
         }
+
+        let mut c = VirtualClock::new(Mode::Host(|_| NOW.get()), 0);
+        assert_eq!(c.time_get(wasi::CLOCKID_MONOTONIC)?, 50);
+
+        NOW.set(40);
+        assert_eq!(c.time_get(wasi::CLOCKID_REALTIME)?, 40);
+        assert_eq!(c.time_get(wasi::CLOCKID_MONOTONIC)?, 50);
+
+        NOW.set(70);
+        assert_eq!(c.time_get(wasi::CLOCKID_MONOTONIC)?, 70);
+        assert_eq!(c.time_get(wasi::CLOCKID_PROCESS_CPUTIME_ID)?, 20);
+
+        Ok(())
     }
 
-    #[no_mangle]
-    unsafe extern "C" fn __shim_clock_time_get(
-        id: Clockid,
-        precision: Timestamp,
-        rp0: *mut Timestamp,
-    ) -> Errno {
-        match POLYFILLS.time_get {
-            Some(f) => f(id, precision, rp0),
-            None => unimplemented!("clock_time_get"),
+    #[test]
+    fn test_settings() {
+        let _lock = testing::LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        unsafe {
+            set_cputime(Cputime::Inval);
+            set_fixed(5);
+            advance(10);
+        }
+        assert_eq!(now(wasi::CLOCKID_MONOTONIC), Ok(5));
+        assert_eq!(
+            now(wasi::CLOCKID_PROCESS_CPUTIME_ID),
+            Err(wasi::ERRNO_INVAL)
+        );
+
+        unsafe {
+            set_cputime(Cputime::Emulated);
+            set_manual(5);
+            advance(10);
         }
+        assert_eq!(now(wasi::CLOCKID_MONOTONIC), Ok(15));
+        assert_eq!(now(wasi::CLOCKID_PROCESS_CPUTIME_ID), Ok(10));
     }
 }
@@ -0,0 +1,541 @@
+use std::mem::size_of;
+
+use wasi::{
+    Ciovec, Dircookie, Dirent, Errno, Fd, Fdflags, Filedelta, Filesize, Filestat, Iovec,
+    Lookupflags, Oflags, Rights, Size, Whence,
+};
+
+// Entry points
+//
+// Checks call the shims exactly as a guest would, so whatever polyfills and layers are
+// registered are what gets tested
+
+extern "C" {
+    fn __shim_fd_close(fd: Fd) -> Errno;
+    fn __shim_fd_filestat_get(fd: Fd, rp0: *mut Filestat) -> Errno;
+    fn __shim_fd_read(fd: Fd, iovs: *const Iovec, iovs_len: i32, rp0: *mut Size) -> Errno;
+    fn __shim_fd_readdir(
+        fd: Fd,
+        buf: *mut u8,
+        buf_len: Size,
+        cookie: Dircookie,
+        rp0: *mut Size,
+    ) -> Errno;
+    fn __shim_fd_seek(fd: Fd, offset: Filedelta, whence: Whence, rp0: *mut Filesize) -> Errno;
+    fn __shim_fd_write(fd: Fd, iovs: *const Ciovec, iovs_len: i32, rp0: *mut Size) -> Errno;
+    fn __shim_path_create_directory(fd: Fd, path: *const u8, path_len: i32) -> Errno;
+    fn __shim_path_filestat_get(
+        fd: Fd,
+        flags: Lookupflags,
+        path: *const u8,
+        path_len: i32,
+        rp0: *mut Filestat,
+    ) -> Errno;
+    fn __shim_path_open(
+        fd: Fd,
+        dirflags: Lookupflags,
+        path: *const u8,
+        path_len: i32,
+        oflags: Oflags,
+        fs_rights_base: Rights,
+        fs_rights_inheriting: Rights,
+        fdflags: Fdflags,
+        rp0: *mut Fd,
+    ) -> Errno;
+    fn __shim_path_remove_directory(fd: Fd, path: *const u8, path_len: i32) -> Errno;
+    fn __shim_path_rename(
+        fd: Fd,
+        old_path: *const u8,
+        old_path_len: i32,
+        new_fd: Fd,
+        new_path: *const u8,
+        new_path_len: i32,
+    ) -> Errno;
+    fn __shim_path_unlink_file(fd: Fd, path: *const u8, path_len: i32) -> Errno;
+}
+
+fn ok(ret: Errno) -> Result<(), Errno> {
+    match ret {
+        wasi::ERRNO_SUCCESS => Ok(()),
+        err => Err(err),
+    }
+}
+
+const RIGHTS_ALL: Rights = Rights::MAX;
+
+unsafe fn open(dir: Fd, path: &str, oflags: Oflags) -> Result<Fd, Errno> {
+    let mut fd = 0;
+    ok(__shim_path_open(
+        dir,
+        0,
+        path.as_ptr(),
+        path.len() as i32,
+        oflags,
+        RIGHTS_ALL,
+        RIGHTS_ALL,
+        0,
+        &mut fd,
+    ))?;
+
+    Ok(fd)
+}
+
+unsafe fn close(fd: Fd) -> Result<(), Errno> {
+    ok(__shim_fd_close(fd))
+}
+
+unsafe fn read(fd: Fd, len: usize) -> Result<Vec<u8>, Errno> {
+    let mut buf = vec![0; len];
+    let iov = Iovec {
+        buf: buf.as_mut_ptr(),
+        buf_len: len,
+    };
+
+    let mut n = 0;
+    ok(__shim_fd_read(fd, &iov, 1, &mut n))?;
+    buf.truncate(n);
+
+    Ok(buf)
+}
+
+unsafe fn write(fd: Fd, data: &[u8]) -> Result<Size, Errno> {
+    let iov = Ciovec {
+        buf: data.as_ptr(),
+        buf_len: data.len(),
+    };
+
+    let mut n = 0;
+    ok(__shim_fd_write(fd, &iov, 1, &mut n))?;
+
+    Ok(n)
+}
+
+unsafe fn seek(fd: Fd, offset: Filedelta, whence: Whence) -> Result<Filesize, Errno> {
+    let mut pos = 0;
+    ok(__shim_fd_seek(fd, offset, whence, &mut pos))?;
+
+    Ok(pos)
+}
+
+unsafe fn fstat(fd: Fd) -> Result<Filestat, Errno> {
+    let mut stat = std::mem::zeroed();
+    ok(__shim_fd_filestat_get(fd, &mut stat))?;
+
+    Ok(stat)
+}
+
+unsafe fn stat(dir: Fd, path: &str) -> Result<Filestat, Errno> {
+    let mut stat = std::mem::zeroed();
+    ok(__shim_path_filestat_get(
+        dir,
+        0,
+        path.as_ptr(),
+        path.len() as i32,
+        &mut stat,
+    ))?;
+
+    Ok(stat)
+}
+
+unsafe fn mkdir(dir: Fd, path: &str) -> Result<(), Errno> {
+    ok(__shim_path_create_directory(
+        dir,
+        path.as_ptr(),
+        path.len() as i32,
+    ))
+}
+
+unsafe fn rmdir(dir: Fd, path: &str) -> Result<(), Errno> {
+    ok(__shim_path_remove_directory(
+        dir,
+        path.as_ptr(),
+        path.len() as i32,
+    ))
+}
+
+unsafe fn unlink(dir: Fd, path: &str) -> Result<(), Errno> {
+    ok(__shim_path_unlink_file(
+        dir,
+        path.as_ptr(),
+        path.len() as i32,
+    ))
+}
+
+unsafe fn rename(dir: Fd, old: &str, new: &str) -> Result<(), Errno> {
+    ok(__shim_path_rename(
+        dir,
+        old.as_ptr(),
+        old.len() as i32,
+        dir,
+        new.as_ptr(),
+        new.len() as i32,
+    ))
+}
+
+// Entries as (cookie of the next entry, name), read `buf_len` bytes at a time the way
+// wasi-libc does, restarting from the last entry that came back whole
+unsafe fn readdir(
+    fd: Fd,
+    cookie: Dircookie,
+    buf_len: Size,
+) -> Result<Vec<(Dircookie, String)>, Errno> {
+    let mut entries = vec![];
+    let mut cookie = cookie;
+    let mut buf = vec![0u8; buf_len];
+
+    loop {
+        let mut n = 0;
+        ok(__shim_fd_readdir(
+            fd,
+            buf.as_mut_ptr(),
+            buf_len,
+            cookie,
+            &mut n,
+        ))?;
+
+        let mut pos = 0;
+        let mut whole = 0;
+        while pos + size_of::<Dirent>() <= n {
+            let d: Dirent = std::ptr::read_unaligned(buf.as_ptr().add(pos) as *const Dirent);
+            let end = pos + size_of::<Dirent>() + d.d_namlen as usize;
+            if end > n {
+                break;
+            }
+
+            let name = &buf[pos + size_of::<Dirent>()..end];
+            entries.push((d.d_next, String::from_utf8_lossy(name).into_owned()));
+            cookie = d.d_next;
+            pos = end;
+            whole += 1;
+        }
+
+        if n < buf_len {
+            return Ok(entries);
+        }
+        if whole == 0 {
+            return Err(wasi::ERRNO_NOBUFS);
+        }
+    }
+}
+
+// Checks
+
+macro_rules! check {
+    ($cond:expr, $($msg:tt)+) => {
+        if !$cond {
+            return Err(format!($($msg)+));
+        }
+    };
+}
+
+macro_rules! expect_errno {
+    ($call:expr, $errno:expr) => {
+        match $call {
+            Err(err) if err == $errno => {}
+            other => {
+                return Err(format!(
+                    "{}: expected {}, got {:?}",
+                    stringify!($call),
+                    $errno.name(),
+                    other.map(|_| ())
+                ))
+            }
+        }
+    };
+}
+
+fn errno(err: Errno) -> String {
+    format!("unexpected {}", err.name())
+}
+
+type CheckFn = unsafe fn(dir: Fd) -> Result<(), String>;
+
+unsafe fn missing_paths(dir: Fd) -> Result<(), String> {
+    expect_errno!(open(dir, "missing", 0), wasi::ERRNO_NOENT);
+    expect_errno!(stat(dir, "missing"), wasi::ERRNO_NOENT);
+    expect_errno!(unlink(dir, "missing"), wasi::ERRNO_NOENT);
+    expect_errno!(rmdir(dir, "missing"), wasi::ERRNO_NOENT);
+    expect_errno!(rename(dir, "missing", "other"), wasi::ERRNO_NOENT);
+    expect_errno!(
+        open(dir, "missing/file", wasi::OFLAGS_CREAT),
+        wasi::ERRNO_NOENT
+    );
+
+    Ok(())
+}
+
+unsafe fn exclusive_create(dir: Fd) -> Result<(), String> {
+    let excl = wasi::OFLAGS_CREAT | wasi::OFLAGS_EXCL;
+
+    close(open(dir, "file", excl).map_err(errno)?).map_err(errno)?;
+    expect_errno!(open(dir, "file", excl), wasi::ERRNO_EXIST);
+    close(open(dir, "file", wasi::OFLAGS_CREAT).map_err(errno)?).map_err(errno)?;
+
+    mkdir(dir, "dir").map_err(errno)?;
+    expect_errno!(open(dir, "dir", excl), wasi::ERRNO_EXIST);
+    expect_errno!(mkdir(dir, "dir"), wasi::ERRNO_EXIST);
+
+    Ok(())
+}
+
+unsafe fn rename_over_existing(dir: Fd) -> Result<(), String> {
+    for (name, data) in [("a", b"from a"), ("b", b"from b")] {
+        let fd = open(dir, name, wasi::OFLAGS_CREAT).map_err(errno)?;
+        write(fd, data).map_err(errno)?;
+        close(fd).map_err(errno)?;
+    }
+
+    rename(dir, "a", "b").map_err(errno)?;
+    expect_errno!(stat(dir, "a"), wasi::ERRNO_NOENT);
+
+    let fd = open(dir, "b", 0).map_err(errno)?;
+    let data = read(fd, 16).map_err(errno)?;
+    close(fd).map_err(errno)?;
+    check!(data == b"from a", "b holds {:?} after the rename", data);
+
+    Ok(())
+}
+
+unsafe fn seek_past_eof(dir: Fd) -> Result<(), String> {
+    let fd = open(dir, "file", wasi::OFLAGS_CREAT).map_err(errno)?;
+    write(fd, b"abc").map_err(errno)?;
+
+    let pos = seek(fd, 10, wasi::WHENCE_SET).map_err(errno)?;
+    check!(pos == 10, "seek to 10 landed at {pos}");
+    check!(
+        read(fd, 4).map_err(errno)?.is_empty(),
+        "read past the end returned data"
+    );
+
+    write(fd, b"x").map_err(errno)?;
+    let size = fstat(fd).map_err(errno)?.size;
+    check!(size == 11, "size is {size} after writing at 10");
+
+    seek(fd, 0, wasi::WHENCE_SET).map_err(errno)?;
+    let data = read(fd, 16).map_err(errno)?;
+    check!(
+        data == b"abc\0\0\0\0\0\0\0x",
+        "the gap reads back as {:?}",
+        data
+    );
+
+    expect_errno!(seek(fd, -1, wasi::WHENCE_SET), wasi::ERRNO_INVAL);
+    close(fd).map_err(errno)?;
+
+    Ok(())
+}
+
+unsafe fn unlink_open_file(dir: Fd) -> Result<(), String> {
+    let fd = open(dir, "file", wasi::OFLAGS_CREAT).map_err(errno)?;
+    write(fd, b"still here").map_err(errno)?;
+
+    unlink(dir, "file").map_err(errno)?;
+    expect_errno!(stat(dir, "file"), wasi::ERRNO_NOENT);
+    expect_errno!(open(dir, "file", 0), wasi::ERRNO_NOENT);
+
+    seek(fd, 0, wasi::WHENCE_SET).map_err(errno)?;
+    let data = read(fd, 16).map_err(errno)?;
+    check!(data == b"still here", "the open file reads {:?}", data);
+    close(fd).map_err(errno)?;
+
+    mkdir(dir, "dir").map_err(errno)?;
+    expect_errno!(unlink(dir, "dir"), wasi::ERRNO_ISDIR);
+
+    Ok(())
+}
+
+// Uses the file `seed` when the backend has put one in place, e.g. in a read-only lower layer
+unsafe fn write_moved_open_file(dir: Fd) -> Result<(), String> {
+    let fd = open(dir, "seed", wasi::OFLAGS_CREAT).map_err(errno)?;
+    if fstat(fd).map_err(errno)?.size == 0 {
+        write(fd, b"seed").map_err(errno)?;
+        seek(fd, 0, wasi::WHENCE_SET).map_err(errno)?;
+    }
+
+    rename(dir, "seed", "moved").map_err(errno)?;
+    write(fd, b"S").map_err(errno)?;
+    let moved = open(dir, "moved", 0).map_err(errno)?;
+    let data = read(moved, 16).map_err(errno)?;
+    close(moved).map_err(errno)?;
+    check!(data == b"Seed", "the renamed file reads {:?}", data);
+
+    unlink(dir, "moved").map_err(errno)?;
+    write(fd, b"E").map_err(errno)?;
+    seek(fd, 0, wasi::WHENCE_SET).map_err(errno)?;
+    let data = read(fd, 16).map_err(errno)?;
+    check!(data == b"SEed", "the unlinked file reads {:?}", data);
+    close(fd).map_err(errno)?;
+
+    Ok(())
+}
+
+unsafe fn readdir_cookies(dir: Fd) -> Result<(), String> {
+    for name in ["e", "d", "c", "b", "a"] {
+        close(open(dir, name, wasi::OFLAGS_CREAT).map_err(errno)?).map_err(errno)?;
+    }
+
+    let fd = open(dir, ".", wasi::OFLAGS_DIRECTORY).map_err(errno)?;
+    let all = readdir(fd, 0, 4096).map_err(errno)?;
+
+    let mut names: Vec<_> = all.iter().map(|(_, n)| n.as_str()).collect();
+    names.sort();
+    check!(
+        names == [".", "..", "a", "b", "c", "d", "e"],
+        "listed {:?}",
+        names
+    );
+
+    // A buffer too small for the whole listing must give the same entries
+    let small = readdir(fd, 0, 40).map_err(errno)?;
+    check!(
+        small == all,
+        "reading 40 bytes at a time listed {:?}",
+        small
+    );
+
+    // Resuming from any cookie lists the entries after it
+    for (i, (cookie, _)) in all.iter().enumerate() {
+        let rest = readdir(fd, *cookie, 4096).map_err(errno)?;
+        check!(
+            rest == all[i + 1..],
+            "resuming after entry {i} listed {:?}",
+            rest
+        );
+    }
+
+    // Removing an entry must not disturb the cookies of the others
+    let (cookie, _) = all[all.len() - 3].clone();
+    let last = all[all.len() - 1].1.clone();
+    let removed = all[all.len() - 2].1.clone();
+    unlink(dir, &removed).map_err(errno)?;
+
+    let rest = readdir(fd, cookie, 4096).map_err(errno)?;
+    let rest: Vec<_> = rest.into_iter().map(|(_, n)| n).collect();
+    check!(
+        rest == [last.clone()],
+        "after removing {removed}, resuming listed {:?}",
+        rest
+    );
+    close(fd).map_err(errno)?;
+
+    Ok(())
+}
+
+pub const CHECKS: &[(&str, CheckFn)] = &[
+    ("missing_paths", missing_paths),
+    ("exclusive_create", exclusive_create),
+    ("rename_over_existing", rename_over_existing),
+    ("seek_past_eof", seek_past_eof),
+    ("unlink_open_file", unlink_open_file),
+    ("write_moved_open_file", write_moved_open_file),
+    ("readdir_cookies", readdir_cookies),
+];
+
+// Runner
+
+#[derive(Debug)]
+pub struct Failure {
+    pub check: &'static str,
+    pub message: String,
+}
+
+/// Runs every check against the directory `dir`, each in a fresh subdirectory that is
+/// removed again where possible, and returns the checks that failed. A subdirectory that
+/// already exists is used as it is, so a backend can seed one with files to work on.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn run(dir: Fd) -> Vec<Failure> {
+    let mut failures = vec![];
+
+    for (check, f) in CHECKS {
+        let scratch = format!("conformance-{check}");
+
+        let result = match mkdir(dir, &scratch) {
+            Ok(()) | Err(wasi::ERRNO_EXIST) => Ok(()),
+            Err(err) => Err(format!("creating {scratch}: {}", err.name())),
+        }
+        .and_then(|_| open(dir, &scratch, wasi::OFLAGS_DIRECTORY).map_err(errno))
+        .and_then(|fd| {
+            let r = f(fd);
+            let _ = close(fd);
+            r
+        });
+
+        if let Err(message) = result {
+            failures.push(Failure { check, message });
+        }
+        remove_all(dir, &scratch);
+    }
+
+    failures
+}
+
+unsafe fn remove_all(dir: Fd, path: &str) {
+    if let Ok(fd) = open(dir, path, wasi::OFLAGS_DIRECTORY) {
+        for (_, name) in readdir(fd, 0, 4096).unwrap_or_default() {
+            if name != "." && name != ".." {
+                let _ = unlink(fd, &name);
+                remove_all(fd, &name);
+            }
+        }
+        let _ = close(fd);
+    }
+
+    let _ = rmdir(dir, path);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{memfs::MemFs, preopens, testing};
+
+    #[test]
+    fn test_memfs() {
+        let _lock = testing::LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        unsafe {
+            preopens::install();
+            let fd = preopens::register("/", MemFs::new().root());
+
+            let failures = run(fd);
+            assert!(failures.is_empty(), "{failures:?}");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hostfs() {
+        let _lock = testing::LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        let root =
+            std::env::temp_dir().join(format!("wasi-shim-conformance-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+
+        unsafe {
+            preopens::install();
+            let fd = preopens::register("/", crate::core::hostfs::HostDir::new(&root).unwrap());
+
+            let failures = run(fd);
+            let _ = std::fs::remove_dir_all(&root);
+            assert!(failures.is_empty(), "{failures:?}");
+        }
+    }
+
+    #[test]
+    fn test_overlay() {
+        let _lock = testing::LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        let lower = crate::core::embedded::EmbeddedFs::new(&[
+            ("base", Some(b"base")),
+            ("conformance-write_moved_open_file/seed", Some(b"seed")),
+        ]);
+        let fs = crate::core::overlay::OverlayFs::new(lower.root());
+
+        unsafe {
+            preopens::install();
+            let fd = preopens::register("/", fs.root());
+
+            let failures = run(fd);
+            assert!(failures.is_empty(), "{failures:?}");
+        }
+    }
+}
@@ -0,0 +1,190 @@
+use std::ptr::addr_of;
+
+use wasi::{Errno, Exitcode, Fd, Fdstat, Iovec, Size};
+
+use crate::core::{args, environ, fd, mem, proc, random, sched};
+
+pub use crate::core::random::EntropyFn;
+
+// Types
+
+pub type SinkFn = fn(fd: Fd, buf: &[u8]);
+
+// State
+
+struct Defaults {
+    sink: Option<SinkFn>,
+    entropy: Option<EntropyFn>,
+}
+
+static mut DEFAULTS: Defaults = Defaults {
+    sink: None,
+    entropy: None,
+};
+
+// Install
+
+/// Registers a minimal set of polyfills: empty args and environment, stdout
+/// and stderr routed to `sink`, randomness drawn from `entropy`, a no-op
+/// `sched_yield` and a `proc_exit` that traps with the exit code.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn install(sink: SinkFn, entropy: EntropyFn) {
+    DEFAULTS.sink = Some(sink);
+    DEFAULTS.entropy = Some(entropy);
+
+    args::set_static(&[]);
+    environ::set_static(&[]);
+    fd::set::fd_write(fd_write);
+    fd::set::fd_fdstat_get(fd_fdstat_get);
+    random::set::random_get(random_get);
+    sched::set::sched_yield(sched_yield);
+    proc::set::proc_exit(proc_exit);
+}
+
+// Polyfills
+
+fn fd_write(fd: Fd, iovs: *const Iovec, iovs_len: i32, rp0: *mut Size) -> Errno {
+    let sink = match (fd, unsafe { (*addr_of!(DEFAULTS)).sink }) {
+        (1 | 2, Some(sink)) => sink,
+        _ => return wasi::ERRNO_BADF,
+    };
+
+    let mut n = 0;
+    for buf in unsafe { mem::bufs(iovs, iovs_len) } {
+        sink(fd, buf);
+        n += buf.len();
+    }
+
+    unsafe { *rp0 = n };
+    wasi::ERRNO_SUCCESS
+}
+
+fn fd_fdstat_get(fd: Fd, rp0: *mut Fdstat) -> Errno {
+    let rights = match fd {
+        0 => wasi::RIGHTS_FD_READ,
+        1 | 2 => wasi::RIGHTS_FD_WRITE,
+        _ => return wasi::ERRNO_BADF,
+    };
+
+    unsafe {
+        *rp0 = Fdstat {
+            fs_filetype: wasi::FILETYPE_CHARACTER_DEVICE,
+            fs_flags: 0,
+            fs_rights_base: rights,
+            fs_rights_inheriting: 0,
+        };
+    }
+    wasi::ERRNO_SUCCESS
+}
+
+fn random_get(buf: *mut u8, buf_len: Size) -> Errno {
+    match unsafe { (*addr_of!(DEFAULTS)).entropy } {
+        Some(entropy) => {
+            entropy(unsafe { mem::bytes_mut(buf, buf_len) });
+            wasi::ERRNO_SUCCESS
+        }
+        None => wasi::ERRNO_NOSYS,
+    }
+}
+
+fn sched_yield() -> Errno {
+    wasi::ERRNO_SUCCESS
+}
+
+fn proc_exit(rval: Exitcode) -> ! {
+    panic!("proc_exit({rval})")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use wasi::Ciovec;
+
+    use super::*;
+    use crate::core::testing;
+
+    extern "C" {
+        fn __shim_args_sizes_get(rp0: *mut Size, rp1: *mut Size) -> Errno;
+        fn __shim_environ_sizes_get(rp0: *mut Size, rp1: *mut Size) -> Errno;
+        fn __shim_fd_fdstat_get(fd: Fd, rp0: *mut Fdstat) -> Errno;
+        fn __shim_fd_write(fd: Fd, iovs: *const Ciovec, iovs_len: i32, rp0: *mut Size) -> Errno;
+        fn __shim_random_get(buf: *mut u8, buf_len: Size) -> Errno;
+    }
+
+    thread_local! {
+        static WRITTEN: RefCell<Vec<(Fd, Vec<u8>)>> = const { RefCell::new(vec![]) };
+    }
+
+    fn sink(fd: Fd, buf: &[u8]) {
+        WRITTEN.with_borrow_mut(|w| w.push((fd, buf.to_vec())));
+    }
+
+    fn entropy(buf: &mut [u8]) {
+        buf.fill(7);
+    }
+
+    unsafe fn write(fd: Fd, buf: &[u8]) -> Errno {
+        let iov = Ciovec {
+            buf: buf.as_ptr(),
+            buf_len: buf.len(),
+        };
+        let mut n = 0;
+
+        __shim_fd_write(fd, &iov, 1, &mut n)
+    }
+
+    unsafe fn filetype(fd: Fd) -> Result<u8, Errno> {
+        let mut stat = Fdstat {
+            fs_filetype: wasi::FILETYPE_UNKNOWN,
+            fs_flags: 0,
+            fs_rights_base: 0,
+            fs_rights_inheriting: 0,
+        };
+
+        match __shim_fd_fdstat_get(fd, &mut stat) {
+            wasi::ERRNO_SUCCESS => Ok(stat.fs_filetype.raw()),
+            err => Err(err),
+        }
+    }
+
+    #[test]
+    fn test_install() {
+        let _polyfills = testing::Polyfills::lock();
+
+        unsafe {
+            install(sink, entropy);
+
+            let (mut count, mut size) = (1, 1);
+            assert_eq!(
+                __shim_args_sizes_get(&mut count, &mut size),
+                wasi::ERRNO_SUCCESS
+            );
+            assert_eq!((count, size), (0, 0));
+            let (mut count, mut size) = (1, 1);
+            assert_eq!(
+                __shim_environ_sizes_get(&mut count, &mut size),
+                wasi::ERRNO_SUCCESS
+            );
+            assert_eq!((count, size), (0, 0));
+
+            assert_eq!(write(1, b"out"), wasi::ERRNO_SUCCESS);
+            assert_eq!(write(2, b"err"), wasi::ERRNO_SUCCESS);
+            assert_eq!(write(3, b"x"), wasi::ERRNO_BADF);
+
+            let mut buf = [0; 4];
+            assert_eq!(
+                __shim_random_get(buf.as_mut_ptr(), buf.len()),
+                wasi::ERRNO_SUCCESS
+            );
+            assert_eq!(buf, [7; 4]);
+
+            let character = wasi::FILETYPE_CHARACTER_DEVICE.raw();
+            assert_eq!(filetype(0), Ok(character));
+            assert_eq!(filetype(2), Ok(character));
+            assert_eq!(filetype(3), Err(wasi::ERRNO_BADF));
+        }
+
+        assert_eq!(WRITTEN.take(), [(1, b"out".to_vec()), (2, b"err".to_vec())]);
+    }
+}
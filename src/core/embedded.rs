@@ -0,0 +1,617 @@
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    fs, hint, io,
+    path::{Path, PathBuf},
+    ptr,
+    rc::Rc,
+    slice, str,
+};
+
+use wasi::{
+    Dircookie, Errno, Fdflags, Filedelta, Filesize, Filestat, Filetype, Fstflags, Lookupflags,
+    Oflags, Rights, Size, Timestamp, Whence,
+};
+
+use crate::core::{
+    fd_table::{Direntry, Handle, HandleRef, Readiness},
+    preopens,
+};
+
+// Types
+
+/// A path relative to the embedded directory, with the file's contents or `None` for a
+/// directory. Parent directories need not be listed.
+pub type Entry = (&'static str, Option<&'static [u8]>);
+
+/// Bakes the directory generated by [`build`] for `path` into the module as an
+/// [`EmbeddedFs`]. The file contents end up in the data section.
+///
+/// ```ignore
+/// // build.rs
+/// wasi_shim::core::embedded::build("assets").unwrap();
+///
+/// // src/main.rs
+/// let assets = wasi_shim::embed_dir!("assets");
+/// ```
+#[macro_export]
+macro_rules! embed_dir {
+    ($path:literal) => {
+        $crate::core::embedded::EmbeddedFs::new(include!(concat!(
+            env!("OUT_DIR"),
+            "/",
+            $path,
+            ".embed.rs"
+        )))
+    };
+}
+
+// Build script
+
+/// Writes the listing of `path`, relative to the package root, for [`embed_dir!`] to include.
+/// Meant to be called from a build script.
+pub fn build(path: &str) -> io::Result<()> {
+    let root = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").map_err(io::Error::other)?);
+    let out = PathBuf::from(std::env::var("OUT_DIR").map_err(io::Error::other)?);
+
+    if Path::new(path).is_absolute() || path.split('/').any(|c| c == "..") {
+        return Err(io::ErrorKind::InvalidInput.into());
+    }
+
+    let out = out.join(format!("{path}.embed.rs"));
+    if let Some(parent) = out.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&out, generate(&root.join(path))?)?;
+
+    Ok(())
+}
+
+// The listing is a slice expression of `Entry`s, with every file pulled in by `include_bytes!`
+fn generate(dir: &Path) -> io::Result<String> {
+    let dir = dir.canonicalize()?;
+    let mut out = String::from("&[\n");
+
+    let mut pending = vec![(dir.clone(), String::new())];
+    while let Some((dir, prefix)) = pending.pop() {
+        println!("cargo:rerun-if-changed={}", dir.display());
+
+        let mut entries: Vec<_> = fs::read_dir(&dir)?.collect::<Result<_, _>>()?;
+        entries.sort_by_key(|e| e.file_name());
+
+        for e in entries {
+            let name = e
+                .file_name()
+                .into_string()
+                .map_err(|_| io::ErrorKind::InvalidData)?;
+            let path = e.path();
+            let rel = format!("{prefix}{name}");
+
+            if path.is_dir() {
+                out.push_str(&format!("    ({rel:?}, None),\n"));
+                pending.push((path, format!("{rel}/")));
+            } else {
+                let abs = path.canonicalize()?;
+                let abs = abs.to_str().ok_or(io::ErrorKind::InvalidData)?;
+
+                println!("cargo:rerun-if-changed={abs}");
+                out.push_str(&format!(
+                    "    ({rel:?}, Some(include_bytes!({abs:?}) as &[u8])),\n"
+                ));
+            }
+        }
+    }
+    out.push(']');
+
+    Ok(out)
+}
+
+// Nodes
+
+struct Node {
+    parent: usize,
+    kind: Kind,
+}
+
+enum Kind {
+    File(&'static [u8]),
+    Dir(BTreeMap<&'static str, usize>),
+}
+
+impl Node {
+    fn filetype(&self) -> Filetype {
+        match self.kind {
+            Kind::File(_) => wasi::FILETYPE_REGULAR_FILE,
+            Kind::Dir(_) => wasi::FILETYPE_DIRECTORY,
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        matches!(self.kind, Kind::Dir(_))
+    }
+}
+
+// Filesystem
+
+/// A read-only tree of files compiled into the module, usually made by [`embed_dir!`].
+#[derive(Clone)]
+pub struct EmbeddedFs {
+    nodes: Rc<Vec<Node>>,
+}
+
+impl EmbeddedFs {
+    pub fn new(entries: &[Entry]) -> Self {
+        let mut nodes = vec![Node {
+            parent: 0,
+            kind: Kind::Dir(BTreeMap::new()),
+        }];
+
+        for (path, data) in entries {
+            let names: Vec<&'static str> = path.split('/').filter(|c| !c.is_empty()).collect();
+
+            let mut cur = 0;
+            for (i, name) in names.iter().enumerate() {
+                let last = i == names.len() - 1;
+                let Kind::Dir(children) = &nodes[cur].kind else {
+                    break;
+                };
+
+                cur = match children.get(name) {
+                    Some(&next) => next,
+                    None => {
+                        let kind = match data {
+                            Some(data) if last => Kind::File(data),
+                            _ => Kind::Dir(BTreeMap::new()),
+                        };
+                        nodes.push(Node { parent: cur, kind });
+
+                        let next = nodes.len() - 1;
+                        if let Kind::Dir(children) = &mut nodes[cur].kind {
+                            children.insert(name, next);
+                        }
+                        next
+                    }
+                };
+            }
+        }
+
+        Self {
+            nodes: Rc::new(nodes),
+        }
+    }
+
+    pub fn root(&self) -> EmbeddedDir {
+        EmbeddedDir {
+            fs: self.clone(),
+            node: 0,
+        }
+    }
+
+    fn stat(&self, node: usize) -> Filestat {
+        Filestat {
+            dev: 0,
+            ino: node as u64 + 1,
+            filetype: self.nodes[node].filetype(),
+            nlink: 1,
+            size: match self.nodes[node].kind {
+                Kind::File(data) => data.len() as Filesize,
+                Kind::Dir(_) => 0,
+            },
+            atim: 0,
+            mtim: 0,
+            ctim: 0,
+        }
+    }
+
+    // Resolves `path` relative to `dir`, where ".." never leaves `dir`. There are no symlinks
+    fn walk(&self, dir: usize, path: &str) -> Result<usize, Errno> {
+        if path.starts_with('/') {
+            return Err(wasi::ERRNO_PERM);
+        }
+
+        let names: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+        if names.is_empty() {
+            return Err(wasi::ERRNO_NOENT);
+        }
+
+        let mut stack = vec![dir];
+        for name in names {
+            let Kind::Dir(children) = &self.nodes[*stack.last().unwrap()].kind else {
+                return Err(wasi::ERRNO_NOTDIR);
+            };
+
+            match name {
+                "." => {}
+                ".." if stack.len() == 1 => return Err(wasi::ERRNO_PERM),
+                ".." => {
+                    stack.pop();
+                }
+                _ => stack.push(*children.get(name).ok_or(wasi::ERRNO_NOENT)?),
+            }
+        }
+
+        let node = stack.pop().unwrap();
+        if path.ends_with('/') && !self.nodes[node].is_dir() {
+            return Err(wasi::ERRNO_NOTDIR);
+        }
+
+        Ok(node)
+    }
+
+    // Calls that would change the tree fail once the path has been checked
+    fn modify(&self, dir: usize, path: &str) -> Result<(), Errno> {
+        match self.walk(dir, path) {
+            Ok(_) | Err(wasi::ERRNO_NOENT) => Err(wasi::ERRNO_ROFS),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+// Handles
+
+struct EmbeddedFile {
+    data: &'static [u8],
+    stat: Filestat,
+    pos: Filesize,
+}
+
+impl Handle for EmbeddedFile {
+    fn filetype(&self) -> Filetype {
+        wasi::FILETYPE_REGULAR_FILE
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<Size, Errno> {
+        let n = self.pread(buf, self.pos)?;
+        self.pos += n as Filesize;
+        Ok(n)
+    }
+
+    fn write(&mut self, _buf: &[u8]) -> Result<Size, Errno> {
+        Err(wasi::ERRNO_ROFS)
+    }
+
+    fn pread(&mut self, buf: &mut [u8], offset: Filesize) -> Result<Size, Errno> {
+        let start = usize::try_from(offset)
+            .unwrap_or(usize::MAX)
+            .min(self.data.len());
+        let n = buf.len().min(self.data.len() - start);
+        buf[..n].copy_from_slice(&self.data[start..start + n]);
+
+        Ok(n)
+    }
+
+    fn pwrite(&mut self, _buf: &[u8], _offset: Filesize) -> Result<Size, Errno> {
+        Err(wasi::ERRNO_ROFS)
+    }
+
+    fn seek(&mut self, offset: Filedelta, whence: Whence) -> Result<Filesize, Errno> {
+        let base = match whence {
+            wasi::WHENCE_CUR => self.pos as i128,
+            wasi::WHENCE_END => self.data.len() as i128,
+            _ => 0,
+        };
+
+        let pos = base + offset as i128;
+        if pos < 0 || pos > Filesize::MAX as i128 {
+            return Err(wasi::ERRNO_INVAL);
+        }
+        self.pos = pos as Filesize;
+
+        Ok(self.pos)
+    }
+
+    fn filestat_get(&self) -> Result<Filestat, Errno> {
+        Ok(self.stat)
+    }
+
+    fn filestat_set_size(&mut self, _size: Filesize) -> Result<(), Errno> {
+        Err(wasi::ERRNO_ROFS)
+    }
+
+    fn filestat_set_times(
+        &mut self,
+        _atim: Timestamp,
+        _mtim: Timestamp,
+        _fst_flags: Fstflags,
+    ) -> Result<(), Errno> {
+        Err(wasi::ERRNO_ROFS)
+    }
+
+    fn allocate(&mut self, _offset: Filesize, _len: Filesize) -> Result<(), Errno> {
+        Err(wasi::ERRNO_ROFS)
+    }
+
+    fn poll_read(&self) -> Option<Readiness> {
+        Some(Readiness {
+            nbytes: (self.data.len() as Filesize).saturating_sub(self.pos),
+            hangup: false,
+        })
+    }
+}
+
+pub struct EmbeddedDir {
+    fs: EmbeddedFs,
+    node: usize,
+}
+
+impl Handle for EmbeddedDir {
+    fn filetype(&self) -> Filetype {
+        wasi::FILETYPE_DIRECTORY
+    }
+
+    fn filestat_get(&self) -> Result<Filestat, Errno> {
+        Ok(self.fs.stat(self.node))
+    }
+
+    fn filestat_set_times(
+        &mut self,
+        _atim: Timestamp,
+        _mtim: Timestamp,
+        _fst_flags: Fstflags,
+    ) -> Result<(), Errno> {
+        Err(wasi::ERRNO_ROFS)
+    }
+
+    // Entries are listed in name order and never change, so cookies are positions
+    fn readdir(&self, cookie: Dircookie) -> Result<Vec<Direntry>, Errno> {
+        let node = &self.fs.nodes[self.node];
+        let Kind::Dir(children) = &node.kind else {
+            return Err(wasi::ERRNO_NOTDIR);
+        };
+
+        let dots = [(".", self.node), ("..", node.parent)];
+        let entries = dots
+            .into_iter()
+            .chain(children.iter().map(|(name, n)| (*name, *n)))
+            .enumerate()
+            .map(|(i, (name, n))| Direntry {
+                next: i as Dircookie + 1,
+                ino: n as u64 + 1,
+                filetype: self.fs.nodes[n].filetype(),
+                name: name.to_string(),
+            })
+            .filter(|e| e.next > cookie)
+            .collect();
+
+        Ok(entries)
+    }
+
+    fn path_open(
+        &self,
+        _dirflags: Lookupflags,
+        path: &str,
+        oflags: Oflags,
+        _rights: Rights,
+        fdflags: Fdflags,
+    ) -> Result<HandleRef, Errno> {
+        let node = match self.fs.walk(self.node, path) {
+            Ok(_) if oflags & wasi::OFLAGS_CREAT != 0 && oflags & wasi::OFLAGS_EXCL != 0 => {
+                return Err(wasi::ERRNO_EXIST)
+            }
+            Ok(node) => node,
+            Err(wasi::ERRNO_NOENT) if oflags & wasi::OFLAGS_CREAT != 0 => {
+                return Err(wasi::ERRNO_ROFS)
+            }
+            Err(err) => return Err(err),
+        };
+
+        match self.fs.nodes[node].kind {
+            Kind::Dir(_) if oflags & wasi::OFLAGS_TRUNC != 0 => Err(wasi::ERRNO_ISDIR),
+            Kind::Dir(_) => Ok(Rc::new(RefCell::new(EmbeddedDir {
+                fs: self.fs.clone(),
+                node,
+            }))),
+            Kind::File(_) if oflags & wasi::OFLAGS_DIRECTORY != 0 => Err(wasi::ERRNO_NOTDIR),
+            Kind::File(_) if oflags & wasi::OFLAGS_TRUNC != 0 => Err(wasi::ERRNO_ROFS),
+            Kind::File(_) if fdflags & wasi::FDFLAGS_APPEND != 0 => Err(wasi::ERRNO_ROFS),
+            Kind::File(data) => Ok(Rc::new(RefCell::new(EmbeddedFile {
+                data,
+                stat: self.fs.stat(node),
+                pos: 0,
+            }))),
+        }
+    }
+
+    fn path_create_directory(&self, path: &str) -> Result<(), Errno> {
+        self.fs.modify(self.node, path)
+    }
+
+    fn path_filestat_get(&self, _flags: Lookupflags, path: &str) -> Result<Filestat, Errno> {
+        Ok(self.fs.stat(self.fs.walk(self.node, path)?))
+    }
+
+    fn path_filestat_set_times(
+        &self,
+        _flags: Lookupflags,
+        path: &str,
+        _atim: Timestamp,
+        _mtim: Timestamp,
+        _fst_flags: Fstflags,
+    ) -> Result<(), Errno> {
+        self.fs.walk(self.node, path)?;
+        Err(wasi::ERRNO_ROFS)
+    }
+
+    fn path_link(
+        &self,
+        _old_flags: Lookupflags,
+        old_path: &str,
+        _new_dir: &dyn Handle,
+        _new_path: &str,
+    ) -> Result<(), Errno> {
+        self.fs.walk(self.node, old_path)?;
+        Err(wasi::ERRNO_ROFS)
+    }
+
+    fn path_readlink(&self, path: &str) -> Result<String, Errno> {
+        self.fs.walk(self.node, path)?;
+        Err(wasi::ERRNO_INVAL)
+    }
+
+    fn path_remove_directory(&self, path: &str) -> Result<(), Errno> {
+        self.fs.walk(self.node, path)?;
+        Err(wasi::ERRNO_ROFS)
+    }
+
+    fn path_rename(
+        &self,
+        old_path: &str,
+        _new_dir: &dyn Handle,
+        _new_path: &str,
+    ) -> Result<(), Errno> {
+        self.fs.walk(self.node, old_path)?;
+        Err(wasi::ERRNO_ROFS)
+    }
+
+    fn path_symlink(&self, _old_path: &str, new_path: &str) -> Result<(), Errno> {
+        self.fs.modify(self.node, new_path)
+    }
+
+    fn path_unlink_file(&self, path: &str) -> Result<(), Errno> {
+        self.fs.walk(self.node, path)?;
+        Err(wasi::ERRNO_ROFS)
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn install(fs: EmbeddedFs) {
+    preopens::install();
+    preopens::register("/", fs.root());
+}
+
+// Injected
+
+// `wasi-shim --embed-dir` appends a table of files to the module and rewrites this function to
+// return its address. The table is a count followed by that many rows of six words: the guest
+// mount point, the path below it and the contents, each as address and length, where a
+// directory has a contents address of 0
+#[no_mangle]
+#[inline(never)]
+extern "C" fn __wasi_shim_embedded() -> *const u32 {
+    hint::black_box(ptr::null())
+}
+
+/// Filesystems injected by `wasi-shim --embed-dir`, with their guest mount points.
+pub fn injected() -> Vec<(&'static str, EmbeddedFs)> {
+    let table = __wasi_shim_embedded();
+    if table.is_null() {
+        return vec![];
+    }
+
+    unsafe fn text(ptr: u32, len: u32) -> &'static str {
+        str::from_utf8_unchecked(bytes(ptr, len))
+    }
+    unsafe fn bytes(ptr: u32, len: u32) -> &'static [u8] {
+        slice::from_raw_parts(ptr as usize as *const u8, len as usize)
+    }
+
+    let rows = unsafe { slice::from_raw_parts(table.add(1), *table as usize * 6) };
+    let mut mounts: Vec<(&'static str, Vec<Entry>)> = vec![];
+    for row in rows.chunks(6) {
+        let mount = unsafe { text(row[0], row[1]) };
+        let path = unsafe { text(row[2], row[3]) };
+        let data = match row[4] {
+            0 => None,
+            ptr => Some(unsafe { bytes(ptr, row[5]) }),
+        };
+
+        match mounts.iter_mut().find(|(m, _)| *m == mount) {
+            Some((_, entries)) => entries.push((path, data)),
+            None => mounts.push((mount, vec![(path, data)])),
+        }
+    }
+
+    mounts
+        .into_iter()
+        .map(|(mount, entries)| (mount, EmbeddedFs::new(&entries)))
+        .collect()
+}
+
+/// Registers each filesystem injected by `wasi-shim --embed-dir` as a preopen at its mount
+/// point. Does nothing for a module that has not been through `--embed-dir`.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn install_injected() {
+    let mounts = injected();
+    if mounts.is_empty() {
+        return;
+    }
+
+    preopens::install();
+    for (mount, fs) in mounts {
+        preopens::register(mount, fs.root());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static ENTRIES: &[Entry] = &[
+        ("config.toml", Some(b"key = 1\n")),
+        ("empty", None),
+        ("templates/page.html", Some(b"<p>")),
+    ];
+
+    #[test]
+    fn test_tree() -> Result<(), Errno> {
+        let root = EmbeddedFs::new(ENTRIES).root();
+
+        let names: Vec<_> = root.readdir(0)?.into_iter().map(|e| e.name).collect();
+        assert_eq!(names, [".", "..", "config.toml", "empty", "templates"]);
+        assert_eq!(root.readdir(4)?.len(), 1);
+
+        assert_eq!(root.path_filestat_get(0, "config.toml")?.size, 8);
+        assert_eq!(
+            root.path_filestat_get(0, "templates/../empty")?.filetype,
+            wasi::FILETYPE_DIRECTORY
+        );
+        assert_eq!(
+            root.path_filestat_get(0, "..").err(),
+            Some(wasi::ERRNO_PERM)
+        );
+
+        let file = root.path_open(0, "templates/page.html", 0, 0, 0)?;
+        let mut buf = [0; 8];
+        assert_eq!(file.borrow_mut().read(&mut buf)?, 3);
+        assert_eq!(&buf[..3], b"<p>");
+        assert_eq!(file.borrow_mut().write(b"x").err(), Some(wasi::ERRNO_ROFS));
+        assert_eq!(file.borrow_mut().pread(&mut buf, Filesize::MAX)?, 0);
+
+        assert_eq!(
+            root.path_open(0, "new", wasi::OFLAGS_CREAT, 0, 0).err(),
+            Some(wasi::ERRNO_ROFS)
+        );
+        assert_eq!(
+            root.path_unlink_file("missing").err(),
+            Some(wasi::ERRNO_NOENT)
+        );
+        assert_eq!(
+            root.path_create_directory("empty/x").err(),
+            Some(wasi::ERRNO_ROFS)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_generate() -> io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("wasi-shim-embed-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub"))?;
+        fs::write(dir.join("a.txt"), b"a")?;
+
+        let out = generate(&dir);
+        let abs = dir.canonicalize()?.join("a.txt");
+        let _ = fs::remove_dir_all(&dir);
+
+        assert_eq!(
+            out?,
+            format!(
+                "&[\n    (\"a.txt\", Some(include_bytes!({:?}) as &[u8])),\n    (\"sub\", None),\n]",
+                abs.to_str().unwrap()
+            )
+        );
+
+        Ok(())
+    }
+}
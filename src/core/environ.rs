@@ -1,5 +1,9 @@
+use std::ptr::addr_of_mut;
+
 use wasi::{Errno, Size};
 
+use crate::core::mem::Strings;
+
 // Types
 
 pub type EnvironGetFn = fn(environ: *mut *mut u8, environ_buf: *mut u8) -> Errno;
@@ -31,24 +35,48 @@ pub mod set {
     }
 }
 
+// Backends
+
+static mut STRINGS: Strings = Strings::Static(Vec::new());
+
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn set_static(vars: &[(&str, &str)]) {
+    install(Strings::Static(
+        vars.iter().map(|(k, v)| format!("{k}={v}")).collect(),
+    ));
+}
+
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn set_dynamic(f: impl Fn() -> Vec<(String, String)> + 'static) {
+    install(Strings::Dynamic(
+        Box::new(move || f().into_iter().map(|(k, v)| format!("{k}={v}")).collect()),
+        None,
+    ));
+}
+
+unsafe fn install(ss: Strings) {
+    STRINGS = ss;
+
+    set::environ_get(strings_get);
+    set::environ_sizes_get(strings_sizes_get);
+}
+
+fn strings_get(environ: *mut *mut u8, environ_buf: *mut u8) -> Errno {
+    unsafe { (*addr_of_mut!(STRINGS)).get(environ, environ_buf) }
+}
+
+fn strings_sizes_get(rp0: *mut Size, rp1: *mut Size) -> Errno {
+    unsafe { (*addr_of_mut!(STRINGS)).sizes_get(rp0, rp1) }
+}
+
 // Shims
 
 pub mod shims {
     use super::*;
+    use crate::core::layer::shims;
 
-    #[no_mangle]
-    unsafe extern "C" fn __shim_environ_get(environ: *mut *mut u8, environ_buf: *mut u8) -> Errno {
-        match POLYFILLS.get {
-            Some(f) => f(environ, environ_buf),
-            None => unimplemented!("environ_get"),
-        }
-    }
-
-    #[no_mangle]
-    unsafe extern "C" fn __shim_environ_sizes_get(rp0: *mut Size, rp1: *mut Size) -> Errno {
-        match POLYFILLS.sizes_get {
-            Some(f) => f(rp0, rp1),
-            None => unimplemented!("environ_sizes_get"),
-        }
+    shims! {
+        __shim_environ_get => EnvironGet { environ: *mut *mut u8, environ_buf: *mut u8 } => get;
+        __shim_environ_sizes_get => EnvironSizesGet { rp0: *mut Size, rp1: *mut Size } => sizes_get;
     }
 }
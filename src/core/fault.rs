@@ -0,0 +1,313 @@
+use std::collections::BTreeMap;
+
+use wasi::{Errno, Size};
+
+use crate::core::layer::{self, Call, Layer};
+
+// Types
+
+pub enum Fault {
+    /// Fails the nth call, counting from 1.
+    Nth(u64, Errno),
+    /// Fails `count` calls in a row starting with call `from`, e.g. a run of `INTR` or `AGAIN`.
+    Storm { errno: Errno, from: u64, count: u64 },
+    /// Fails each call with probability `rate`, with an errno picked from `errnos`.
+    Random { rate: f64, errnos: Vec<Errno> },
+    /// Transfers at most this many bytes per read or write.
+    Short(Size),
+}
+
+pub struct Rule {
+    // WASI name of the function, e.g. "fd_write"
+    pub call: &'static str,
+    pub fault: Fault,
+}
+
+// Layer
+
+struct Faults {
+    rules: Vec<Rule>,
+    counts: BTreeMap<&'static str, u64>,
+    rng: u64,
+}
+
+impl Faults {
+    fn new(rules: Vec<Rule>, seed: u64) -> Self {
+        Self {
+            rules,
+            counts: BTreeMap::new(),
+            rng: seed,
+        }
+    }
+
+    // SplitMix64
+    fn next(&mut self) -> u64 {
+        self.rng = self.rng.wrapping_add(0x9e3779b97f4a7c15);
+
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn fail(&mut self, name: &str, n: u64) -> Option<Errno> {
+        for i in 0..self.rules.len() {
+            if self.rules[i].call != name {
+                continue;
+            }
+
+            let errno = match self.rules[i].fault {
+                Fault::Nth(nth, errno) if nth == n => Some(errno),
+                Fault::Storm { errno, from, count }
+                    if (from..from.saturating_add(count)).contains(&n) =>
+                {
+                    Some(errno)
+                }
+                Fault::Random { rate, .. } => {
+                    let roll = (self.next() >> 11) as f64 / (1u64 << 53) as f64;
+                    let pick = self.next();
+
+                    match &self.rules[i].fault {
+                        Fault::Random { errnos, .. } if roll < rate && !errnos.is_empty() => {
+                            Some(errnos[pick as usize % errnos.len()])
+                        }
+                        _ => None,
+                    }
+                }
+                _ => None,
+            };
+
+            if errno.is_some() {
+                return errno;
+            }
+        }
+
+        None
+    }
+}
+
+impl Layer for Faults {
+    fn enter(&mut self, call: &Call) -> Option<Errno> {
+        if let Call::ProcExit { .. } = call {
+            return None;
+        }
+
+        let name = call.name();
+        let n = self.counts.entry(name).or_default();
+        *n += 1;
+
+        let n = *n;
+        self.fail(name, n)
+    }
+
+    fn max_len(&mut self, call: &Call) -> Option<Size> {
+        self.rules
+            .iter()
+            .filter(|r| r.call == call.name())
+            .filter_map(|r| match r.fault {
+                Fault::Short(max) => Some(max),
+                _ => None,
+            })
+            .min()
+    }
+}
+
+/// Injects the faults described by `rules` into shim calls, drawing random failures from
+/// `seed`. Push it after any tracing or recording layers that should see the faults.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn install(rules: Vec<Rule>, seed: u64) {
+    layer::push(Faults::new(rules, seed));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use wasi::Iovec;
+
+    use super::*;
+    use crate::core::{mem, testing, trace};
+
+    #[test]
+    fn test_errors() {
+        let mut f = Faults::new(
+            vec![
+                Rule {
+                    call: "fd_write",
+                    fault: Fault::Nth(2, wasi::ERRNO_NOSPC),
+                },
+                Rule {
+                    call: "fd_write",
+                    fault: Fault::Storm {
+                        errno: wasi::ERRNO_INTR,
+                        from: 4,
+                        count: 2,
+                    },
+                },
+            ],
+            0,
+        );
+
+        let call = Call::FdWrite {
+            fd: 1,
+            iovs: std::ptr::null(),
+            iovs_len: 0,
+            rp0: std::ptr::null_mut(),
+        };
+        let errnos: Vec<_> = (0..6)
+            .map(|_| {
+                let ret = f.enter(&call);
+                if ret.is_none() {
+                    f.exit(&call, wasi::ERRNO_SUCCESS);
+                }
+                ret
+            })
+            .collect();
+
+        assert_eq!(
+            errnos,
+            [
+                None,
+                Some(wasi::ERRNO_NOSPC),
+                None,
+                Some(wasi::ERRNO_INTR),
+                Some(wasi::ERRNO_INTR),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn test_random() {
+        let rules = || {
+            vec![Rule {
+                call: "fd_read",
+                fault: Fault::Random {
+                    rate: 0.5,
+                    errnos: vec![wasi::ERRNO_IO, wasi::ERRNO_AGAIN],
+                },
+            }]
+        };
+        let run = |seed| {
+            let mut f = Faults::new(rules(), seed);
+            (0..64)
+                .map(|_| f.fail("fd_read", 1).map(|e| e.raw()).unwrap_or(0))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+        assert!(run(7).contains(&wasi::ERRNO_IO.raw()));
+        assert!(run(7).contains(&0));
+    }
+
+    #[test]
+    fn test_short() {
+        let mut f = Faults::new(
+            vec![Rule {
+                call: "fd_read",
+                fault: Fault::Short(3),
+            }],
+            0,
+        );
+
+        let mut a = [0u8; 2];
+        let mut b = [0u8; 4];
+        let iovs = [
+            Iovec {
+                buf: a.as_mut_ptr(),
+                buf_len: a.len(),
+            },
+            Iovec {
+                buf: b.as_mut_ptr(),
+                buf_len: b.len(),
+            },
+        ];
+        let call = Call::FdRead {
+            fd: 0,
+            iovs: iovs.as_ptr(),
+            iovs_len: 2,
+            rp0: std::ptr::null_mut(),
+        };
+
+        assert_eq!(f.enter(&call), None);
+        assert_eq!(f.max_len(&call), Some(3));
+        assert_eq!(f.max_len(&Call::FdClose { fd: 0 }), None);
+
+        let _lock = testing::LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let lens = unsafe {
+            layer::clear();
+            layer::push(f);
+
+            let mut lens = vec![];
+            layer::call(call, |c| {
+                let Call::FdRead { iovs, iovs_len, .. } = c else {
+                    unreachable!()
+                };
+
+                lens = mem::iovecs(iovs, iovs_len)
+                    .iter()
+                    .map(|i| i.buf_len)
+                    .collect();
+                wasi::ERRNO_SUCCESS
+            });
+            layer::clear();
+            lens
+        };
+        assert_eq!(lens, [2, 1]);
+    }
+
+    #[test]
+    fn test_short_under_trace() {
+        let _lock = testing::LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        let lines = Rc::new(RefCell::new(vec![]));
+        let sink = lines.clone();
+        unsafe {
+            layer::clear();
+            trace::install(move |line| sink.borrow_mut().push(line.to_string()));
+            install(
+                vec![Rule {
+                    call: "fd_write",
+                    fault: Fault::Short(2),
+                }],
+                0,
+            );
+        }
+
+        let data = *b"hello";
+        let iov = Iovec {
+            buf: data.as_ptr() as *mut u8,
+            buf_len: data.len(),
+        };
+        let mut n = 0;
+        let call = Call::FdWrite {
+            fd: 1,
+            iovs: &iov,
+            iovs_len: 1,
+            rp0: &mut n,
+        };
+        let ret = unsafe {
+            let ret = layer::call(call, |c| {
+                let Call::FdWrite {
+                    iovs,
+                    iovs_len,
+                    rp0,
+                    ..
+                } = c
+                else {
+                    unreachable!()
+                };
+
+                *rp0 = mem::iovecs(iovs, iovs_len).iter().map(|i| i.buf_len).sum();
+                wasi::ERRNO_SUCCESS
+            });
+            layer::clear();
+            ret
+        };
+
+        // The trace sees the call as the guest made it
+        assert_eq!((ret, n), (wasi::ERRNO_SUCCESS, 2));
+        assert_eq!(*lines.borrow(), ["fd_write(1, [5]) = 2"]);
+    }
+}
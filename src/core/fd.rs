@@ -1,8 +1,15 @@
+use std::{
+    cell::RefCell,
+    io::{self, Read, Seek, SeekFrom, Write},
+};
+
 use wasi::{
-    Advice, Dircookie, Errno, Fd, Fdflags, Fdstat, Filedelta, Filesize, Filestat, Fstflags, Iovec,
-    Prestat, Rights, Size, Timestamp, Whence,
+    Advice, Ciovec, Dircookie, Errno, Fd, Fdflags, Fdstat, Filedelta, Filesize, Filestat, Filetype,
+    Fstflags, Iovec, Prestat, Rights, Size, Timestamp, Whence,
 };
 
+use crate::core::fd_table::{self, Entry, Handle};
+
 // Types
 
 pub type FdAdviseFn = fn(fd: Fd, offset: Filesize, len: Filesize, advice: Advice) -> Errno;
@@ -191,227 +198,312 @@ pub mod set {
     }
 }
 
-// Shims
+// Backends
 
-pub mod shims {
-    use super::*;
+pub trait Stream: Read + Write + Seek {}
 
-    #[no_mangle]
-    unsafe extern "C" fn __shim_fd_advise(
-        fd: Fd,
-        offset: Filesize,
-        len: Filesize,
-        advice: Advice,
-    ) -> Errno {
-        match POLYFILLS.advise {
-            Some(f) => f(fd, offset, len, advice),
-            None => unimplemented!("fd_advise"),
-        }
-    }
+impl<T: Read + Write + Seek> Stream for T {}
 
-    #[no_mangle]
-    unsafe extern "C" fn __shim_fd_allocate(fd: Fd, offset: Filesize, len: Filesize) -> Errno {
-        match POLYFILLS.allocate {
-            Some(f) => f(fd, offset, len),
-            None => unimplemented!("fd_allocate"),
-        }
-    }
+enum StreamHandle {
+    // Finding the size of a stream means seeking it, which `filestat_get` has to do through `&self`
+    Stream(RefCell<Box<dyn Stream>>),
+    Reader(Box<dyn Read>),
+    Writer(Box<dyn Write>),
+}
 
-    #[no_mangle]
-    unsafe extern "C" fn __shim_fd_close(fd: Fd) -> Errno {
-        match POLYFILLS.close {
-            Some(f) => f(fd),
-            None => unimplemented!("fd_close"),
+impl Handle for StreamHandle {
+    fn filetype(&self) -> Filetype {
+        match self {
+            StreamHandle::Stream(_) => wasi::FILETYPE_REGULAR_FILE,
+            _ => wasi::FILETYPE_UNKNOWN,
         }
     }
 
-    #[no_mangle]
-    unsafe extern "C" fn __shim_fd_datasync(fd: Fd) -> Errno {
-        match POLYFILLS.datasync {
-            Some(f) => f(fd),
-            None => unimplemented!("fd_datasync"),
+    fn read(&mut self, buf: &mut [u8]) -> Result<Size, Errno> {
+        match self {
+            StreamHandle::Stream(s) => s.get_mut().read(buf).map_err(io_errno),
+            StreamHandle::Reader(r) => r.read(buf).map_err(io_errno),
+            StreamHandle::Writer(_) => Err(wasi::ERRNO_BADF),
         }
     }
 
-    #[no_mangle]
-    unsafe extern "C" fn __shim_fd_fdstat_get(fd: Fd, rp0: *mut Fdstat) -> Errno {
-        match POLYFILLS.fdstat_get {
-            Some(f) => f(fd, rp0),
-            None => unimplemented!("fd_fdstat_get"),
+    fn write(&mut self, buf: &[u8]) -> Result<Size, Errno> {
+        match self {
+            StreamHandle::Stream(s) => s.get_mut().write(buf).map_err(io_errno),
+            StreamHandle::Writer(w) => w.write(buf).map_err(io_errno),
+            StreamHandle::Reader(_) => Err(wasi::ERRNO_BADF),
         }
     }
 
-    #[no_mangle]
-    unsafe extern "C" fn __shim_fd_fdstat_set_flags(fd: Fd, flags: Fdflags) -> Errno {
-        match POLYFILLS.fdstat_set_flags {
-            Some(f) => f(fd, flags),
-            None => unimplemented!("fd_fdstat_set_flags"),
-        }
-    }
+    fn seek(&mut self, offset: Filedelta, whence: Whence) -> Result<Filesize, Errno> {
+        let pos = match whence {
+            wasi::WHENCE_SET if offset < 0 => return Err(wasi::ERRNO_INVAL),
+            wasi::WHENCE_SET => SeekFrom::Start(offset as u64),
+            wasi::WHENCE_CUR => SeekFrom::Current(offset),
+            wasi::WHENCE_END => SeekFrom::End(offset),
+            _ => return Err(wasi::ERRNO_INVAL),
+        };
 
-    #[no_mangle]
-    unsafe extern "C" fn __shim_fd_fdstat_set_rights(
-        fd: Fd,
-        fs_rights_base: Rights,
-        fs_rights_inheriting: Rights,
-    ) -> Errno {
-        match POLYFILLS.fdstat_set_rights {
-            Some(f) => f(fd, fs_rights_base, fs_rights_inheriting),
-            None => unimplemented!("fd_fdstat_set_rights"),
+        match self {
+            StreamHandle::Stream(s) => s.get_mut().seek(pos).map_err(io_errno),
+            _ => Err(wasi::ERRNO_SPIPE),
         }
     }
 
-    #[no_mangle]
-    unsafe extern "C" fn __shim_fd_filestat_get(fd: Fd, rp0: *mut Filestat) -> Errno {
-        match POLYFILLS.filestat_get {
-            Some(f) => f(fd, rp0),
-            None => unimplemented!("fd_filestat_get"),
+    fn filestat_get(&self) -> Result<Filestat, Errno> {
+        let size = match self {
+            StreamHandle::Stream(s) => {
+                let mut s = s.borrow_mut();
+                let pos = s.stream_position().map_err(io_errno)?;
+                let size = s.seek(SeekFrom::End(0)).map_err(io_errno)?;
+                s.seek(SeekFrom::Start(pos)).map_err(io_errno)?;
+                size
+            }
+            _ => 0,
+        };
+
+        Ok(Filestat {
+            dev: 0,
+            ino: 0,
+            filetype: self.filetype(),
+            nlink: 1,
+            size,
+            atim: 0,
+            mtim: 0,
+            ctim: 0,
+        })
+    }
+
+    fn sync(&mut self) -> Result<(), Errno> {
+        match self {
+            StreamHandle::Stream(s) => s.get_mut().flush().map_err(io_errno),
+            StreamHandle::Writer(w) => w.flush().map_err(io_errno),
+            StreamHandle::Reader(_) => Ok(()),
         }
     }
 
-    #[no_mangle]
-    unsafe extern "C" fn __shim_fd_filestat_set_size(fd: Fd, size: Filesize) -> Errno {
-        match POLYFILLS.filestat_set_size {
-            Some(f) => f(fd, size),
-            None => unimplemented!("fd_filestat_set_size"),
-        }
+    fn close(&mut self) -> Result<(), Errno> {
+        self.sync()
     }
+}
 
-    #[no_mangle]
-    unsafe extern "C" fn __shim_fd_filestat_set_times(
-        fd: Fd,
-        atim: Timestamp,
-        mtim: Timestamp,
-        fst_flags: Fstflags,
-    ) -> Errno {
-        match POLYFILLS.filestat_set_times {
-            Some(f) => f(fd, atim, mtim, fst_flags),
-            None => unimplemented!("fd_filestat_set_times"),
-        }
-    }
+unsafe fn register(h: StreamHandle, rights: Rights) -> Fd {
+    fd_table::ensure_installed();
 
-    #[no_mangle]
-    unsafe extern "C" fn __shim_fd_pread(
-        fd: Fd,
-        iovs: *const Iovec,
-        len: i32,
-        offset: Filesize,
-        rp0: *mut Size,
-    ) -> Errno {
-        match POLYFILLS.pread {
-            Some(f) => f(fd, iovs, len, offset, rp0),
-            None => unimplemented!("fd_pread"),
-        }
-    }
+    let mut entry = Entry::new(h);
+    entry.rights_base = rights;
+    entry.rights_inheriting = 0;
 
-    #[no_mangle]
-    unsafe extern "C" fn __shim_fd_prestat_dir_name(
-        fd: Fd,
-        path: *mut u8,
-        path_len: Size,
-    ) -> Errno {
-        match POLYFILLS.prestat_dir_name {
-            Some(f) => f(fd, path, path_len),
-            None => unimplemented!("fd_prestat_dir_name"),
-        }
-    }
+    fd_table::insert(entry)
+}
 
-    #[no_mangle]
-    unsafe extern "C" fn __shim_fd_prestat_get(fd: Fd, rp0: *mut Prestat) -> Errno {
-        match POLYFILLS.prestat_get {
-            Some(f) => f(fd, rp0),
-            None => unimplemented!("fd_prestat_get"),
-        }
-    }
+const RIGHTS_STREAM: Rights = wasi::RIGHTS_FD_FDSTAT_SET_FLAGS
+    | wasi::RIGHTS_FD_FILESTAT_GET
+    | wasi::RIGHTS_POLL_FD_READWRITE;
+
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn register_stream(s: Box<dyn Stream>) -> Fd {
+    register(
+        StreamHandle::Stream(RefCell::new(s)),
+        RIGHTS_STREAM
+            | wasi::RIGHTS_FD_READ
+            | wasi::RIGHTS_FD_WRITE
+            | wasi::RIGHTS_FD_SEEK
+            | wasi::RIGHTS_FD_TELL
+            | wasi::RIGHTS_FD_SYNC
+            | wasi::RIGHTS_FD_DATASYNC,
+    )
+}
 
-    #[no_mangle]
-    unsafe extern "C" fn __shim_fd_pwrite(
-        fd: Fd,
-        iovs: *const Iovec,
-        iovs_len: i32,
-        offset: Filesize,
-        rp0: *mut Size,
-    ) -> Errno {
-        match POLYFILLS.pwrite {
-            Some(f) => f(fd, iovs, iovs_len, offset, rp0),
-            None => unimplemented!("fd_pwrite"),
-        }
-    }
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn register_reader(r: Box<dyn Read>) -> Fd {
+    register(
+        StreamHandle::Reader(r),
+        RIGHTS_STREAM | wasi::RIGHTS_FD_READ,
+    )
+}
 
-    #[no_mangle]
-    unsafe extern "C" fn __shim_fd_read(
-        fd: Fd,
-        iovs: *const Iovec,
-        iovs_len: i32,
-        rp0: *mut Size,
-    ) -> Errno {
-        match POLYFILLS.read {
-            Some(f) => f(fd, iovs, iovs_len, rp0),
-            None => unimplemented!("fd_read"),
-        }
-    }
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn register_writer(w: Box<dyn Write>) -> Fd {
+    register(
+        StreamHandle::Writer(w),
+        RIGHTS_STREAM | wasi::RIGHTS_FD_WRITE | wasi::RIGHTS_FD_SYNC | wasi::RIGHTS_FD_DATASYNC,
+    )
+}
 
-    #[no_mangle]
-    unsafe extern "C" fn __shim_fd_readdir(
-        fd: Fd,
-        buf: *mut u8,
-        buf_len: Size,
-        cookie: Dircookie,
-        rp0: *mut Size,
-    ) -> Errno {
-        match POLYFILLS.readdir {
-            Some(f) => f(fd, buf, buf_len, cookie, rp0),
-            None => unimplemented!("fd_readdir"),
-        }
-    }
+// Helpers
 
-    #[no_mangle]
-    unsafe extern "C" fn __shim_fd_renumber(fd: Fd, to: Fd) -> Errno {
-        match POLYFILLS.renumber {
-            Some(f) => f(fd, to),
-            None => unimplemented!("fd_renumber"),
-        }
+pub(crate) fn write(fd: Fd, buf: &[u8]) -> Result<Size, Errno> {
+    let iov = Ciovec {
+        buf: buf.as_ptr(),
+        buf_len: buf.len(),
+    };
+
+    let mut n = 0;
+    match unsafe { POLYFILLS.write } {
+        Some(f) => match f(fd, &iov as *const Ciovec as *const Iovec, 1, &mut n) {
+            wasi::ERRNO_SUCCESS => Ok(n),
+            err => Err(err),
+        },
+        None => Err(wasi::ERRNO_NOSYS),
     }
+}
 
-    #[no_mangle]
-    unsafe extern "C" fn __shim_fd_seek(
-        fd: Fd,
-        offset: Filedelta,
-        whence: Whence,
-        rp0: *mut Filesize,
-    ) -> Errno {
-        match POLYFILLS.seek {
-            Some(f) => f(fd, offset, whence, rp0),
-            None => unimplemented!("fd_seek"),
-        }
+pub(crate) fn io_errno(err: io::Error) -> Errno {
+    match err.kind() {
+        io::ErrorKind::NotFound => wasi::ERRNO_NOENT,
+        io::ErrorKind::PermissionDenied => wasi::ERRNO_ACCES,
+        io::ErrorKind::AlreadyExists => wasi::ERRNO_EXIST,
+        io::ErrorKind::WouldBlock => wasi::ERRNO_AGAIN,
+        io::ErrorKind::InvalidInput => wasi::ERRNO_INVAL,
+        io::ErrorKind::Interrupted => wasi::ERRNO_INTR,
+        io::ErrorKind::Unsupported => wasi::ERRNO_NOTSUP,
+        io::ErrorKind::BrokenPipe => wasi::ERRNO_PIPE,
+        io::ErrorKind::NotADirectory => wasi::ERRNO_NOTDIR,
+        io::ErrorKind::IsADirectory => wasi::ERRNO_ISDIR,
+        io::ErrorKind::DirectoryNotEmpty => wasi::ERRNO_NOTEMPTY,
+        io::ErrorKind::ReadOnlyFilesystem => wasi::ERRNO_ROFS,
+        io::ErrorKind::StorageFull => wasi::ERRNO_NOSPC,
+        io::ErrorKind::FileTooLarge => wasi::ERRNO_FBIG,
+        io::ErrorKind::CrossesDevices => wasi::ERRNO_XDEV,
+        io::ErrorKind::TooManyLinks => wasi::ERRNO_MLINK,
+        io::ErrorKind::InvalidFilename => wasi::ERRNO_NAMETOOLONG,
+        _ => wasi::ERRNO_IO,
     }
+}
 
-    #[no_mangle]
-    unsafe extern "C" fn __shim_fd_sync(fd: Fd) -> Errno {
-        match POLYFILLS.sync {
-            Some(f) => f(fd),
-            None => unimplemented!("fd_sync"),
-        }
+// Shims
+
+pub mod shims {
+    use super::*;
+    use crate::core::layer::shims;
+
+    shims! {
+        __shim_fd_advise => FdAdvise {
+            fd: Fd,
+            offset: Filesize,
+            len: Filesize,
+            advice: Advice,
+        } => advise;
+        __shim_fd_allocate => FdAllocate { fd: Fd, offset: Filesize, len: Filesize } => allocate;
+        __shim_fd_close => FdClose { fd: Fd } => close;
+        __shim_fd_datasync => FdDatasync { fd: Fd } => datasync;
+        __shim_fd_fdstat_get => FdFdstatGet { fd: Fd, rp0: *mut Fdstat } => fdstat_get;
+        __shim_fd_fdstat_set_flags => FdFdstatSetFlags {
+            fd: Fd,
+            flags: Fdflags,
+        } => fdstat_set_flags;
+        __shim_fd_fdstat_set_rights => FdFdstatSetRights {
+            fd: Fd,
+            fs_rights_base: Rights,
+            fs_rights_inheriting: Rights,
+        } => fdstat_set_rights;
+        __shim_fd_filestat_get => FdFilestatGet { fd: Fd, rp0: *mut Filestat } => filestat_get;
+        __shim_fd_filestat_set_size => FdFilestatSetSize {
+            fd: Fd,
+            size: Filesize,
+        } => filestat_set_size;
+        __shim_fd_filestat_set_times => FdFilestatSetTimes {
+            fd: Fd,
+            atim: Timestamp,
+            mtim: Timestamp,
+            fst_flags: Fstflags,
+        } => filestat_set_times;
+        __shim_fd_pread => FdPread {
+            fd: Fd,
+            iovs: *const Iovec,
+            len: i32,
+            offset: Filesize,
+            rp0: *mut Size,
+        } => pread;
+        __shim_fd_prestat_dir_name => FdPrestatDirName {
+            fd: Fd,
+            path: *mut u8,
+            path_len: Size,
+        } => prestat_dir_name;
+        __shim_fd_prestat_get => FdPrestatGet { fd: Fd, rp0: *mut Prestat } => prestat_get;
+        __shim_fd_pwrite => FdPwrite {
+            fd: Fd,
+            iovs: *const Iovec,
+            iovs_len: i32,
+            offset: Filesize,
+            rp0: *mut Size,
+        } => pwrite;
+        __shim_fd_read => FdRead {
+            fd: Fd,
+            iovs: *const Iovec,
+            iovs_len: i32,
+            rp0: *mut Size,
+        } => read;
+        __shim_fd_readdir => FdReaddir {
+            fd: Fd,
+            buf: *mut u8,
+            buf_len: Size,
+            cookie: Dircookie,
+            rp0: *mut Size,
+        } => readdir;
+        __shim_fd_renumber => FdRenumber { fd: Fd, to: Fd } => renumber;
+        __shim_fd_seek => FdSeek {
+            fd: Fd,
+            offset: Filedelta,
+            whence: Whence,
+            rp0: *mut Filesize,
+        } => seek;
+        __shim_fd_sync => FdSync { fd: Fd } => sync;
+        __shim_fd_tell => FdTell { fd: Fd, rp0: *mut Filesize } => tell;
+        __shim_fd_write => FdWrite {
+            fd: Fd,
+            iovs: *const Iovec,
+            iovs_len: i32,
+            rp0: *mut Size,
+        } => write;
     }
+}
 
-    #[no_mangle]
-    unsafe extern "C" fn __shim_fd_tell(fd: Fd, rp0: *mut Filesize) -> Errno {
-        match POLYFILLS.tell {
-            Some(f) => f(fd, rp0),
-            None => unimplemented!("fd_tell"),
-        }
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::core::testing;
+
+    #[test]
+    fn test_stream_handle() -> Result<(), Errno> {
+        let mut h = StreamHandle::Stream(RefCell::new(Box::new(Cursor::new(vec![]))));
+
+        assert_eq!(h.write(b"hello")?, 5);
+        assert_eq!(h.seek(1, wasi::WHENCE_SET)?, 1);
+
+        let mut buf = [0; 8];
+        assert_eq!(h.read(&mut buf)?, 4);
+        assert_eq!(&buf[..4], b"ello");
+
+        assert_eq!(h.filestat_get()?.size, 5);
+        assert_eq!(h.seek(0, wasi::WHENCE_CUR)?, 5);
+        assert_eq!(h.seek(-1, wasi::WHENCE_SET).err(), Some(wasi::ERRNO_INVAL));
+
+        let mut r = StreamHandle::Reader(Box::new(&b"abc"[..]));
+        assert_eq!(r.read(&mut buf)?, 3);
+        assert_eq!(r.write(b"x").err(), Some(wasi::ERRNO_BADF));
+        assert_eq!(r.seek(0, wasi::WHENCE_CUR).err(), Some(wasi::ERRNO_SPIPE));
+
+        Ok(())
     }
 
-    #[no_mangle]
-    unsafe extern "C" fn __shim_fd_write(
-        fd: Fd,
-        iovs: *const Iovec,
-        iovs_len: i32,
-        rp0: *mut Size,
-    ) -> Errno {
-        match POLYFILLS.write {
-            Some(f) => f(fd, iovs, iovs_len, rp0),
-            None => unimplemented!("fd_write"),
+    #[test]
+    fn test_register_keeps_polyfills() {
+        let _polyfills = testing::Polyfills::lock();
+
+        fn notsup(_: Fd, _: *const Iovec, _: i32, _: *mut Size) -> Errno {
+            wasi::ERRNO_NOTSUP
         }
+
+        let written = unsafe {
+            register_writer(Box::new(io::sink()));
+            set::fd_write(notsup);
+            let fd = register_writer(Box::new(io::sink()));
+            write(fd, b"x")
+        };
+        assert_eq!(written, Err(wasi::ERRNO_NOTSUP));
     }
 }
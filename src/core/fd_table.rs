@@ -0,0 +1,961 @@
+use std::{
+    any::Any,
+    cell::RefCell,
+    collections::BTreeMap,
+    mem::size_of,
+    ptr::{self, addr_of, addr_of_mut},
+    rc::Rc,
+};
+
+use wasi::{
+    Advice, Ciovec, Dircookie, Dirent, Errno, Fd, Fdflags, Fdstat, Filedelta, Filesize, Filestat,
+    Filetype, Fstflags, Inode, Iovec, Lookupflags, Oflags, Riflags, Rights, Roflags, Sdflags,
+    Siflags, Size, Timestamp, Whence,
+};
+
+use crate::core::{fd, mem, path, sock};
+
+pub const RIGHTS_ALL: Rights = (1 << 30) - 1;
+
+// Handles
+
+pub type HandleRef = Rc<RefCell<dyn Handle>>;
+
+pub struct Direntry {
+    pub next: Dircookie,
+    pub ino: Inode,
+    pub filetype: Filetype,
+    pub name: String,
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct Readiness {
+    pub nbytes: Filesize,
+    pub hangup: bool,
+}
+
+pub trait Handle: Any {
+    fn filetype(&self) -> Filetype;
+
+    fn read(&mut self, _buf: &mut [u8]) -> Result<Size, Errno> {
+        Err(wasi::ERRNO_BADF)
+    }
+
+    fn write(&mut self, _buf: &[u8]) -> Result<Size, Errno> {
+        Err(wasi::ERRNO_BADF)
+    }
+
+    fn pread(&mut self, _buf: &mut [u8], _offset: Filesize) -> Result<Size, Errno> {
+        Err(wasi::ERRNO_SPIPE)
+    }
+
+    fn pwrite(&mut self, _buf: &[u8], _offset: Filesize) -> Result<Size, Errno> {
+        Err(wasi::ERRNO_SPIPE)
+    }
+
+    fn seek(&mut self, _offset: Filedelta, _whence: Whence) -> Result<Filesize, Errno> {
+        Err(wasi::ERRNO_SPIPE)
+    }
+
+    fn filestat_get(&self) -> Result<Filestat, Errno> {
+        Ok(Filestat {
+            dev: 0,
+            ino: 0,
+            filetype: self.filetype(),
+            nlink: 1,
+            size: 0,
+            atim: 0,
+            mtim: 0,
+            ctim: 0,
+        })
+    }
+
+    fn filestat_set_size(&mut self, _size: Filesize) -> Result<(), Errno> {
+        Err(wasi::ERRNO_INVAL)
+    }
+
+    fn filestat_set_times(
+        &mut self,
+        _atim: Timestamp,
+        _mtim: Timestamp,
+        _fst_flags: Fstflags,
+    ) -> Result<(), Errno> {
+        Err(wasi::ERRNO_NOTSUP)
+    }
+
+    fn advise(&mut self, _offset: Filesize, _len: Filesize, _advice: Advice) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    fn allocate(&mut self, _offset: Filesize, _len: Filesize) -> Result<(), Errno> {
+        Err(wasi::ERRNO_NOTSUP)
+    }
+
+    fn sync(&mut self) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    fn datasync(&mut self) -> Result<(), Errno> {
+        self.sync()
+    }
+
+    fn readdir(&self, _cookie: Dircookie) -> Result<Vec<Direntry>, Errno> {
+        Err(wasi::ERRNO_NOTDIR)
+    }
+
+    fn close(&mut self) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    // Sockets
+
+    fn sock_accept(&mut self, _flags: Fdflags) -> Result<HandleRef, Errno> {
+        Err(wasi::ERRNO_NOTSOCK)
+    }
+
+    fn sock_recv(&mut self, _buf: &mut [u8], _flags: Riflags) -> Result<(Size, Roflags), Errno> {
+        Err(wasi::ERRNO_NOTSOCK)
+    }
+
+    fn sock_send(&mut self, _buf: &[u8], _flags: Siflags) -> Result<Size, Errno> {
+        Err(wasi::ERRNO_NOTSOCK)
+    }
+
+    fn sock_shutdown(&mut self, _how: Sdflags) -> Result<(), Errno> {
+        Err(wasi::ERRNO_NOTSOCK)
+    }
+
+    // Readiness as reported by `poll_oneoff`, None while reading or writing would block
+
+    fn poll_read(&self) -> Option<Readiness> {
+        Some(Readiness::default())
+    }
+
+    fn poll_write(&self) -> Option<Readiness> {
+        Some(Readiness::default())
+    }
+
+    // Paths
+
+    fn path_open(
+        &self,
+        _dirflags: Lookupflags,
+        _path: &str,
+        _oflags: Oflags,
+        _rights: Rights,
+        _fdflags: Fdflags,
+    ) -> Result<HandleRef, Errno> {
+        Err(wasi::ERRNO_NOTDIR)
+    }
+
+    fn path_create_directory(&self, _path: &str) -> Result<(), Errno> {
+        Err(wasi::ERRNO_NOTDIR)
+    }
+
+    fn path_filestat_get(&self, _flags: Lookupflags, _path: &str) -> Result<Filestat, Errno> {
+        Err(wasi::ERRNO_NOTDIR)
+    }
+
+    fn path_filestat_set_times(
+        &self,
+        _flags: Lookupflags,
+        _path: &str,
+        _atim: Timestamp,
+        _mtim: Timestamp,
+        _fst_flags: Fstflags,
+    ) -> Result<(), Errno> {
+        Err(wasi::ERRNO_NOTDIR)
+    }
+
+    fn path_link(
+        &self,
+        _old_flags: Lookupflags,
+        _old_path: &str,
+        _new_dir: &dyn Handle,
+        _new_path: &str,
+    ) -> Result<(), Errno> {
+        Err(wasi::ERRNO_NOTDIR)
+    }
+
+    fn path_readlink(&self, _path: &str) -> Result<String, Errno> {
+        Err(wasi::ERRNO_NOTDIR)
+    }
+
+    fn path_remove_directory(&self, _path: &str) -> Result<(), Errno> {
+        Err(wasi::ERRNO_NOTDIR)
+    }
+
+    fn path_rename(
+        &self,
+        _old_path: &str,
+        _new_dir: &dyn Handle,
+        _new_path: &str,
+    ) -> Result<(), Errno> {
+        Err(wasi::ERRNO_NOTDIR)
+    }
+
+    fn path_symlink(&self, _old_path: &str, _new_path: &str) -> Result<(), Errno> {
+        Err(wasi::ERRNO_NOTDIR)
+    }
+
+    fn path_unlink_file(&self, _path: &str) -> Result<(), Errno> {
+        Err(wasi::ERRNO_NOTDIR)
+    }
+}
+
+// Table
+
+pub struct Entry {
+    pub handle: HandleRef,
+    pub flags: Fdflags,
+    pub rights_base: Rights,
+    pub rights_inheriting: Rights,
+}
+
+impl Entry {
+    pub fn new(handle: impl Handle) -> Self {
+        Self {
+            handle: Rc::new(RefCell::new(handle)),
+            flags: 0,
+            rights_base: RIGHTS_ALL,
+            rights_inheriting: RIGHTS_ALL,
+        }
+    }
+
+    fn check(&self, rights: Rights) -> Result<HandleRef, Errno> {
+        match self.rights_base & rights {
+            0 => Err(wasi::ERRNO_NOTCAPABLE),
+            _ => Ok(self.handle.clone()),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct FdTable {
+    entries: BTreeMap<Fd, Entry>,
+}
+
+impl FdTable {
+    pub const fn new() -> Self {
+        Self {
+            entries: BTreeMap::new(),
+        }
+    }
+
+    // Descriptors 0 to 2 are left for stdio and only ever assigned explicitly
+    pub fn insert(&mut self, entry: Entry) -> Fd {
+        let fd = (3..).find(|fd| !self.entries.contains_key(fd)).unwrap();
+        self.entries.insert(fd, entry);
+        fd
+    }
+
+    pub fn insert_at(&mut self, fd: Fd, entry: Entry) -> Option<Entry> {
+        self.entries.insert(fd, entry)
+    }
+
+    pub fn get(&self, fd: Fd) -> Result<&Entry, Errno> {
+        self.entries.get(&fd).ok_or(wasi::ERRNO_BADF)
+    }
+
+    pub fn get_mut(&mut self, fd: Fd) -> Result<&mut Entry, Errno> {
+        self.entries.get_mut(&fd).ok_or(wasi::ERRNO_BADF)
+    }
+
+    pub fn remove(&mut self, fd: Fd) -> Result<Entry, Errno> {
+        self.entries.remove(&fd).ok_or(wasi::ERRNO_BADF)
+    }
+
+    // Both descriptors have to be open, the one being replaced is closed as part of the move
+    pub fn renumber(&mut self, fd: Fd, to: Fd) -> Result<Option<Entry>, Errno> {
+        self.get(fd)?;
+        self.get(to)?;
+
+        if fd == to {
+            return Ok(None);
+        }
+
+        let entry = self.remove(fd)?;
+        Ok(self.entries.insert(to, entry))
+    }
+}
+
+static mut TABLE: FdTable = FdTable::new();
+static mut INSTALLED: bool = false;
+
+#[cfg(test)]
+pub(crate) unsafe fn clear() {
+    TABLE = FdTable::new();
+}
+
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn insert(entry: Entry) -> Fd {
+    (*addr_of_mut!(TABLE)).insert(entry)
+}
+
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn insert_at(fd: Fd, entry: Entry) {
+    if let Some(old) = (*addr_of_mut!(TABLE)).insert_at(fd, entry) {
+        let _ = old.handle.borrow_mut().close();
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn get(fd: Fd) -> Result<HandleRef, Errno> {
+    (*addr_of!(TABLE)).get(fd).map(|e| e.handle.clone())
+}
+
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn rights(fd: Fd) -> Result<(Rights, Rights), Errno> {
+    (*addr_of!(TABLE))
+        .get(fd)
+        .map(|e| (e.rights_base, e.rights_inheriting))
+}
+
+fn with<T>(f: impl FnOnce(&mut FdTable) -> Result<T, Errno>) -> Result<T, Errno> {
+    f(unsafe { &mut *addr_of_mut!(TABLE) })
+}
+
+// Handles are cloned out of the table before being called into, so a handle is free to use
+// the table itself (e.g. to open new descriptors) while serving a call
+pub(crate) fn handle(fd: Fd, rights: Rights) -> Result<HandleRef, Errno> {
+    with(|t| t.get(fd)?.check(rights))
+}
+
+/// Registers polyfills for every `fd_*` call other than the prestat pair, as well as every
+/// `path_*` and `sock_*` call, dispatching to the handles in the table. Registering a handle
+/// does this the first time only, so polyfills set after that are left in place.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn install() {
+    INSTALLED = true;
+
+    fd::set::fd_advise(fd_advise);
+    fd::set::fd_allocate(fd_allocate);
+    fd::set::fd_close(fd_close);
+    fd::set::fd_datasync(fd_datasync);
+    fd::set::fd_fdstat_get(fd_fdstat_get);
+    fd::set::fd_fdstat_set_flags(fd_fdstat_set_flags);
+    fd::set::fd_fdstat_set_rights(fd_fdstat_set_rights);
+    fd::set::fd_filestat_get(fd_filestat_get);
+    fd::set::fd_filestat_set_size(fd_filestat_set_size);
+    fd::set::fd_filestat_set_times(fd_filestat_set_times);
+    fd::set::fd_pread(fd_pread);
+    fd::set::fd_pwrite(fd_pwrite);
+    fd::set::fd_read(fd_read);
+    fd::set::fd_readdir(fd_readdir);
+    fd::set::fd_renumber(fd_renumber);
+    fd::set::fd_seek(fd_seek);
+    fd::set::fd_sync(fd_sync);
+    fd::set::fd_tell(fd_tell);
+    fd::set::fd_write(fd_write);
+
+    path::set::path_create_directory(path_create_directory);
+    path::set::path_filestat_get(path_filestat_get);
+    path::set::path_filestat_set_times(path_filestat_set_times);
+    path::set::path_link(path_link);
+    path::set::path_open(path_open);
+    path::set::path_readlink(path_readlink);
+    path::set::path_remove_directory(path_remove_directory);
+    path::set::path_rename(path_rename);
+    path::set::path_symlink(path_symlink);
+    path::set::path_unlink_file(path_unlink_file);
+
+    sock::set::sock_accept(sock_accept);
+    sock::set::sock_recv(sock_recv);
+    sock::set::sock_send(sock_send);
+    sock::set::sock_shutdown(sock_shutdown);
+}
+
+pub(crate) unsafe fn ensure_installed() {
+    if !INSTALLED {
+        install();
+    }
+}
+
+// Polyfills
+
+fn fd_advise(fd: Fd, offset: Filesize, len: Filesize, advice: Advice) -> Errno {
+    mem::errno(
+        handle(fd, wasi::RIGHTS_FD_ADVISE).and_then(|h| h.borrow_mut().advise(offset, len, advice)),
+    )
+}
+
+fn fd_allocate(fd: Fd, offset: Filesize, len: Filesize) -> Errno {
+    mem::errno(
+        handle(fd, wasi::RIGHTS_FD_ALLOCATE).and_then(|h| h.borrow_mut().allocate(offset, len)),
+    )
+}
+
+fn fd_close(fd: Fd) -> Errno {
+    mem::errno(with(|t| t.remove(fd)).and_then(|e| e.handle.borrow_mut().close()))
+}
+
+fn fd_datasync(fd: Fd) -> Errno {
+    mem::errno(handle(fd, wasi::RIGHTS_FD_DATASYNC).and_then(|h| h.borrow_mut().datasync()))
+}
+
+fn fd_fdstat_get(fd: Fd, rp0: *mut Fdstat) -> Errno {
+    mem::errno(with(|t| {
+        let e = t.get(fd)?;
+        let stat = Fdstat {
+            fs_filetype: e.handle.borrow().filetype(),
+            fs_flags: e.flags,
+            fs_rights_base: e.rights_base,
+            fs_rights_inheriting: e.rights_inheriting,
+        };
+
+        unsafe { *rp0 = stat };
+        Ok(())
+    }))
+}
+
+fn fd_fdstat_set_flags(fd: Fd, flags: Fdflags) -> Errno {
+    mem::errno(with(|t| {
+        let e = t.get_mut(fd)?;
+        e.check(wasi::RIGHTS_FD_FDSTAT_SET_FLAGS)?;
+        e.flags = flags;
+
+        Ok(())
+    }))
+}
+
+// Rights can only ever be dropped, never gained
+fn fd_fdstat_set_rights(fd: Fd, fs_rights_base: Rights, fs_rights_inheriting: Rights) -> Errno {
+    mem::errno(with(|t| {
+        let e = t.get_mut(fd)?;
+        if fs_rights_base & !e.rights_base != 0 || fs_rights_inheriting & !e.rights_inheriting != 0
+        {
+            return Err(wasi::ERRNO_NOTCAPABLE);
+        }
+
+        e.rights_base = fs_rights_base;
+        e.rights_inheriting = fs_rights_inheriting;
+        Ok(())
+    }))
+}
+
+fn fd_filestat_get(fd: Fd, rp0: *mut Filestat) -> Errno {
+    mem::errno(handle(fd, wasi::RIGHTS_FD_FILESTAT_GET).and_then(|h| {
+        let stat = h.borrow().filestat_get()?;
+
+        unsafe { *rp0 = stat };
+        Ok(())
+    }))
+}
+
+fn fd_filestat_set_size(fd: Fd, size: Filesize) -> Errno {
+    mem::errno(
+        handle(fd, wasi::RIGHTS_FD_FILESTAT_SET_SIZE)
+            .and_then(|h| h.borrow_mut().filestat_set_size(size)),
+    )
+}
+
+fn fd_filestat_set_times(fd: Fd, atim: Timestamp, mtim: Timestamp, fst_flags: Fstflags) -> Errno {
+    mem::errno(
+        handle(fd, wasi::RIGHTS_FD_FILESTAT_SET_TIMES)
+            .and_then(|h| h.borrow_mut().filestat_set_times(atim, mtim, fst_flags)),
+    )
+}
+
+fn fd_pread(fd: Fd, iovs: *const Iovec, len: i32, offset: Filesize, rp0: *mut Size) -> Errno {
+    mem::errno(handle(fd, wasi::RIGHTS_FD_READ).and_then(|h| {
+        let mut h = h.borrow_mut();
+
+        let mut n = 0;
+        for buf in unsafe { mem::bufs_mut(iovs, len) } {
+            let at = offset.checked_add(n as Filesize).ok_or(wasi::ERRNO_FBIG)?;
+            let m = h.pread(buf, at)?;
+            n += m;
+            if m < buf.len() {
+                break;
+            }
+        }
+
+        unsafe { *rp0 = n };
+        Ok(())
+    }))
+}
+
+fn fd_pwrite(fd: Fd, iovs: *const Iovec, iovs_len: i32, offset: Filesize, rp0: *mut Size) -> Errno {
+    mem::errno(handle(fd, wasi::RIGHTS_FD_WRITE).and_then(|h| {
+        let mut h = h.borrow_mut();
+
+        let mut n = 0;
+        for buf in unsafe { mem::bufs(iovs, iovs_len) } {
+            let at = offset.checked_add(n as Filesize).ok_or(wasi::ERRNO_FBIG)?;
+            let m = h.pwrite(buf, at)?;
+            n += m;
+            if m < buf.len() {
+                break;
+            }
+        }
+
+        unsafe { *rp0 = n };
+        Ok(())
+    }))
+}
+
+fn fd_read(fd: Fd, iovs: *const Iovec, iovs_len: i32, rp0: *mut Size) -> Errno {
+    mem::errno(handle(fd, wasi::RIGHTS_FD_READ).and_then(|h| {
+        let mut h = h.borrow_mut();
+
+        let mut n = 0;
+        for buf in unsafe { mem::bufs_mut(iovs, iovs_len) } {
+            let m = h.read(buf)?;
+            n += m;
+            if m < buf.len() {
+                break;
+            }
+        }
+
+        unsafe { *rp0 = n };
+        Ok(())
+    }))
+}
+
+fn fd_readdir(fd: Fd, buf: *mut u8, buf_len: Size, cookie: Dircookie, rp0: *mut Size) -> Errno {
+    mem::errno(handle(fd, wasi::RIGHTS_FD_READDIR).and_then(|h| {
+        let entries = h.borrow().readdir(cookie)?;
+
+        // Entries are serialized until the buffer is full, the last one possibly truncated,
+        // which tells the caller to come back with a larger buffer. Headers are laid out as a
+        // little-endian `Dirent`, padding zeroed
+        let mut out = vec![];
+        for e in entries {
+            if out.len() >= buf_len {
+                break;
+            }
+
+            let start = out.len();
+            out.extend_from_slice(&e.next.to_le_bytes());
+            out.extend_from_slice(&e.ino.to_le_bytes());
+            out.extend_from_slice(&(e.name.len() as u32).to_le_bytes());
+            out.push(e.filetype.raw());
+            out.resize(start + size_of::<Dirent>(), 0);
+            out.extend_from_slice(e.name.as_bytes());
+        }
+
+        let n = out.len().min(buf_len);
+        unsafe {
+            ptr::copy_nonoverlapping(out.as_ptr(), buf, n);
+            *rp0 = n;
+        }
+        Ok(())
+    }))
+}
+
+fn fd_renumber(fd: Fd, to: Fd) -> Errno {
+    mem::errno(with(|t| t.renumber(fd, to)).and_then(|old| match old {
+        Some(e) => e.handle.borrow_mut().close(),
+        None => Ok(()),
+    }))
+}
+
+fn fd_seek(fd: Fd, offset: Filedelta, whence: Whence, rp0: *mut Filesize) -> Errno {
+    // A plain position query only requires the right to tell
+    let rights = match (offset, whence) {
+        (0, wasi::WHENCE_CUR) => wasi::RIGHTS_FD_SEEK | wasi::RIGHTS_FD_TELL,
+        _ => wasi::RIGHTS_FD_SEEK,
+    };
+
+    mem::errno(handle(fd, rights).and_then(|h| {
+        let pos = h.borrow_mut().seek(offset, whence)?;
+
+        unsafe { *rp0 = pos };
+        Ok(())
+    }))
+}
+
+fn fd_sync(fd: Fd) -> Errno {
+    mem::errno(handle(fd, wasi::RIGHTS_FD_SYNC).and_then(|h| h.borrow_mut().sync()))
+}
+
+fn fd_tell(fd: Fd, rp0: *mut Filesize) -> Errno {
+    mem::errno(handle(fd, wasi::RIGHTS_FD_TELL).and_then(|h| {
+        let pos = h.borrow_mut().seek(0, wasi::WHENCE_CUR)?;
+
+        unsafe { *rp0 = pos };
+        Ok(())
+    }))
+}
+
+fn fd_write(fd: Fd, iovs: *const Iovec, iovs_len: i32, rp0: *mut Size) -> Errno {
+    mem::errno(with(|t| Ok(t.get(fd)?.flags)).and_then(|flags| {
+        let h = handle(fd, wasi::RIGHTS_FD_WRITE)?;
+        let mut h = h.borrow_mut();
+
+        if flags & wasi::FDFLAGS_APPEND != 0 {
+            h.seek(0, wasi::WHENCE_END)?;
+        }
+
+        let mut n = 0;
+        for buf in unsafe { mem::bufs(iovs, iovs_len) } {
+            let m = h.write(buf)?;
+            n += m;
+            if m < buf.len() {
+                break;
+            }
+        }
+
+        unsafe { *rp0 = n };
+        Ok(())
+    }))
+}
+
+fn path_create_directory(fd: Fd, path: *const u8, path_len: i32) -> Errno {
+    mem::errno(
+        handle(fd, wasi::RIGHTS_PATH_CREATE_DIRECTORY).and_then(|h| {
+            let path = unsafe { mem::str(path, path_len)? };
+            h.borrow().path_create_directory(path)
+        }),
+    )
+}
+
+fn path_filestat_get(
+    fd: Fd,
+    flags: Lookupflags,
+    path: *const u8,
+    path_len: i32,
+    rp0: *mut Filestat,
+) -> Errno {
+    mem::errno(handle(fd, wasi::RIGHTS_PATH_FILESTAT_GET).and_then(|h| {
+        let path = unsafe { mem::str(path, path_len)? };
+        let stat = h.borrow().path_filestat_get(flags, path)?;
+
+        unsafe { *rp0 = stat };
+        Ok(())
+    }))
+}
+
+fn path_filestat_set_times(
+    fd: Fd,
+    flags: Lookupflags,
+    path: *const u8,
+    path_len: i32,
+    atim: Timestamp,
+    mtim: Timestamp,
+    fst_flags: Fstflags,
+) -> Errno {
+    mem::errno(
+        handle(fd, wasi::RIGHTS_PATH_FILESTAT_SET_TIMES).and_then(|h| {
+            let path = unsafe { mem::str(path, path_len)? };
+            h.borrow()
+                .path_filestat_set_times(flags, path, atim, mtim, fst_flags)
+        }),
+    )
+}
+
+fn path_link(
+    old_fd: Fd,
+    old_flags: Lookupflags,
+    old_path: *const u8,
+    old_path_len: i32,
+    new_fd: Fd,
+    new_path: *const u8,
+    new_path_len: i32,
+) -> Errno {
+    mem::errno(
+        handle(old_fd, wasi::RIGHTS_PATH_LINK_SOURCE).and_then(|old_dir| {
+            let new_dir = handle(new_fd, wasi::RIGHTS_PATH_LINK_TARGET)?;
+            let old_path = unsafe { mem::str(old_path, old_path_len)? };
+            let new_path = unsafe { mem::str(new_path, new_path_len)? };
+
+            let new_dir = new_dir.borrow();
+            old_dir
+                .borrow()
+                .path_link(old_flags, old_path, &*new_dir, new_path)
+        }),
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn path_open(
+    fd: Fd,
+    dirflags: Lookupflags,
+    path: *const u8,
+    path_len: i32,
+    oflags: Oflags,
+    fs_rights_base: Rights,
+    fs_rights_inheriting: Rights,
+    fdflags: Fdflags,
+    rp0: *mut Fd,
+) -> Errno {
+    mem::errno(handle(fd, wasi::RIGHTS_PATH_OPEN).and_then(|h| {
+        let path = unsafe { mem::str(path, path_len)? };
+
+        // An opened descriptor never has more rights than its directory hands down
+        let (_, inheriting) = unsafe { rights(fd)? };
+        let rights_base = fs_rights_base & inheriting;
+
+        let opened = h
+            .borrow()
+            .path_open(dirflags, path, oflags, rights_base, fdflags)?;
+        let entry = Entry {
+            handle: opened,
+            flags: fdflags,
+            rights_base,
+            rights_inheriting: fs_rights_inheriting & inheriting,
+        };
+
+        unsafe { *rp0 = insert(entry) };
+        Ok(())
+    }))
+}
+
+fn path_readlink(
+    fd: Fd,
+    path: *const u8,
+    path_len: i32,
+    buf: *mut u8,
+    buf_len: Size,
+    rp0: *mut Size,
+) -> Errno {
+    mem::errno(handle(fd, wasi::RIGHTS_PATH_READLINK).and_then(|h| {
+        let path = unsafe { mem::str(path, path_len)? };
+        let target = h.borrow().path_readlink(path)?;
+
+        let n = target.len().min(buf_len);
+        unsafe {
+            ptr::copy_nonoverlapping(target.as_ptr(), buf, n);
+            *rp0 = n;
+        }
+        Ok(())
+    }))
+}
+
+fn path_remove_directory(fd: Fd, path: *const u8, path_len: i32) -> Errno {
+    mem::errno(
+        handle(fd, wasi::RIGHTS_PATH_REMOVE_DIRECTORY).and_then(|h| {
+            let path = unsafe { mem::str(path, path_len)? };
+            h.borrow().path_remove_directory(path)
+        }),
+    )
+}
+
+fn path_rename(
+    fd: Fd,
+    old_path: *const u8,
+    old_path_len: i32,
+    new_fd: Fd,
+    new_path: *const u8,
+    new_path_len: i32,
+) -> Errno {
+    mem::errno(
+        handle(fd, wasi::RIGHTS_PATH_RENAME_SOURCE).and_then(|old_dir| {
+            let new_dir = handle(new_fd, wasi::RIGHTS_PATH_RENAME_TARGET)?;
+            let old_path = unsafe { mem::str(old_path, old_path_len)? };
+            let new_path = unsafe { mem::str(new_path, new_path_len)? };
+
+            let new_dir = new_dir.borrow();
+            old_dir.borrow().path_rename(old_path, &*new_dir, new_path)
+        }),
+    )
+}
+
+fn path_symlink(
+    old_path: *const u8,
+    old_path_len: i32,
+    fd: Fd,
+    new_path: *const u8,
+    new_path_len: i32,
+) -> Errno {
+    mem::errno(handle(fd, wasi::RIGHTS_PATH_SYMLINK).and_then(|h| {
+        let old_path = unsafe { mem::str(old_path, old_path_len)? };
+        let new_path = unsafe { mem::str(new_path, new_path_len)? };
+        h.borrow().path_symlink(old_path, new_path)
+    }))
+}
+
+fn path_unlink_file(fd: Fd, path: *const u8, path_len: i32) -> Errno {
+    mem::errno(handle(fd, wasi::RIGHTS_PATH_UNLINK_FILE).and_then(|h| {
+        let path = unsafe { mem::str(path, path_len)? };
+        h.borrow().path_unlink_file(path)
+    }))
+}
+
+fn sock_accept(fd: Fd, flags: Fdflags, rp0: *mut Fd) -> Errno {
+    mem::errno(handle(fd, wasi::RIGHTS_SOCK_ACCEPT).and_then(|h| {
+        let accepted = h.borrow_mut().sock_accept(flags)?;
+
+        let (_, inheriting) = unsafe { rights(fd)? };
+        let entry = Entry {
+            handle: accepted,
+            flags,
+            rights_base: inheriting,
+            rights_inheriting: inheriting,
+        };
+
+        unsafe { *rp0 = insert(entry) };
+        Ok(())
+    }))
+}
+
+// Buffers are gathered into one contiguous buffer so each call is served in one go, which a
+// peek that spans several buffers relies on
+fn sock_recv(
+    fd: Fd,
+    ri_data: *const Iovec,
+    ri_data_len: i32,
+    ri_flags: Riflags,
+    rp0: *mut Size,
+    rp1: *mut Roflags,
+) -> Errno {
+    mem::errno(handle(fd, wasi::RIGHTS_FD_READ).and_then(|h| {
+        let len = unsafe { mem::iovecs(ri_data, ri_data_len) }
+            .iter()
+            .map(|iov| iov.buf_len)
+            .sum();
+
+        let mut data = vec![0; len];
+        let (n, flags) = h.borrow_mut().sock_recv(&mut data, ri_flags)?;
+
+        let mut rest = &data[..n];
+        for buf in unsafe { mem::bufs_mut(ri_data, ri_data_len) } {
+            let m = buf.len().min(rest.len());
+            buf[..m].copy_from_slice(&rest[..m]);
+            rest = &rest[m..];
+        }
+
+        unsafe {
+            *rp0 = n;
+            *rp1 = flags;
+        }
+        Ok(())
+    }))
+}
+
+fn sock_send(
+    fd: Fd,
+    si_data: *const Ciovec,
+    si_data_len: i32,
+    si_flags: Siflags,
+    rp0: *mut Size,
+) -> Errno {
+    mem::errno(handle(fd, wasi::RIGHTS_FD_WRITE).and_then(|h| {
+        let data: Vec<u8> = unsafe { mem::bufs(si_data as *const Iovec, si_data_len) }
+            .flatten()
+            .copied()
+            .collect();
+
+        let n = h.borrow_mut().sock_send(&data, si_flags)?;
+
+        unsafe { *rp0 = n };
+        Ok(())
+    }))
+}
+
+fn sock_shutdown(fd: Fd, how: Sdflags) -> Errno {
+    mem::errno(
+        handle(fd, wasi::RIGHTS_SOCK_SHUTDOWN).and_then(|h| h.borrow_mut().sock_shutdown(how)),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::testing;
+
+    struct Null;
+
+    impl Handle for Null {
+        fn filetype(&self) -> Filetype {
+            wasi::FILETYPE_CHARACTER_DEVICE
+        }
+    }
+
+    // Reads zeroes and takes any write, at any offset
+    struct Zero;
+
+    impl Handle for Zero {
+        fn filetype(&self) -> Filetype {
+            wasi::FILETYPE_REGULAR_FILE
+        }
+
+        fn pread(&mut self, buf: &mut [u8], _offset: Filesize) -> Result<Size, Errno> {
+            buf.fill(0);
+            Ok(buf.len())
+        }
+
+        fn pwrite(&mut self, buf: &[u8], _offset: Filesize) -> Result<Size, Errno> {
+            Ok(buf.len())
+        }
+
+        fn readdir(&self, _cookie: Dircookie) -> Result<Vec<Direntry>, Errno> {
+            Ok(vec![Direntry {
+                next: 1,
+                ino: 2,
+                filetype: wasi::FILETYPE_REGULAR_FILE,
+                name: "ab".to_string(),
+            }])
+        }
+    }
+
+    #[test]
+    fn test_insert_lowest_free() -> Result<(), Errno> {
+        let mut t = FdTable::new();
+
+        assert_eq!(t.insert(Entry::new(Null)), 3);
+        assert_eq!(t.insert(Entry::new(Null)), 4);
+
+        t.remove(3)?;
+        assert_eq!(t.insert(Entry::new(Null)), 3);
+
+        t.insert_at(1, Entry::new(Null));
+        assert_eq!(t.insert(Entry::new(Null)), 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_renumber() -> Result<(), Errno> {
+        let mut t = FdTable::new();
+
+        let a = t.insert(Entry::new(Null));
+        let b = t.insert(Entry::new(Null));
+        t.get_mut(a)?.flags = wasi::FDFLAGS_APPEND;
+
+        assert_eq!(t.renumber(a, 10).err(), Some(wasi::ERRNO_BADF));
+        assert_eq!(t.renumber(10, a).err(), Some(wasi::ERRNO_BADF));
+
+        assert!(t.renumber(a, a)?.is_none());
+        assert!(t.renumber(a, b)?.is_some());
+
+        assert_eq!(t.get(a).err(), Some(wasi::ERRNO_BADF));
+        assert_eq!(t.get(b)?.flags, wasi::FDFLAGS_APPEND);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_polyfills() {
+        let _lock = testing::LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        let fd = unsafe { insert(Entry::new(Zero)) };
+        let mut buf = [0xff; 32];
+        let mut n = 0;
+        assert_eq!(
+            fd_readdir(fd, buf.as_mut_ptr(), buf.len(), 0, &mut n),
+            wasi::ERRNO_SUCCESS
+        );
+        assert_eq!(n, 26);
+        assert_eq!(
+            buf[..n],
+            [1, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 4, 0, 0, 0, b'a', b'b']
+        );
+
+        // Offsets past the end of the file space fail rather than wrap
+        let iovs = [Iovec {
+            buf: buf.as_mut_ptr(),
+            buf_len: 1,
+        }; 2];
+        let offset = Filesize::MAX;
+        assert_eq!(
+            fd_pread(fd, iovs.as_ptr(), 2, offset, &mut n),
+            wasi::ERRNO_FBIG
+        );
+        assert_eq!(
+            fd_pwrite(fd, iovs.as_ptr(), 2, offset, &mut n),
+            wasi::ERRNO_FBIG
+        );
+        assert_eq!(fd_close(fd), wasi::ERRNO_SUCCESS);
+    }
+}
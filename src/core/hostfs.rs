@@ -0,0 +1,637 @@
+use std::{
+    any::Any,
+    cell::RefCell,
+    fs::{self, File, FileTimes, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    os::unix::fs::{DirEntryExt, FileExt, FileTypeExt, MetadataExt},
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::OnceLock,
+    time::{Duration, Instant, SystemTime},
+};
+
+use wasi::{
+    Clockid, Dircookie, Errno, Fdflags, Filedelta, Filesize, Filestat, Filetype, Fstflags,
+    Lookupflags, Oflags, Rights, Size, Timestamp, Whence,
+};
+
+use crate::core::{
+    clock,
+    fd::io_errno,
+    fd_table::{Direntry, Handle, HandleRef, Readiness},
+    preopens, random,
+};
+
+const MAX_SYMLINKS: usize = 40;
+
+// Rights that need the host file opened for writing
+const WRITE_RIGHTS: Rights =
+    wasi::RIGHTS_FD_WRITE | wasi::RIGHTS_FD_ALLOCATE | wasi::RIGHTS_FD_FILESTAT_SET_SIZE;
+
+// Metadata
+
+fn filetype(ft: fs::FileType) -> Filetype {
+    match ft {
+        ft if ft.is_dir() => wasi::FILETYPE_DIRECTORY,
+        ft if ft.is_file() => wasi::FILETYPE_REGULAR_FILE,
+        ft if ft.is_symlink() => wasi::FILETYPE_SYMBOLIC_LINK,
+        ft if ft.is_block_device() => wasi::FILETYPE_BLOCK_DEVICE,
+        ft if ft.is_char_device() => wasi::FILETYPE_CHARACTER_DEVICE,
+        ft if ft.is_socket() => wasi::FILETYPE_SOCKET_STREAM,
+        _ => wasi::FILETYPE_UNKNOWN,
+    }
+}
+
+fn nanos(secs: i64, nsecs: i64) -> Timestamp {
+    (secs.max(0) as Timestamp) * 1_000_000_000 + nsecs.max(0) as Timestamp
+}
+
+fn stat(m: &fs::Metadata) -> Filestat {
+    Filestat {
+        dev: m.dev(),
+        ino: m.ino(),
+        filetype: filetype(m.file_type()),
+        nlink: m.nlink(),
+        size: m.size(),
+        atim: nanos(m.atime(), m.atime_nsec()),
+        mtim: nanos(m.mtime(), m.mtime_nsec()),
+        ctim: nanos(m.ctime(), m.ctime_nsec()),
+    }
+}
+
+fn set_times(
+    file: &File,
+    atim: Timestamp,
+    mtim: Timestamp,
+    fst_flags: Fstflags,
+) -> Result<(), Errno> {
+    let time = |t: Timestamp, set: Fstflags, now: Fstflags| match fst_flags & (set | now) {
+        0 => Ok(None),
+        f if f == set => Ok(Some(SystemTime::UNIX_EPOCH + Duration::from_nanos(t))),
+        f if f == now => Ok(Some(SystemTime::now())),
+        _ => Err(wasi::ERRNO_INVAL),
+    };
+
+    let mut times = FileTimes::new();
+    if let Some(t) = time(atim, wasi::FSTFLAGS_ATIM, wasi::FSTFLAGS_ATIM_NOW)? {
+        times = times.set_accessed(t);
+    }
+    if let Some(t) = time(mtim, wasi::FSTFLAGS_MTIM, wasi::FSTFLAGS_MTIM_NOW)? {
+        times = times.set_modified(t);
+    }
+
+    file.set_times(times).map_err(io_errno)
+}
+
+// Handles
+
+struct HostFile {
+    file: File,
+}
+
+impl Handle for HostFile {
+    fn filetype(&self) -> Filetype {
+        self.file
+            .metadata()
+            .map_or(wasi::FILETYPE_UNKNOWN, |m| filetype(m.file_type()))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<Size, Errno> {
+        self.file.read(buf).map_err(io_errno)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<Size, Errno> {
+        self.file.write(buf).map_err(io_errno)
+    }
+
+    fn pread(&mut self, buf: &mut [u8], offset: Filesize) -> Result<Size, Errno> {
+        self.file.read_at(buf, offset).map_err(io_errno)
+    }
+
+    fn pwrite(&mut self, buf: &[u8], offset: Filesize) -> Result<Size, Errno> {
+        self.file.write_at(buf, offset).map_err(io_errno)
+    }
+
+    fn seek(&mut self, offset: Filedelta, whence: Whence) -> Result<Filesize, Errno> {
+        let pos = match whence {
+            wasi::WHENCE_CUR => SeekFrom::Current(offset),
+            wasi::WHENCE_END => SeekFrom::End(offset),
+            _ => SeekFrom::Start(u64::try_from(offset).map_err(|_| wasi::ERRNO_INVAL)?),
+        };
+
+        self.file.seek(pos).map_err(io_errno)
+    }
+
+    fn filestat_get(&self) -> Result<Filestat, Errno> {
+        Ok(stat(&self.file.metadata().map_err(io_errno)?))
+    }
+
+    fn filestat_set_size(&mut self, size: Filesize) -> Result<(), Errno> {
+        self.file.set_len(size).map_err(io_errno)
+    }
+
+    fn filestat_set_times(
+        &mut self,
+        atim: Timestamp,
+        mtim: Timestamp,
+        fst_flags: Fstflags,
+    ) -> Result<(), Errno> {
+        set_times(&self.file, atim, mtim, fst_flags)
+    }
+
+    fn allocate(&mut self, offset: Filesize, len: Filesize) -> Result<(), Errno> {
+        let size = offset.checked_add(len).ok_or(wasi::ERRNO_FBIG)?;
+        if self.filestat_get()?.size < size {
+            self.file.set_len(size).map_err(io_errno)?;
+        }
+
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), Errno> {
+        self.file.sync_all().map_err(io_errno)
+    }
+
+    fn datasync(&mut self) -> Result<(), Errno> {
+        self.file.sync_data().map_err(io_errno)
+    }
+
+    fn poll_read(&self) -> Option<Readiness> {
+        let size = self.file.metadata().ok()?.len();
+        let pos = (&self.file).stream_position().ok()?;
+
+        Some(Readiness {
+            nbytes: size.saturating_sub(pos),
+            hangup: false,
+        })
+    }
+}
+
+/// A directory on the host. Guest paths are resolved one component at a time and may not
+/// leave the directory, neither through `..` nor through symlinks.
+pub struct HostDir {
+    // Canonical path of the sandbox, shared by every directory opened from it
+    root: Rc<PathBuf>,
+    // Components below the root
+    path: Vec<String>,
+}
+
+impl HostDir {
+    pub fn new(root: impl AsRef<Path>) -> Result<Self, Errno> {
+        let root = root.as_ref().canonicalize().map_err(io_errno)?;
+        if !root.is_dir() {
+            return Err(wasi::ERRNO_NOTDIR);
+        }
+
+        Ok(Self {
+            root: Rc::new(root),
+            path: vec![],
+        })
+    }
+
+    fn host(&self, path: &[String]) -> PathBuf {
+        let mut host = PathBuf::from(&*self.root);
+        host.extend(path);
+        host
+    }
+
+    fn resolve(&self, path: &str, follow: bool) -> Result<PathBuf, Errno> {
+        let mut stack = self.path.clone();
+        self.walk_from(&mut stack, path, follow, &mut 0)?;
+
+        Ok(self.host(&stack))
+    }
+
+    fn walk_from(
+        &self,
+        stack: &mut Vec<String>,
+        path: &str,
+        follow: bool,
+        depth: &mut usize,
+    ) -> Result<(), Errno> {
+        if path.starts_with('/') {
+            return Err(wasi::ERRNO_PERM);
+        }
+
+        let must_dir = path.ends_with('/');
+        let names: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+        if names.is_empty() {
+            return Err(wasi::ERRNO_NOENT);
+        }
+
+        for (i, name) in names.iter().enumerate() {
+            match *name {
+                "." => continue,
+                ".." if stack.len() == self.path.len() => return Err(wasi::ERRNO_PERM),
+                ".." => {
+                    stack.pop();
+                    continue;
+                }
+                _ => {}
+            }
+
+            stack.push(name.to_string());
+            let last = i == names.len() - 1;
+
+            let meta = match fs::symlink_metadata(self.host(stack)) {
+                Ok(meta) => meta,
+                // Whatever is being created does not exist yet
+                Err(err) if last => match err.kind() {
+                    std::io::ErrorKind::NotFound => break,
+                    _ => return Err(io_errno(err)),
+                },
+                Err(err) => return Err(io_errno(err)),
+            };
+
+            if meta.is_symlink() && (!last || follow || must_dir) {
+                *depth += 1;
+                if *depth > MAX_SYMLINKS {
+                    return Err(wasi::ERRNO_LOOP);
+                }
+
+                let target = fs::read_link(self.host(stack)).map_err(io_errno)?;
+                let target = target.to_str().ok_or(wasi::ERRNO_ILSEQ)?.to_string();
+
+                stack.pop();
+                self.walk_from(stack, &target, true, depth)?;
+            } else if !last && !meta.is_dir() {
+                return Err(wasi::ERRNO_NOTDIR);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn peer<'a>(&self, other: &'a dyn Handle) -> Result<&'a HostDir, Errno> {
+        if other.filetype() != wasi::FILETYPE_DIRECTORY {
+            return Err(wasi::ERRNO_NOTDIR);
+        }
+
+        let any: &dyn Any = other;
+        match any.downcast_ref::<HostDir>() {
+            Some(d) if d.root == self.root => Ok(d),
+            _ => Err(wasi::ERRNO_XDEV),
+        }
+    }
+
+    fn child(&self, host: &Path) -> Result<HostDir, Errno> {
+        let rel = host
+            .strip_prefix(&*self.root)
+            .map_err(|_| wasi::ERRNO_PERM)?;
+        let path = rel
+            .iter()
+            .map(|c| c.to_str().map(str::to_string).ok_or(wasi::ERRNO_ILSEQ))
+            .collect::<Result<_, _>>()?;
+
+        Ok(HostDir {
+            root: self.root.clone(),
+            path,
+        })
+    }
+}
+
+impl Handle for HostDir {
+    fn filetype(&self) -> Filetype {
+        wasi::FILETYPE_DIRECTORY
+    }
+
+    fn filestat_get(&self) -> Result<Filestat, Errno> {
+        Ok(stat(
+            &fs::metadata(self.host(&self.path)).map_err(io_errno)?,
+        ))
+    }
+
+    fn filestat_set_times(
+        &mut self,
+        atim: Timestamp,
+        mtim: Timestamp,
+        fst_flags: Fstflags,
+    ) -> Result<(), Errno> {
+        let dir = File::open(self.host(&self.path)).map_err(io_errno)?;
+        set_times(&dir, atim, mtim, fst_flags)
+    }
+
+    // The host gives no stable cookies, so entries are numbered in name order
+    fn readdir(&self, cookie: Dircookie) -> Result<Vec<Direntry>, Errno> {
+        let host = self.host(&self.path);
+        let meta = fs::metadata(&host).map_err(io_errno)?;
+
+        let mut names = vec![
+            (".".to_string(), meta.ino(), wasi::FILETYPE_DIRECTORY),
+            ("..".to_string(), meta.ino(), wasi::FILETYPE_DIRECTORY),
+        ];
+        let mut entries = vec![];
+        for e in fs::read_dir(&host).map_err(io_errno)? {
+            let e = e.map_err(io_errno)?;
+            let ft = e.file_type().map_err(io_errno)?;
+            if let Ok(name) = e.file_name().into_string() {
+                entries.push((name, e.ino(), filetype(ft)));
+            }
+        }
+        entries.sort();
+        names.extend(entries);
+
+        Ok(names
+            .into_iter()
+            .enumerate()
+            .map(|(i, (name, ino, filetype))| Direntry {
+                next: i as Dircookie + 1,
+                ino,
+                filetype,
+                name,
+            })
+            .filter(|e| e.next > cookie)
+            .collect())
+    }
+
+    fn path_open(
+        &self,
+        dirflags: Lookupflags,
+        path: &str,
+        oflags: Oflags,
+        rights: Rights,
+        fdflags: Fdflags,
+    ) -> Result<HandleRef, Errno> {
+        let follow = dirflags & wasi::LOOKUPFLAGS_SYMLINK_FOLLOW != 0;
+        let host = self.resolve(path, follow)?;
+
+        match fs::symlink_metadata(&host) {
+            Ok(meta) if meta.is_dir() => {
+                if oflags & wasi::OFLAGS_CREAT != 0 && oflags & wasi::OFLAGS_EXCL != 0 {
+                    return Err(wasi::ERRNO_EXIST);
+                }
+                if oflags & wasi::OFLAGS_TRUNC != 0 {
+                    return Err(wasi::ERRNO_ISDIR);
+                }
+
+                return Ok(Rc::new(RefCell::new(self.child(&host)?)));
+            }
+            Ok(meta) if meta.is_symlink() => return Err(wasi::ERRNO_LOOP),
+            Ok(_) if oflags & wasi::OFLAGS_DIRECTORY != 0 => return Err(wasi::ERRNO_NOTDIR),
+            Err(_) if oflags & wasi::OFLAGS_DIRECTORY != 0 && oflags & wasi::OFLAGS_CREAT != 0 => {
+                return Err(wasi::ERRNO_ISDIR)
+            }
+            _ => {}
+        }
+
+        // The file is opened for no more than the rights ask for, though std only creates or
+        // truncates files it opens for writing
+        let trunc = oflags & wasi::OFLAGS_TRUNC != 0;
+        let write = rights & WRITE_RIGHTS != 0 || oflags & wasi::OFLAGS_CREAT != 0 || trunc;
+        let append = write && fdflags & wasi::FDFLAGS_APPEND != 0;
+
+        let mut opts = OpenOptions::new();
+        opts.read(rights & wasi::RIGHTS_FD_READ != 0 || !write)
+            .write(write)
+            .append(append)
+            .truncate(trunc && !append);
+        match oflags & (wasi::OFLAGS_CREAT | wasi::OFLAGS_EXCL) {
+            0 => {}
+            wasi::OFLAGS_CREAT => {
+                opts.create(true);
+            }
+            _ => {
+                opts.create_new(true);
+            }
+        }
+
+        let file = opts.open(&host).map_err(io_errno)?;
+        // std won't truncate a file it opens for appending, so that waits until it is open
+        if trunc && append {
+            file.set_len(0).map_err(io_errno)?;
+        }
+
+        Ok(Rc::new(RefCell::new(HostFile { file })))
+    }
+
+    fn path_create_directory(&self, path: &str) -> Result<(), Errno> {
+        fs::create_dir(self.resolve(path, false)?).map_err(io_errno)
+    }
+
+    fn path_filestat_get(&self, flags: Lookupflags, path: &str) -> Result<Filestat, Errno> {
+        let follow = flags & wasi::LOOKUPFLAGS_SYMLINK_FOLLOW != 0;
+        let meta = fs::symlink_metadata(self.resolve(path, follow)?).map_err(io_errno)?;
+
+        Ok(stat(&meta))
+    }
+
+    fn path_filestat_set_times(
+        &self,
+        flags: Lookupflags,
+        path: &str,
+        atim: Timestamp,
+        mtim: Timestamp,
+        fst_flags: Fstflags,
+    ) -> Result<(), Errno> {
+        let follow = flags & wasi::LOOKUPFLAGS_SYMLINK_FOLLOW != 0;
+        let host = self.resolve(path, follow)?;
+        if fs::symlink_metadata(&host).map_err(io_errno)?.is_symlink() {
+            return Err(wasi::ERRNO_NOTSUP);
+        }
+
+        set_times(&File::open(host).map_err(io_errno)?, atim, mtim, fst_flags)
+    }
+
+    fn path_link(
+        &self,
+        old_flags: Lookupflags,
+        old_path: &str,
+        new_dir: &dyn Handle,
+        new_path: &str,
+    ) -> Result<(), Errno> {
+        let follow = old_flags & wasi::LOOKUPFLAGS_SYMLINK_FOLLOW != 0;
+        let new_dir = self.peer(new_dir)?;
+
+        fs::hard_link(
+            self.resolve(old_path, follow)?,
+            new_dir.resolve(new_path, false)?,
+        )
+        .map_err(io_errno)
+    }
+
+    fn path_readlink(&self, path: &str) -> Result<String, Errno> {
+        let target = fs::read_link(self.resolve(path, false)?).map_err(io_errno)?;
+        target
+            .into_os_string()
+            .into_string()
+            .map_err(|_| wasi::ERRNO_ILSEQ)
+    }
+
+    fn path_remove_directory(&self, path: &str) -> Result<(), Errno> {
+        fs::remove_dir(self.resolve(path, false)?).map_err(io_errno)
+    }
+
+    fn path_rename(
+        &self,
+        old_path: &str,
+        new_dir: &dyn Handle,
+        new_path: &str,
+    ) -> Result<(), Errno> {
+        let new_dir = self.peer(new_dir)?;
+
+        fs::rename(
+            self.resolve(old_path, false)?,
+            new_dir.resolve(new_path, false)?,
+        )
+        .map_err(io_errno)
+    }
+
+    // The target is stored as given; it is checked when a lookup goes through the link
+    fn path_symlink(&self, old_path: &str, new_path: &str) -> Result<(), Errno> {
+        std::os::unix::fs::symlink(old_path, self.resolve(new_path, false)?).map_err(io_errno)
+    }
+
+    fn path_unlink_file(&self, path: &str) -> Result<(), Errno> {
+        let host = self.resolve(path, false)?;
+        if fs::symlink_metadata(&host).map_err(io_errno)?.is_dir() {
+            return Err(wasi::ERRNO_ISDIR);
+        }
+
+        fs::remove_file(host).map_err(io_errno)
+    }
+}
+
+// Clock and randomness
+
+fn host_time(id: Clockid) -> Timestamp {
+    static START: OnceLock<Instant> = OnceLock::new();
+
+    match id {
+        wasi::CLOCKID_REALTIME => SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos() as Timestamp),
+        _ => START.get_or_init(Instant::now).elapsed().as_nanos() as Timestamp,
+    }
+}
+
+fn host_entropy(buf: &mut [u8]) {
+    if let Ok(mut f) = File::open("/dev/urandom") {
+        let _ = f.read_exact(buf);
+    }
+}
+
+/// Serves the guest's `/` from the host directory `root`, and its clocks and randomness from
+/// the host, so shim-based code can run natively.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn install(root: impl AsRef<Path>) -> Result<(), Errno> {
+    let dir = HostDir::new(root)?;
+
+    preopens::install();
+    preopens::register("/", dir);
+    clock::set_host(host_time);
+    random::set_entropy(host_entropy);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("wasi-shim-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn test_sandbox() -> Result<(), Errno> {
+        let tmp = TempDir::new("sandbox");
+        fs::create_dir(tmp.0.join("box")).unwrap();
+        fs::write(tmp.0.join("secret"), b"x").unwrap();
+
+        let dir = HostDir::new(tmp.0.join("box"))?;
+        dir.path_symlink("../secret", "up")?;
+        dir.path_symlink("/etc", "abs")?;
+        dir.path_create_directory("sub")?;
+        dir.path_symlink("sub", "inside")?;
+
+        let follow = wasi::LOOKUPFLAGS_SYMLINK_FOLLOW;
+        assert_eq!(
+            dir.path_open(0, "../secret", 0, 0, 0).err(),
+            Some(wasi::ERRNO_PERM)
+        );
+        assert_eq!(
+            dir.path_open(follow, "up", 0, 0, 0).err(),
+            Some(wasi::ERRNO_PERM)
+        );
+        assert_eq!(
+            dir.path_filestat_get(0, "abs/passwd").err(),
+            Some(wasi::ERRNO_PERM)
+        );
+        assert_eq!(dir.path_readlink("up")?, "../secret");
+
+        let sub = dir.path_open(follow, "inside", wasi::OFLAGS_DIRECTORY, 0, 0)?;
+        assert_eq!(
+            sub.borrow().path_open(0, "../../secret", 0, 0, 0).err(),
+            Some(wasi::ERRNO_PERM)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_files() -> Result<(), Errno> {
+        let tmp = TempDir::new("files");
+        let dir = HostDir::new(&tmp.0)?;
+
+        let f = dir.path_open(
+            0,
+            "a.txt",
+            wasi::OFLAGS_CREAT,
+            wasi::RIGHTS_FD_READ | wasi::RIGHTS_FD_WRITE,
+            0,
+        )?;
+        f.borrow_mut().write(b"hello")?;
+        assert_eq!(fs::read(tmp.0.join("a.txt")).unwrap(), b"hello");
+
+        let mut buf = [0; 3];
+        assert_eq!(f.borrow_mut().pread(&mut buf, 2)?, 3);
+        assert_eq!(&buf, b"llo");
+
+        assert_eq!(
+            dir.path_open(0, "a.txt", wasi::OFLAGS_CREAT | wasi::OFLAGS_EXCL, 0, 0)
+                .err(),
+            Some(wasi::ERRNO_EXIST)
+        );
+
+        dir.path_rename("a.txt", &dir, "b.txt")?;
+        let names: Vec<_> = dir.readdir(0)?.into_iter().map(|e| e.name).collect();
+        assert_eq!(names, [".", "..", "b.txt"]);
+
+        assert_eq!(dir.path_filestat_get(0, "b.txt")?.size, 5);
+        dir.path_unlink_file("b.txt")?;
+        assert_eq!(
+            dir.path_filestat_get(0, "b.txt").err(),
+            Some(wasi::ERRNO_NOENT)
+        );
+
+        // Truncated first, then appended to
+        fs::write(tmp.0.join("c.txt"), b"hello").unwrap();
+        let f = dir.path_open(
+            0,
+            "c.txt",
+            wasi::OFLAGS_TRUNC,
+            wasi::RIGHTS_FD_WRITE,
+            wasi::FDFLAGS_APPEND,
+        )?;
+        f.borrow_mut().write(b"ab")?;
+        f.borrow_mut().seek(0, wasi::WHENCE_SET)?;
+        f.borrow_mut().write(b"cd")?;
+        assert_eq!(fs::read(tmp.0.join("c.txt")).unwrap(), b"abcd");
+
+        Ok(())
+    }
+}
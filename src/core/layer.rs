@@ -0,0 +1,474 @@
+use std::ptr::{addr_of, addr_of_mut};
+
+use wasi::{
+    Advice, Ciovec, Clockid, Dircookie, Errno, Event, Exitcode, Fd, Fdflags, Fdstat, Filedelta,
+    Filesize, Filestat, Fstflags, Iovec, Lookupflags, Oflags, Prestat, Riflags, Rights, Roflags,
+    Sdflags, Siflags, Signal, Size, Subscription, Timestamp, Whence,
+};
+
+use crate::core::mem;
+
+// Calls
+
+// Arguments of a shim call exactly as the guest passed them, pointers included
+#[derive(Clone, Copy, Debug)]
+pub enum Call {
+    ArgsGet {
+        argv: *mut *mut u8,
+        argv_buf: *mut u8,
+    },
+    ArgsSizesGet {
+        rp0: *mut Size,
+        rp1: *mut Size,
+    },
+    EnvironGet {
+        environ: *mut *mut u8,
+        environ_buf: *mut u8,
+    },
+    EnvironSizesGet {
+        rp0: *mut Size,
+        rp1: *mut Size,
+    },
+    ClockResGet {
+        id: Clockid,
+        rp0: *mut Timestamp,
+    },
+    ClockTimeGet {
+        id: Clockid,
+        precision: Timestamp,
+        rp0: *mut Timestamp,
+    },
+    FdAdvise {
+        fd: Fd,
+        offset: Filesize,
+        len: Filesize,
+        advice: Advice,
+    },
+    FdAllocate {
+        fd: Fd,
+        offset: Filesize,
+        len: Filesize,
+    },
+    FdClose {
+        fd: Fd,
+    },
+    FdDatasync {
+        fd: Fd,
+    },
+    FdFdstatGet {
+        fd: Fd,
+        rp0: *mut Fdstat,
+    },
+    FdFdstatSetFlags {
+        fd: Fd,
+        flags: Fdflags,
+    },
+    FdFdstatSetRights {
+        fd: Fd,
+        fs_rights_base: Rights,
+        fs_rights_inheriting: Rights,
+    },
+    FdFilestatGet {
+        fd: Fd,
+        rp0: *mut Filestat,
+    },
+    FdFilestatSetSize {
+        fd: Fd,
+        size: Filesize,
+    },
+    FdFilestatSetTimes {
+        fd: Fd,
+        atim: Timestamp,
+        mtim: Timestamp,
+        fst_flags: Fstflags,
+    },
+    FdPread {
+        fd: Fd,
+        iovs: *const Iovec,
+        len: i32,
+        offset: Filesize,
+        rp0: *mut Size,
+    },
+    FdPrestatDirName {
+        fd: Fd,
+        path: *mut u8,
+        path_len: Size,
+    },
+    FdPrestatGet {
+        fd: Fd,
+        rp0: *mut Prestat,
+    },
+    FdPwrite {
+        fd: Fd,
+        iovs: *const Iovec,
+        iovs_len: i32,
+        offset: Filesize,
+        rp0: *mut Size,
+    },
+    FdRead {
+        fd: Fd,
+        iovs: *const Iovec,
+        iovs_len: i32,
+        rp0: *mut Size,
+    },
+    FdReaddir {
+        fd: Fd,
+        buf: *mut u8,
+        buf_len: Size,
+        cookie: Dircookie,
+        rp0: *mut Size,
+    },
+    FdRenumber {
+        fd: Fd,
+        to: Fd,
+    },
+    FdSeek {
+        fd: Fd,
+        offset: Filedelta,
+        whence: Whence,
+        rp0: *mut Filesize,
+    },
+    FdSync {
+        fd: Fd,
+    },
+    FdTell {
+        fd: Fd,
+        rp0: *mut Filesize,
+    },
+    FdWrite {
+        fd: Fd,
+        iovs: *const Iovec,
+        iovs_len: i32,
+        rp0: *mut Size,
+    },
+    PathCreateDirectory {
+        fd: Fd,
+        path: *const u8,
+        path_len: i32,
+    },
+    PathFilestatGet {
+        fd: Fd,
+        flags: Lookupflags,
+        path: *const u8,
+        path_len: i32,
+        rp0: *mut Filestat,
+    },
+    PathFilestatSetTimes {
+        fd: Fd,
+        flags: Lookupflags,
+        path: *const u8,
+        path_len: i32,
+        atim: Timestamp,
+        mtim: Timestamp,
+        fst_flags: Fstflags,
+    },
+    PathLink {
+        old_fd: Fd,
+        old_flags: Lookupflags,
+        old_path: *const u8,
+        old_path_len: i32,
+        new_fd: Fd,
+        new_path: *const u8,
+        new_path_len: i32,
+    },
+    PathOpen {
+        fd: Fd,
+        dirflags: Lookupflags,
+        path: *const u8,
+        path_len: i32,
+        oflags: Oflags,
+        fs_rights_base: Rights,
+        fs_rights_inheriting: Rights,
+        fdflags: Fdflags,
+        rp0: *mut Fd,
+    },
+    PathReadlink {
+        fd: Fd,
+        path: *const u8,
+        path_len: i32,
+        buf: *mut u8,
+        buf_len: Size,
+        rp0: *mut Size,
+    },
+    PathRemoveDirectory {
+        fd: Fd,
+        path: *const u8,
+        path_len: i32,
+    },
+    PathRename {
+        fd: Fd,
+        old_path: *const u8,
+        old_path_len: i32,
+        new_fd: Fd,
+        new_path: *const u8,
+        new_path_len: i32,
+    },
+    PathSymlink {
+        old_path: *const u8,
+        old_path_len: i32,
+        fd: Fd,
+        new_path: *const u8,
+        new_path_len: i32,
+    },
+    PathUnlinkFile {
+        fd: Fd,
+        path: *const u8,
+        path_len: i32,
+    },
+    PollOneoff {
+        in_: *const Subscription,
+        out: *mut Event,
+        nsubscriptions: Size,
+        rp0: *mut Size,
+    },
+    ProcExit {
+        rval: Exitcode,
+    },
+    ProcRaise {
+        sig: Signal,
+    },
+    RandomGet {
+        buf: *mut u8,
+        buf_len: Size,
+    },
+    SchedYield,
+    SockAccept {
+        fd: Fd,
+        flags: Fdflags,
+        rp0: *mut Fd,
+    },
+    SockRecv {
+        fd: Fd,
+        ri_data: *const Iovec,
+        ri_data_len: i32,
+        ri_flags: Riflags,
+        rp0: *mut Size,
+        rp1: *mut Roflags,
+    },
+    SockSend {
+        fd: Fd,
+        si_data: *const Ciovec,
+        si_data_len: i32,
+        si_flags: Siflags,
+        rp0: *mut Size,
+    },
+    SockShutdown {
+        fd: Fd,
+        how: Sdflags,
+    },
+}
+
+impl Call {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Call::ArgsGet { .. } => "args_get",
+            Call::ArgsSizesGet { .. } => "args_sizes_get",
+            Call::EnvironGet { .. } => "environ_get",
+            Call::EnvironSizesGet { .. } => "environ_sizes_get",
+            Call::ClockResGet { .. } => "clock_res_get",
+            Call::ClockTimeGet { .. } => "clock_time_get",
+            Call::FdAdvise { .. } => "fd_advise",
+            Call::FdAllocate { .. } => "fd_allocate",
+            Call::FdClose { .. } => "fd_close",
+            Call::FdDatasync { .. } => "fd_datasync",
+            Call::FdFdstatGet { .. } => "fd_fdstat_get",
+            Call::FdFdstatSetFlags { .. } => "fd_fdstat_set_flags",
+            Call::FdFdstatSetRights { .. } => "fd_fdstat_set_rights",
+            Call::FdFilestatGet { .. } => "fd_filestat_get",
+            Call::FdFilestatSetSize { .. } => "fd_filestat_set_size",
+            Call::FdFilestatSetTimes { .. } => "fd_filestat_set_times",
+            Call::FdPread { .. } => "fd_pread",
+            Call::FdPrestatDirName { .. } => "fd_prestat_dir_name",
+            Call::FdPrestatGet { .. } => "fd_prestat_get",
+            Call::FdPwrite { .. } => "fd_pwrite",
+            Call::FdRead { .. } => "fd_read",
+            Call::FdReaddir { .. } => "fd_readdir",
+            Call::FdRenumber { .. } => "fd_renumber",
+            Call::FdSeek { .. } => "fd_seek",
+            Call::FdSync { .. } => "fd_sync",
+            Call::FdTell { .. } => "fd_tell",
+            Call::FdWrite { .. } => "fd_write",
+            Call::PathCreateDirectory { .. } => "path_create_directory",
+            Call::PathFilestatGet { .. } => "path_filestat_get",
+            Call::PathFilestatSetTimes { .. } => "path_filestat_set_times",
+            Call::PathLink { .. } => "path_link",
+            Call::PathOpen { .. } => "path_open",
+            Call::PathReadlink { .. } => "path_readlink",
+            Call::PathRemoveDirectory { .. } => "path_remove_directory",
+            Call::PathRename { .. } => "path_rename",
+            Call::PathSymlink { .. } => "path_symlink",
+            Call::PathUnlinkFile { .. } => "path_unlink_file",
+            Call::PollOneoff { .. } => "poll_oneoff",
+            Call::ProcExit { .. } => "proc_exit",
+            Call::ProcRaise { .. } => "proc_raise",
+            Call::RandomGet { .. } => "random_get",
+            Call::SchedYield => "sched_yield",
+            Call::SockAccept { .. } => "sock_accept",
+            Call::SockRecv { .. } => "sock_recv",
+            Call::SockSend { .. } => "sock_send",
+            Call::SockShutdown { .. } => "sock_shutdown",
+        }
+    }
+
+    // Iovec list of a read or write, sock_send's Ciovecs included as they share the layout
+    fn iovecs_mut(&mut self) -> Option<(&mut *const Iovec, &mut i32)> {
+        match self {
+            Call::FdPread { iovs, len, .. } => Some((iovs, len)),
+            Call::FdPwrite { iovs, iovs_len, .. }
+            | Call::FdRead { iovs, iovs_len, .. }
+            | Call::FdWrite { iovs, iovs_len, .. } => Some((iovs, iovs_len)),
+            Call::SockRecv {
+                ri_data,
+                ri_data_len,
+                ..
+            } => Some((ri_data, ri_data_len)),
+            Call::SockSend {
+                si_data,
+                si_data_len,
+                ..
+            } => Some((
+                unsafe { &mut *(si_data as *mut *const Ciovec as *mut *const Iovec) },
+                si_data_len,
+            )),
+            _ => None,
+        }
+    }
+}
+
+// Layers
+
+pub trait Layer {
+    // Runs before the polyfill, which is skipped along with the layers below when an errno is
+    // returned
+    fn enter(&mut self, _call: &Call) -> Option<Errno> {
+        None
+    }
+
+    // Caps the bytes a read or write hands on to the layers below and the polyfill, e.g. to
+    // force short transfers. Only asked once `enter` let the call through
+    fn max_len(&mut self, _call: &Call) -> Option<Size> {
+        None
+    }
+
+    fn exit(&mut self, _call: &Call, _ret: Errno) {}
+}
+
+static mut LAYERS: Vec<Box<dyn Layer>> = Vec::new();
+
+/// Adds `layer` below the layers already added, so it sees calls after them.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn push(layer: impl Layer + 'static) {
+    (*addr_of_mut!(LAYERS)).push(Box::new(layer));
+}
+
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn clear() {
+    (*addr_of_mut!(LAYERS)).clear();
+}
+
+// Layers are only borrowed while they run and never across the polyfill, which is free to make
+// shim calls of its own. Each layer gets the call back as it was handed in, since the shortened
+// iovec lists of the layers below are gone by then
+pub(crate) unsafe fn call(mut call: Call, f: impl FnOnce(Call) -> Errno) -> Errno {
+    let n = (*addr_of!(LAYERS)).len();
+
+    let mut seen = Vec::with_capacity(n);
+    let mut scratch = vec![];
+    let mut ret = None;
+    while seen.len() < n && ret.is_none() {
+        let Some(l) = (&mut *addr_of_mut!(LAYERS)).get_mut(seen.len()) else {
+            break;
+        };
+
+        seen.push(call);
+        ret = l.enter(&call);
+        if ret.is_none() {
+            if let Some(max) = l.max_len(&call) {
+                scratch.push(shorten(&mut call, max));
+            }
+        }
+    }
+    let entered = seen.len();
+
+    let (ret, entered) = match ret {
+        Some(errno) => (errno, entered - 1),
+        None => (f(call), entered),
+    };
+
+    for i in (0..entered).rev() {
+        if let Some(l) = (&mut *addr_of_mut!(LAYERS)).get_mut(i) {
+            l.exit(&seen[i], ret);
+        }
+    }
+
+    ret
+}
+
+// For calls that never return, layers only get to see them going in
+pub(crate) unsafe fn enter(call: Call) {
+    let n = (*addr_of!(LAYERS)).len();
+
+    for i in 0..n {
+        if let Some(l) = (&mut *addr_of_mut!(LAYERS)).get_mut(i) {
+            if l.enter(&call).is_some() {
+                break;
+            }
+        }
+    }
+}
+
+// Points the call's iovecs at a copy covering at most `max` bytes, which the caller keeps alive
+unsafe fn shorten(call: &mut Call, mut max: Size) -> Box<[Iovec]> {
+    let Some((iovs, iovs_len)) = call.iovecs_mut() else {
+        return Box::new([]);
+    };
+
+    let mut short = vec![];
+    for iov in mem::iovecs(*iovs, *iovs_len) {
+        if max == 0 {
+            break;
+        }
+
+        let buf_len = iov.buf_len.min(max);
+        short.push(Iovec {
+            buf: iov.buf,
+            buf_len,
+        });
+        max -= buf_len;
+    }
+
+    let short = short.into_boxed_slice();
+    *iovs = short.as_ptr();
+    *iovs_len = short.len() as i32;
+
+    short
+}
+
+// Shims
+
+// Defines the exported shims, each handing its arguments to the layers as the `Call` variant
+// of the same name and then to the polyfill
+macro_rules! shims {
+    ($($shim:ident => $variant:ident { $($arg:ident: $ty:ty),* $(,)? } => $polyfill:ident;)*) => {
+        $(
+            #[no_mangle]
+            unsafe extern "C" fn $shim($($arg: $ty),*) -> wasi::Errno {
+                use $crate::core::layer::{self, Call};
+
+                layer::call(Call::$variant { $($arg),* }, |c| {
+                    // Layers only see the call, so it comes back as the same variant
+                    let Call::$variant { $($arg),* } = c else {
+                        unreachable!()
+                    };
+
+                    match POLYFILLS.$polyfill {
+                        Some(f) => f($($arg),*),
+                        None => unimplemented!("{}", c.name()),
+                    }
+                })
+            }
+        )*
+    };
+}
+
+pub(crate) use shims;
@@ -0,0 +1,151 @@
+use std::{ptr, slice};
+
+use wasi::{Errno, Iovec, Size};
+
+// Iovecs
+
+pub(crate) unsafe fn iovecs<'a>(iovs: *const Iovec, iovs_len: i32) -> &'a [Iovec] {
+    match iovs_len {
+        n if n > 0 && !iovs.is_null() => slice::from_raw_parts(iovs, n as usize),
+        _ => &[],
+    }
+}
+
+pub(crate) unsafe fn bufs<'a>(iovs: *const Iovec, iovs_len: i32) -> impl Iterator<Item = &'a [u8]> {
+    iovecs(iovs, iovs_len)
+        .iter()
+        .map(|iov| bytes(iov.buf, iov.buf_len))
+}
+
+pub(crate) unsafe fn bufs_mut<'a>(
+    iovs: *const Iovec,
+    iovs_len: i32,
+) -> impl Iterator<Item = &'a mut [u8]> {
+    iovecs(iovs, iovs_len)
+        .iter()
+        .map(|iov| bytes_mut(iov.buf, iov.buf_len))
+}
+
+// Buffers
+
+pub(crate) unsafe fn bytes<'a>(buf: *const u8, buf_len: Size) -> &'a [u8] {
+    match buf_len {
+        0 => &[],
+        _ => slice::from_raw_parts(buf, buf_len),
+    }
+}
+
+pub(crate) unsafe fn bytes_mut<'a>(buf: *mut u8, buf_len: Size) -> &'a mut [u8] {
+    match buf_len {
+        0 => &mut [],
+        _ => slice::from_raw_parts_mut(buf, buf_len),
+    }
+}
+
+pub(crate) unsafe fn str<'a>(buf: *const u8, buf_len: i32) -> Result<&'a str, Errno> {
+    match buf_len {
+        n if n >= 0 => std::str::from_utf8(bytes(buf, n as usize)).map_err(|_| wasi::ERRNO_ILSEQ),
+        _ => Err(wasi::ERRNO_INVAL),
+    }
+}
+
+// Results
+
+pub(crate) fn errno(r: Result<(), Errno>) -> Errno {
+    match r {
+        Ok(()) => wasi::ERRNO_SUCCESS,
+        Err(err) => err,
+    }
+}
+
+// String lists
+
+pub(crate) enum Strings {
+    Static(Vec<String>),
+    Dynamic(Box<dyn Fn() -> Vec<String>>, Option<Vec<String>>),
+}
+
+impl Strings {
+    fn snapshot(&mut self, refresh: bool) -> &[String] {
+        match self {
+            Strings::Static(ss) => ss,
+            Strings::Dynamic(f, ss) => {
+                if refresh || ss.is_none() {
+                    *ss = Some(f());
+                }
+                ss.get_or_insert_with(Vec::new)
+            }
+        }
+    }
+
+    // Taking a fresh snapshot on every sizes call and reusing it for the following get
+    // keeps both calls consistent even if a dynamic provider changes its output
+    pub(crate) unsafe fn sizes_get(&mut self, rp0: *mut Size, rp1: *mut Size) -> Errno {
+        let ss = self.snapshot(true);
+
+        *rp0 = ss.len();
+        *rp1 = ss.iter().map(|s| s.len() + 1).sum();
+
+        wasi::ERRNO_SUCCESS
+    }
+
+    pub(crate) unsafe fn get(&mut self, ptrs: *mut *mut u8, buf: *mut u8) -> Errno {
+        let mut offset = 0;
+
+        for (i, s) in self.snapshot(false).iter().enumerate() {
+            let dst = buf.add(offset);
+
+            ptr::copy_nonoverlapping(s.as_ptr(), dst, s.len());
+            *dst.add(s.len()) = 0;
+            *ptrs.add(i) = dst;
+
+            offset += s.len() + 1;
+        }
+
+        wasi::ERRNO_SUCCESS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Strings;
+
+    #[test]
+    fn test_strings_layout() {
+        let mut ss = Strings::Static(vec!["a".to_string(), "bc".to_string()]);
+
+        let (mut n, mut size) = (0, 0);
+        unsafe { ss.sizes_get(&mut n, &mut size) };
+        assert_eq!((n, size), (2, 5));
+
+        let mut ptrs = vec![std::ptr::null_mut(); n];
+        let mut buf = vec![0xff; size];
+        unsafe { ss.get(ptrs.as_mut_ptr(), buf.as_mut_ptr()) };
+
+        assert_eq!(buf, b"a\0bc\0");
+        assert_eq!(ptrs[0], buf.as_mut_ptr());
+        assert_eq!(ptrs[1], unsafe { buf.as_mut_ptr().add(2) });
+    }
+
+    #[test]
+    fn test_strings_dynamic_snapshot() {
+        let counter = std::rc::Rc::new(std::cell::Cell::new(0));
+
+        let mut ss = Strings::Dynamic(
+            Box::new(move || {
+                counter.set(counter.get() + 1);
+                vec!["x".repeat(counter.get())]
+            }),
+            None,
+        );
+
+        let (mut n, mut size) = (0, 0);
+        unsafe { ss.sizes_get(&mut n, &mut size) };
+        assert_eq!((n, size), (1, 2));
+
+        let mut ptrs = vec![std::ptr::null_mut(); n];
+        let mut buf = vec![0xff; size];
+        unsafe { ss.get(ptrs.as_mut_ptr(), buf.as_mut_ptr()) };
+        assert_eq!(buf, b"x\0");
+    }
+}
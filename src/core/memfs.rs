@@ -0,0 +1,979 @@
+use std::{
+    any::Any,
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    rc::{Rc, Weak},
+};
+
+use wasi::{
+    Dircookie, Errno, Fdflags, Filedelta, Filesize, Filestat, Filetype, Fstflags, Inode,
+    Lookupflags, Oflags, Rights, Size, Timestamp, Whence,
+};
+
+use crate::core::{
+    clock,
+    fd_table::{Direntry, Handle, HandleRef, Readiness},
+    preopens,
+};
+
+const MAX_SYMLINKS: usize = 40;
+
+// Nodes
+
+type NodeRef = Rc<RefCell<Node>>;
+
+struct Node {
+    ino: Inode,
+    nlink: u64,
+    atim: Timestamp,
+    mtim: Timestamp,
+    ctim: Timestamp,
+    kind: Kind,
+}
+
+enum Kind {
+    File(Vec<u8>),
+    Dir(Dir),
+    Symlink(String),
+}
+
+#[derive(Default)]
+struct Dir {
+    parent: Weak<RefCell<Node>>,
+    entries: BTreeMap<String, (Dircookie, NodeRef)>,
+    next_cookie: Dircookie,
+}
+
+impl Node {
+    fn filetype(&self) -> Filetype {
+        match self.kind {
+            Kind::File(_) => wasi::FILETYPE_REGULAR_FILE,
+            Kind::Dir(_) => wasi::FILETYPE_DIRECTORY,
+            Kind::Symlink(_) => wasi::FILETYPE_SYMBOLIC_LINK,
+        }
+    }
+
+    fn stat(&self) -> Filestat {
+        Filestat {
+            dev: 0,
+            ino: self.ino,
+            filetype: self.filetype(),
+            nlink: self.nlink,
+            size: match &self.kind {
+                Kind::File(data) => data.len() as Filesize,
+                Kind::Dir(_) => 0,
+                Kind::Symlink(target) => target.len() as Filesize,
+            },
+            atim: self.atim,
+            mtim: self.mtim,
+            ctim: self.ctim,
+        }
+    }
+
+    fn dir(&self) -> Result<&Dir, Errno> {
+        match &self.kind {
+            Kind::Dir(dir) => Ok(dir),
+            _ => Err(wasi::ERRNO_NOTDIR),
+        }
+    }
+
+    fn dir_mut(&mut self) -> Result<&mut Dir, Errno> {
+        match &mut self.kind {
+            Kind::Dir(dir) => Ok(dir),
+            _ => Err(wasi::ERRNO_NOTDIR),
+        }
+    }
+
+    fn data_mut(&mut self) -> Result<&mut Vec<u8>, Errno> {
+        match &mut self.kind {
+            Kind::File(data) => Ok(data),
+            Kind::Dir(_) => Err(wasi::ERRNO_ISDIR),
+            Kind::Symlink(_) => Err(wasi::ERRNO_INVAL),
+        }
+    }
+
+    fn is_dir(&self) -> bool {
+        matches!(self.kind, Kind::Dir(_))
+    }
+}
+
+// Filesystem
+
+#[derive(Clone)]
+pub struct MemFs {
+    root: NodeRef,
+    inodes: Rc<Cell<Inode>>,
+}
+
+impl Default for MemFs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemFs {
+    pub fn new() -> Self {
+        let inodes = Rc::new(Cell::new(1));
+        let root = new_node(&inodes, Kind::Dir(Dir::default()));
+
+        Self { root, inodes }
+    }
+
+    pub fn root(&self) -> MemDir {
+        MemDir {
+            fs: self.clone(),
+            node: self.root.clone(),
+        }
+    }
+
+    pub fn create_dir_all(&self, path: &str) -> Result<(), Errno> {
+        let mut cur = self.root.clone();
+
+        for name in path.split('/').filter(|c| !c.is_empty() && *c != ".") {
+            let next = match self.step(&cur, name) {
+                Ok(next) => next,
+                Err(wasi::ERRNO_NOENT) => {
+                    let node = self.new_node(Kind::Dir(Dir::default()));
+                    self.insert(&cur, name, node.clone())?;
+                    node
+                }
+                Err(err) => return Err(err),
+            };
+
+            if !next.borrow().is_dir() {
+                return Err(wasi::ERRNO_NOTDIR);
+            }
+            cur = next;
+        }
+
+        Ok(())
+    }
+
+    pub fn write_file(&self, path: &str, data: &[u8]) -> Result<(), Errno> {
+        let path = path.trim_start_matches('/');
+        let node = self.open(
+            &self.root,
+            path,
+            true,
+            wasi::OFLAGS_CREAT | wasi::OFLAGS_TRUNC,
+        )?;
+        write_at(&node, data, 0)?;
+
+        Ok(())
+    }
+
+    pub fn read_file(&self, path: &str) -> Result<Vec<u8>, Errno> {
+        let path = path.trim_start_matches('/');
+        let node = self.walk(&self.root, path, true)?;
+        let mut node = node.borrow_mut();
+
+        node.data_mut().cloned()
+    }
+
+    fn new_node(&self, kind: Kind) -> NodeRef {
+        new_node(&self.inodes, kind)
+    }
+
+    fn step(&self, dir: &NodeRef, name: &str) -> Result<NodeRef, Errno> {
+        match name {
+            "." | ".." => Err(wasi::ERRNO_INVAL),
+            _ => match dir.borrow().dir()?.entries.get(name) {
+                Some((_, node)) => Ok(node.clone()),
+                None => Err(wasi::ERRNO_NOENT),
+            },
+        }
+    }
+
+    // Resolves `path` relative to `dir`, always expanding symlinks in intermediate components
+    // and expanding a symlink in the final component only when `follow` is set. The walk keeps
+    // the directories it went through so ".." never leaves `dir`, even across symlinks
+    fn walk(&self, dir: &NodeRef, path: &str, follow: bool) -> Result<NodeRef, Errno> {
+        let mut stack = vec![dir.clone()];
+        self.walk_from(&mut stack, path, follow, &mut 0)?;
+
+        Ok(stack.pop().unwrap())
+    }
+
+    fn walk_from(
+        &self,
+        stack: &mut Vec<NodeRef>,
+        path: &str,
+        follow: bool,
+        depth: &mut usize,
+    ) -> Result<(), Errno> {
+        if path.starts_with('/') {
+            return Err(wasi::ERRNO_PERM);
+        }
+
+        let must_dir = path.ends_with('/');
+        let names: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+        if names.is_empty() {
+            return Err(wasi::ERRNO_NOENT);
+        }
+
+        for (i, name) in names.iter().enumerate() {
+            let cur = stack.last().unwrap().clone();
+            cur.borrow().dir()?;
+
+            match *name {
+                "." => continue,
+                ".." if stack.len() == 1 => return Err(wasi::ERRNO_PERM),
+                ".." => {
+                    stack.pop();
+                    continue;
+                }
+                _ => {}
+            }
+
+            let next = self.step(&cur, name)?;
+            let last = i == names.len() - 1;
+
+            let target = match &next.borrow().kind {
+                Kind::Symlink(target) if !last || follow || must_dir => Some(target.clone()),
+                _ => None,
+            };
+
+            match target {
+                Some(target) => {
+                    *depth += 1;
+                    if *depth > MAX_SYMLINKS {
+                        return Err(wasi::ERRNO_LOOP);
+                    }
+                    self.walk_from(stack, &target, true, depth)?;
+                }
+                None => stack.push(next),
+            }
+        }
+
+        if must_dir && !stack.last().unwrap().borrow().is_dir() {
+            return Err(wasi::ERRNO_NOTDIR);
+        }
+
+        Ok(())
+    }
+
+    fn walk_parent<'a>(&self, dir: &NodeRef, path: &'a str) -> Result<(NodeRef, &'a str), Errno> {
+        let path = path.trim_end_matches('/');
+        let (parent, name) = match path.rsplit_once('/') {
+            Some((parent, name)) => (self.walk(dir, &format!("{parent}/"), true)?, name),
+            None => (dir.clone(), path),
+        };
+
+        match name {
+            "" | "." | ".." => Err(wasi::ERRNO_INVAL),
+            _ => Ok((parent, name)),
+        }
+    }
+
+    fn insert(&self, dir: &NodeRef, name: &str, node: NodeRef) -> Result<(), Errno> {
+        let mut parent = dir.borrow_mut();
+        parent.mtim = clock::realtime();
+
+        let d = parent.dir_mut()?;
+        if d.entries.contains_key(name) {
+            return Err(wasi::ERRNO_EXIST);
+        }
+
+        if let Kind::Dir(child) = &mut node.borrow_mut().kind {
+            child.parent = Rc::downgrade(dir);
+        }
+
+        d.next_cookie = d.next_cookie.max(2) + 1;
+        d.entries.insert(name.to_string(), (d.next_cookie, node));
+
+        Ok(())
+    }
+
+    fn remove(&self, dir: &NodeRef, name: &str) -> Result<NodeRef, Errno> {
+        let mut parent = dir.borrow_mut();
+        parent.mtim = clock::realtime();
+
+        let (_, node) = parent
+            .dir_mut()?
+            .entries
+            .remove(name)
+            .ok_or(wasi::ERRNO_NOENT)?;
+
+        let mut n = node.borrow_mut();
+        n.nlink = n.nlink.saturating_sub(1);
+        n.ctim = clock::realtime();
+        if let Kind::Dir(child) = &mut n.kind {
+            child.parent = Weak::new();
+        }
+        drop(n);
+
+        Ok(node)
+    }
+
+    fn open(
+        &self,
+        dir: &NodeRef,
+        path: &str,
+        follow: bool,
+        oflags: Oflags,
+    ) -> Result<NodeRef, Errno> {
+        let node = match self.walk(dir, path, follow) {
+            Ok(_) if oflags & wasi::OFLAGS_CREAT != 0 && oflags & wasi::OFLAGS_EXCL != 0 => {
+                return Err(wasi::ERRNO_EXIST)
+            }
+            Ok(node) => node,
+            Err(wasi::ERRNO_NOENT) if oflags & wasi::OFLAGS_CREAT != 0 => {
+                if oflags & wasi::OFLAGS_DIRECTORY != 0 || path.ends_with('/') {
+                    return Err(wasi::ERRNO_ISDIR);
+                }
+
+                let (parent, name) = self.walk_parent(dir, path)?;
+                let node = self.new_node(Kind::File(vec![]));
+                self.insert(&parent, name, node.clone())?;
+                node
+            }
+            Err(err) => return Err(err),
+        };
+
+        let mut n = node.borrow_mut();
+        match n.kind {
+            Kind::Symlink(_) => return Err(wasi::ERRNO_LOOP),
+            Kind::Dir(_) if oflags & wasi::OFLAGS_TRUNC != 0 => return Err(wasi::ERRNO_ISDIR),
+            Kind::File(_) if oflags & wasi::OFLAGS_DIRECTORY != 0 => {
+                return Err(wasi::ERRNO_NOTDIR)
+            }
+            Kind::File(ref mut data) if oflags & wasi::OFLAGS_TRUNC != 0 => {
+                data.clear();
+                n.mtim = clock::realtime();
+            }
+            _ => {}
+        }
+        drop(n);
+
+        Ok(node)
+    }
+
+    fn create_directory(&self, dir: &NodeRef, path: &str) -> Result<(), Errno> {
+        let (parent, name) = self.walk_parent(dir, path)?;
+        self.insert(&parent, name, self.new_node(Kind::Dir(Dir::default())))
+    }
+
+    fn link(
+        &self,
+        old_dir: &NodeRef,
+        old_path: &str,
+        follow: bool,
+        new_dir: &NodeRef,
+        new_path: &str,
+    ) -> Result<(), Errno> {
+        let node = self.walk(old_dir, old_path, follow)?;
+        if node.borrow().is_dir() {
+            return Err(wasi::ERRNO_PERM);
+        }
+
+        let (parent, name) = self.walk_parent(new_dir, new_path)?;
+        self.insert(&parent, name, node.clone())?;
+
+        let mut n = node.borrow_mut();
+        n.nlink += 1;
+        n.ctim = clock::realtime();
+
+        Ok(())
+    }
+
+    fn readlink(&self, dir: &NodeRef, path: &str) -> Result<String, Errno> {
+        match &self.walk(dir, path, false)?.borrow().kind {
+            Kind::Symlink(target) => Ok(target.clone()),
+            _ => Err(wasi::ERRNO_INVAL),
+        }
+    }
+
+    fn remove_directory(&self, dir: &NodeRef, path: &str) -> Result<(), Errno> {
+        let (parent, name) = self.walk_parent(dir, path)?;
+        let node = self.step(&parent, name)?;
+
+        if !node.borrow().dir()?.entries.is_empty() {
+            return Err(wasi::ERRNO_NOTEMPTY);
+        }
+
+        self.remove(&parent, name).map(|_| ())
+    }
+
+    fn rename(
+        &self,
+        old_dir: &NodeRef,
+        old_path: &str,
+        new_dir: &NodeRef,
+        new_path: &str,
+    ) -> Result<(), Errno> {
+        let (old_parent, old_name) = self.walk_parent(old_dir, old_path)?;
+        let (new_parent, new_name) = self.walk_parent(new_dir, new_path)?;
+
+        let node = self.step(&old_parent, old_name)?;
+        let is_dir = node.borrow().is_dir();
+
+        if (old_path.ends_with('/') || new_path.ends_with('/')) && !is_dir {
+            return Err(wasi::ERRNO_NOTDIR);
+        }
+
+        // Refuse to move a directory underneath itself
+        let mut cur = Some(new_parent.clone());
+        while let Some(d) = cur {
+            if Rc::ptr_eq(&d, &node) {
+                return Err(wasi::ERRNO_INVAL);
+            }
+            cur = d.borrow().dir()?.parent.upgrade();
+        }
+
+        match self.step(&new_parent, new_name) {
+            Ok(target) if Rc::ptr_eq(&target, &node) => return Ok(()),
+            Ok(target) => {
+                match (is_dir, &target.borrow().kind) {
+                    (true, Kind::Dir(d)) if !d.entries.is_empty() => {
+                        return Err(wasi::ERRNO_NOTEMPTY)
+                    }
+                    (true, Kind::Dir(_)) => {}
+                    (true, _) => return Err(wasi::ERRNO_NOTDIR),
+                    (false, Kind::Dir(_)) => return Err(wasi::ERRNO_ISDIR),
+                    (false, _) => {}
+                }
+                self.remove(&new_parent, new_name)?;
+            }
+            Err(wasi::ERRNO_NOENT) => {}
+            Err(err) => return Err(err),
+        }
+
+        self.remove(&old_parent, old_name)?;
+        node.borrow_mut().nlink += 1;
+        self.insert(&new_parent, new_name, node)
+    }
+
+    fn symlink(&self, target: &str, dir: &NodeRef, path: &str) -> Result<(), Errno> {
+        let (parent, name) = self.walk_parent(dir, path)?;
+        self.insert(
+            &parent,
+            name,
+            self.new_node(Kind::Symlink(target.to_string())),
+        )
+    }
+
+    fn unlink_file(&self, dir: &NodeRef, path: &str) -> Result<(), Errno> {
+        let (parent, name) = self.walk_parent(dir, path)?;
+        let node = self.step(&parent, name)?;
+
+        match node.borrow().kind {
+            Kind::Dir(_) => return Err(wasi::ERRNO_ISDIR),
+            _ if path.ends_with('/') => return Err(wasi::ERRNO_NOTDIR),
+            _ => {}
+        }
+
+        self.remove(&parent, name).map(|_| ())
+    }
+}
+
+fn new_node(inodes: &Cell<Inode>, kind: Kind) -> NodeRef {
+    let ino = inodes.get();
+    inodes.set(ino + 1);
+
+    let now = clock::realtime();
+    Rc::new(RefCell::new(Node {
+        ino,
+        nlink: 1,
+        atim: now,
+        mtim: now,
+        ctim: now,
+        kind,
+    }))
+}
+
+fn read_at(node: &NodeRef, buf: &mut [u8], offset: Filesize) -> Result<Size, Errno> {
+    let mut node = node.borrow_mut();
+    node.atim = clock::realtime();
+
+    // An offset past what fits in memory is past the end of the file too
+    let data = node.data_mut()?;
+    let start = usize::try_from(offset)
+        .unwrap_or(usize::MAX)
+        .min(data.len());
+    let n = buf.len().min(data.len() - start);
+    buf[..n].copy_from_slice(&data[start..start + n]);
+
+    Ok(n)
+}
+
+fn write_at(node: &NodeRef, buf: &[u8], offset: Filesize) -> Result<Size, Errno> {
+    let mut node = node.borrow_mut();
+    node.mtim = clock::realtime();
+
+    let data = node.data_mut()?;
+    let start = usize::try_from(offset).map_err(|_| wasi::ERRNO_FBIG)?;
+    let end = start.checked_add(buf.len()).ok_or(wasi::ERRNO_FBIG)?;
+    if data.len() < end {
+        resize(data, end as Filesize)?;
+    }
+    data[start..end].copy_from_slice(buf);
+
+    Ok(buf.len())
+}
+
+// Sizes come from the guest, so one too large for memory is FBIG rather than an abort in the
+// allocator
+fn resize(data: &mut Vec<u8>, size: Filesize) -> Result<(), Errno> {
+    let size = usize::try_from(size).map_err(|_| wasi::ERRNO_FBIG)?;
+    if size > data.len() {
+        data.try_reserve_exact(size - data.len())
+            .map_err(|_| wasi::ERRNO_FBIG)?;
+    }
+    data.resize(size, 0);
+
+    Ok(())
+}
+
+fn set_times(
+    node: &NodeRef,
+    atim: Timestamp,
+    mtim: Timestamp,
+    fst_flags: Fstflags,
+) -> Result<(), Errno> {
+    if (fst_flags & wasi::FSTFLAGS_ATIM != 0 && fst_flags & wasi::FSTFLAGS_ATIM_NOW != 0)
+        || (fst_flags & wasi::FSTFLAGS_MTIM != 0 && fst_flags & wasi::FSTFLAGS_MTIM_NOW != 0)
+    {
+        return Err(wasi::ERRNO_INVAL);
+    }
+
+    let now = clock::realtime();
+    let mut node = node.borrow_mut();
+
+    if fst_flags & wasi::FSTFLAGS_ATIM != 0 {
+        node.atim = atim;
+    }
+    if fst_flags & wasi::FSTFLAGS_ATIM_NOW != 0 {
+        node.atim = now;
+    }
+    if fst_flags & wasi::FSTFLAGS_MTIM != 0 {
+        node.mtim = mtim;
+    }
+    if fst_flags & wasi::FSTFLAGS_MTIM_NOW != 0 {
+        node.mtim = now;
+    }
+    node.ctim = now;
+
+    Ok(())
+}
+
+// Directory entries are listed in insertion order, with each entry's cookie fixed at the time
+// it was inserted, so a cookie stays valid while entries are added or removed
+fn readdir(node: &NodeRef, cookie: Dircookie) -> Result<Vec<Direntry>, Errno> {
+    let n = node.borrow();
+    let d = n.dir()?;
+
+    let mut entries = vec![
+        Direntry {
+            next: 1,
+            ino: n.ino,
+            filetype: wasi::FILETYPE_DIRECTORY,
+            name: ".".to_string(),
+        },
+        Direntry {
+            next: 2,
+            ino: d.parent.upgrade().map_or(n.ino, |p| p.borrow().ino),
+            filetype: wasi::FILETYPE_DIRECTORY,
+            name: "..".to_string(),
+        },
+    ];
+
+    for (name, (next, node)) in d.entries.iter() {
+        let node = node.borrow();
+        entries.push(Direntry {
+            next: *next,
+            ino: node.ino,
+            filetype: node.filetype(),
+            name: name.clone(),
+        });
+    }
+
+    entries.retain(|e| e.next > cookie);
+    entries.sort_by_key(|e| e.next);
+
+    Ok(entries)
+}
+
+// Handles
+
+struct MemFile {
+    node: NodeRef,
+    pos: Filesize,
+}
+
+impl Handle for MemFile {
+    fn filetype(&self) -> Filetype {
+        self.node.borrow().filetype()
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<Size, Errno> {
+        let n = read_at(&self.node, buf, self.pos)?;
+        self.pos += n as Filesize;
+        Ok(n)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<Size, Errno> {
+        let n = write_at(&self.node, buf, self.pos)?;
+        self.pos += n as Filesize;
+        Ok(n)
+    }
+
+    fn pread(&mut self, buf: &mut [u8], offset: Filesize) -> Result<Size, Errno> {
+        read_at(&self.node, buf, offset)
+    }
+
+    fn pwrite(&mut self, buf: &[u8], offset: Filesize) -> Result<Size, Errno> {
+        write_at(&self.node, buf, offset)
+    }
+
+    fn seek(&mut self, offset: Filedelta, whence: Whence) -> Result<Filesize, Errno> {
+        let base = match whence {
+            wasi::WHENCE_CUR => self.pos as i128,
+            wasi::WHENCE_END => self.node.borrow_mut().data_mut()?.len() as i128,
+            _ => 0,
+        };
+
+        let pos = base + offset as i128;
+        if pos < 0 || pos > Filesize::MAX as i128 {
+            return Err(wasi::ERRNO_INVAL);
+        }
+        self.pos = pos as Filesize;
+
+        Ok(self.pos)
+    }
+
+    fn filestat_get(&self) -> Result<Filestat, Errno> {
+        Ok(self.node.borrow().stat())
+    }
+
+    fn filestat_set_size(&mut self, size: Filesize) -> Result<(), Errno> {
+        let mut node = self.node.borrow_mut();
+        resize(node.data_mut()?, size)?;
+        node.mtim = clock::realtime();
+
+        Ok(())
+    }
+
+    fn filestat_set_times(
+        &mut self,
+        atim: Timestamp,
+        mtim: Timestamp,
+        fst_flags: Fstflags,
+    ) -> Result<(), Errno> {
+        set_times(&self.node, atim, mtim, fst_flags)
+    }
+
+    fn allocate(&mut self, offset: Filesize, len: Filesize) -> Result<(), Errno> {
+        let mut node = self.node.borrow_mut();
+        let data = node.data_mut()?;
+
+        let size = offset.checked_add(len).ok_or(wasi::ERRNO_FBIG)?;
+        if (data.len() as Filesize) < size {
+            resize(data, size)?;
+        }
+
+        Ok(())
+    }
+
+    fn poll_read(&self) -> Option<Readiness> {
+        let size = self.node.borrow().stat().size;
+
+        Some(Readiness {
+            nbytes: size.saturating_sub(self.pos),
+            hangup: false,
+        })
+    }
+}
+
+pub struct MemDir {
+    fs: MemFs,
+    node: NodeRef,
+}
+
+impl MemDir {
+    fn peer<'a>(&self, other: &'a dyn Handle) -> Result<&'a MemDir, Errno> {
+        if other.filetype() != wasi::FILETYPE_DIRECTORY {
+            return Err(wasi::ERRNO_NOTDIR);
+        }
+
+        let any: &dyn Any = other;
+        match any.downcast_ref::<MemDir>() {
+            Some(d) if Rc::ptr_eq(&d.fs.inodes, &self.fs.inodes) => Ok(d),
+            _ => Err(wasi::ERRNO_XDEV),
+        }
+    }
+}
+
+impl Handle for MemDir {
+    fn filetype(&self) -> Filetype {
+        wasi::FILETYPE_DIRECTORY
+    }
+
+    fn filestat_get(&self) -> Result<Filestat, Errno> {
+        Ok(self.node.borrow().stat())
+    }
+
+    fn filestat_set_times(
+        &mut self,
+        atim: Timestamp,
+        mtim: Timestamp,
+        fst_flags: Fstflags,
+    ) -> Result<(), Errno> {
+        set_times(&self.node, atim, mtim, fst_flags)
+    }
+
+    fn readdir(&self, cookie: Dircookie) -> Result<Vec<Direntry>, Errno> {
+        readdir(&self.node, cookie)
+    }
+
+    fn path_open(
+        &self,
+        dirflags: Lookupflags,
+        path: &str,
+        oflags: Oflags,
+        _rights: Rights,
+        _fdflags: Fdflags,
+    ) -> Result<HandleRef, Errno> {
+        let follow = dirflags & wasi::LOOKUPFLAGS_SYMLINK_FOLLOW != 0;
+        let node = self.fs.open(&self.node, path, follow, oflags)?;
+
+        let is_dir = node.borrow().is_dir();
+        Ok(match is_dir {
+            true => Rc::new(RefCell::new(MemDir {
+                fs: self.fs.clone(),
+                node,
+            })),
+            false => Rc::new(RefCell::new(MemFile { node, pos: 0 })),
+        })
+    }
+
+    fn path_create_directory(&self, path: &str) -> Result<(), Errno> {
+        self.fs.create_directory(&self.node, path)
+    }
+
+    fn path_filestat_get(&self, flags: Lookupflags, path: &str) -> Result<Filestat, Errno> {
+        let follow = flags & wasi::LOOKUPFLAGS_SYMLINK_FOLLOW != 0;
+        let stat = self.fs.walk(&self.node, path, follow)?.borrow().stat();
+
+        Ok(stat)
+    }
+
+    fn path_filestat_set_times(
+        &self,
+        flags: Lookupflags,
+        path: &str,
+        atim: Timestamp,
+        mtim: Timestamp,
+        fst_flags: Fstflags,
+    ) -> Result<(), Errno> {
+        let follow = flags & wasi::LOOKUPFLAGS_SYMLINK_FOLLOW != 0;
+        let node = self.fs.walk(&self.node, path, follow)?;
+
+        set_times(&node, atim, mtim, fst_flags)
+    }
+
+    fn path_link(
+        &self,
+        old_flags: Lookupflags,
+        old_path: &str,
+        new_dir: &dyn Handle,
+        new_path: &str,
+    ) -> Result<(), Errno> {
+        let follow = old_flags & wasi::LOOKUPFLAGS_SYMLINK_FOLLOW != 0;
+        let new_dir = self.peer(new_dir)?;
+
+        self.fs
+            .link(&self.node, old_path, follow, &new_dir.node, new_path)
+    }
+
+    fn path_readlink(&self, path: &str) -> Result<String, Errno> {
+        self.fs.readlink(&self.node, path)
+    }
+
+    fn path_remove_directory(&self, path: &str) -> Result<(), Errno> {
+        self.fs.remove_directory(&self.node, path)
+    }
+
+    fn path_rename(
+        &self,
+        old_path: &str,
+        new_dir: &dyn Handle,
+        new_path: &str,
+    ) -> Result<(), Errno> {
+        let new_dir = self.peer(new_dir)?;
+        self.fs
+            .rename(&self.node, old_path, &new_dir.node, new_path)
+    }
+
+    fn path_symlink(&self, old_path: &str, new_path: &str) -> Result<(), Errno> {
+        self.fs.symlink(old_path, &self.node, new_path)
+    }
+
+    fn path_unlink_file(&self, path: &str) -> Result<(), Errno> {
+        self.fs.unlink_file(&self.node, path)
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn install(fs: MemFs) {
+    preopens::install();
+    preopens::register("/", fs.root());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_flags() -> Result<(), Errno> {
+        let fs = MemFs::new();
+        let root = fs.root.clone();
+
+        assert_eq!(fs.open(&root, "a", true, 0).err(), Some(wasi::ERRNO_NOENT));
+
+        let node = fs.open(&root, "a", true, wasi::OFLAGS_CREAT)?;
+        write_at(&node, b"hello", 0)?;
+
+        let excl = wasi::OFLAGS_CREAT | wasi::OFLAGS_EXCL;
+        assert_eq!(
+            fs.open(&root, "a", true, excl).err(),
+            Some(wasi::ERRNO_EXIST)
+        );
+
+        let dir = wasi::OFLAGS_DIRECTORY;
+        assert_eq!(
+            fs.open(&root, "a", true, dir).err(),
+            Some(wasi::ERRNO_NOTDIR)
+        );
+
+        fs.open(&root, "a", true, wasi::OFLAGS_TRUNC)?;
+        assert_eq!(fs.read_file("a")?, b"");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_huge_offsets() -> Result<(), Errno> {
+        let fs = MemFs::new();
+        let node = fs.open(&fs.root, "a", true, wasi::OFLAGS_CREAT)?;
+        write_at(&node, b"data", 0)?;
+
+        assert_eq!(
+            write_at(&node, b"xy", Filesize::MAX - 1).err(),
+            Some(wasi::ERRNO_FBIG)
+        );
+        assert_eq!(write_at(&node, b"x", 1 << 62).err(), Some(wasi::ERRNO_FBIG));
+        assert_eq!(read_at(&node, &mut [0; 4], (1 << 32) + 1)?, 0);
+        assert_eq!(fs.read_file("a")?, b"data");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_huge_size() -> Result<(), Errno> {
+        let fs = MemFs::new();
+        let node = fs.open(&fs.root, "a", true, wasi::OFLAGS_CREAT)?;
+        let mut file = MemFile { node, pos: 0 };
+
+        assert_eq!(
+            file.filestat_set_size(Filesize::MAX).err(),
+            Some(wasi::ERRNO_FBIG)
+        );
+        assert_eq!(
+            file.allocate(1 << 62, 1 << 62).err(),
+            Some(wasi::ERRNO_FBIG)
+        );
+        file.filestat_set_size(3)?;
+        assert_eq!(fs.read_file("a")?, [0; 3]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_walk() -> Result<(), Errno> {
+        let fs = MemFs::new();
+        let root = fs.root.clone();
+
+        fs.create_dir_all("a/b")?;
+        fs.write_file("a/b/c", b"data")?;
+        fs.symlink("b/c", &fs.walk(&root, "a", true)?, "link")?;
+
+        assert_eq!(fs.read_file("a/link")?, b"data");
+        assert_eq!(fs.read_file("a/b/../b/./c")?, b"data");
+        assert_eq!(fs.readlink(&root, "a/link")?, "b/c");
+
+        assert_eq!(fs.walk(&root, "..", true).err(), Some(wasi::ERRNO_PERM));
+        let a = fs.walk(&root, "a", true)?;
+        assert_eq!(fs.walk(&a, "b/../..", true).err(), Some(wasi::ERRNO_PERM));
+        fs.symlink("../..", &fs.walk(&a, "b", true)?, "up")?;
+        assert_eq!(fs.walk(&a, "b/up", true).err(), Some(wasi::ERRNO_PERM));
+        assert_eq!(fs.walk(&root, "/a", true).err(), Some(wasi::ERRNO_PERM));
+        assert_eq!(
+            fs.walk(&root, "a/b/c/", true).err(),
+            Some(wasi::ERRNO_NOTDIR)
+        );
+
+        fs.symlink("loop", &root, "loop")?;
+        assert_eq!(fs.walk(&root, "loop", true).err(), Some(wasi::ERRNO_LOOP));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename() -> Result<(), Errno> {
+        let fs = MemFs::new();
+        let root = fs.root.clone();
+
+        fs.create_dir_all("d/e")?;
+        fs.write_file("f", b"1")?;
+        fs.write_file("g", b"2")?;
+
+        fs.rename(&root, "f", &root, "g")?;
+        assert_eq!(fs.read_file("g")?, b"1");
+        assert_eq!(fs.read_file("f").err(), Some(wasi::ERRNO_NOENT));
+
+        assert_eq!(
+            fs.rename(&root, "g", &root, "d").err(),
+            Some(wasi::ERRNO_ISDIR)
+        );
+        assert_eq!(
+            fs.rename(&root, "d", &root, "d/e/x").err(),
+            Some(wasi::ERRNO_INVAL)
+        );
+
+        fs.rename(&root, "g", &root, "d/e/g")?;
+        assert_eq!(fs.read_file("d/e/g")?, b"1");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_readdir_cookies() -> Result<(), Errno> {
+        let fs = MemFs::new();
+        let root = fs.root.clone();
+
+        fs.write_file("b", b"")?;
+        fs.write_file("a", b"")?;
+        fs.write_file("c", b"")?;
+
+        let names = |cookie| -> Result<Vec<(Dircookie, String)>, Errno> {
+            Ok(readdir(&root, cookie)?
+                .into_iter()
+                .map(|e| (e.next, e.name))
+                .collect())
+        };
+
+        let all = names(0)?;
+        let order: Vec<&str> = all.iter().map(|(_, n)| n.as_str()).collect();
+        assert_eq!(order, [".", "..", "b", "a", "c"]);
+
+        // Resume after "b" while "a" is removed and "d" is added
+        let cookie = all[2].0;
+        fs.unlink_file(&root, "a")?;
+        fs.write_file("d", b"")?;
+
+        let rest: Vec<String> = names(cookie)?.into_iter().map(|(_, n)| n).collect();
+        assert_eq!(rest, ["c", "d"]);
+
+        Ok(())
+    }
+}
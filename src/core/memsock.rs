@@ -0,0 +1,320 @@
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io::{self, Read, Write},
+    rc::Rc,
+};
+
+use wasi::{Errno, Fd, Fdflags, Filetype, Riflags, Rights, Roflags, Sdflags, Siflags, Size};
+
+use crate::core::fd_table::{self, Entry, Handle, HandleRef, Readiness};
+
+// One direction of a connection
+#[derive(Default)]
+struct Pipe {
+    buf: VecDeque<u8>,
+    writer_closed: bool,
+    reader_closed: bool,
+}
+
+type PipeRef = Rc<RefCell<Pipe>>;
+
+// Sockets
+
+/// One end of an in-memory stream connection. The host keeps one end and reads and writes it
+/// through `std::io`, which reports `WouldBlock` rather than waiting for the guest.
+pub struct Socket {
+    rx: PipeRef,
+    tx: PipeRef,
+    rd_shut: bool,
+    wr_shut: bool,
+}
+
+pub fn pair() -> (Socket, Socket) {
+    let a: PipeRef = Default::default();
+    let b: PipeRef = Default::default();
+
+    (Socket::new(a.clone(), b.clone()), Socket::new(b, a))
+}
+
+impl Socket {
+    fn new(rx: PipeRef, tx: PipeRef) -> Self {
+        Self {
+            rx,
+            tx,
+            rd_shut: false,
+            wr_shut: false,
+        }
+    }
+
+    pub fn shutdown(&mut self, how: Sdflags) -> Result<(), Errno> {
+        if how & !(wasi::SDFLAGS_RD | wasi::SDFLAGS_WR) != 0 || how == 0 {
+            return Err(wasi::ERRNO_INVAL);
+        }
+
+        if how & wasi::SDFLAGS_RD != 0 {
+            self.rd_shut = true;
+            self.rx.borrow_mut().reader_closed = true;
+        }
+        if how & wasi::SDFLAGS_WR != 0 {
+            self.wr_shut = true;
+            self.tx.borrow_mut().writer_closed = true;
+        }
+
+        Ok(())
+    }
+
+    // Nothing can arrive while the guest is waiting on it, so a receive that cannot be served
+    // in full right away fails with AGAIN instead of blocking
+    fn recv(&mut self, buf: &mut [u8], flags: Riflags) -> Result<Size, Errno> {
+        if self.rd_shut || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let mut rx = self.rx.borrow_mut();
+        let n = buf.len().min(rx.buf.len());
+
+        if n == 0 && !rx.writer_closed {
+            return Err(wasi::ERRNO_AGAIN);
+        }
+        if flags & wasi::RIFLAGS_RECV_WAITALL != 0 && n < buf.len() && !rx.writer_closed {
+            return Err(wasi::ERRNO_AGAIN);
+        }
+
+        for (b, v) in buf.iter_mut().zip(rx.buf.iter()) {
+            *b = *v;
+        }
+        if flags & wasi::RIFLAGS_RECV_PEEK == 0 {
+            rx.buf.drain(..n);
+        }
+
+        Ok(n)
+    }
+
+    fn send(&mut self, buf: &[u8]) -> Result<Size, Errno> {
+        let mut tx = self.tx.borrow_mut();
+        if self.wr_shut || tx.reader_closed {
+            return Err(wasi::ERRNO_PIPE);
+        }
+
+        tx.buf.extend(buf);
+        Ok(buf.len())
+    }
+}
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        self.tx.borrow_mut().writer_closed = true;
+        self.rx.borrow_mut().reader_closed = true;
+    }
+}
+
+impl Read for Socket {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.recv(buf, 0).map_err(errno_io)
+    }
+}
+
+impl Write for Socket {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.send(buf).map_err(errno_io)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn errno_io(err: Errno) -> io::Error {
+    match err {
+        wasi::ERRNO_AGAIN => io::ErrorKind::WouldBlock.into(),
+        wasi::ERRNO_PIPE => io::ErrorKind::BrokenPipe.into(),
+        _ => io::Error::other(err.message()),
+    }
+}
+
+impl Handle for Socket {
+    fn filetype(&self) -> Filetype {
+        wasi::FILETYPE_SOCKET_STREAM
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<Size, Errno> {
+        self.recv(buf, 0)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<Size, Errno> {
+        self.send(buf)
+    }
+
+    fn sock_recv(&mut self, buf: &mut [u8], flags: Riflags) -> Result<(Size, Roflags), Errno> {
+        Ok((self.recv(buf, flags)?, 0))
+    }
+
+    fn sock_send(&mut self, buf: &[u8], _flags: Siflags) -> Result<Size, Errno> {
+        self.send(buf)
+    }
+
+    fn sock_shutdown(&mut self, how: Sdflags) -> Result<(), Errno> {
+        self.shutdown(how)
+    }
+
+    fn poll_read(&self) -> Option<Readiness> {
+        let rx = self.rx.borrow();
+        let hangup = rx.writer_closed || self.rd_shut;
+
+        match (rx.buf.len(), hangup) {
+            (0, false) => None,
+            (n, hangup) => Some(Readiness {
+                nbytes: n as u64,
+                hangup,
+            }),
+        }
+    }
+
+    fn poll_write(&self) -> Option<Readiness> {
+        Some(Readiness {
+            nbytes: 0,
+            hangup: self.wr_shut || self.tx.borrow().reader_closed,
+        })
+    }
+}
+
+// Listeners
+
+type Queue = Rc<RefCell<VecDeque<Socket>>>;
+
+struct Listener {
+    queue: Queue,
+}
+
+impl Handle for Listener {
+    fn filetype(&self) -> Filetype {
+        wasi::FILETYPE_SOCKET_STREAM
+    }
+
+    fn sock_accept(&mut self, _flags: Fdflags) -> Result<HandleRef, Errno> {
+        match self.queue.borrow_mut().pop_front() {
+            Some(s) => Ok(Rc::new(RefCell::new(s))),
+            None => Err(wasi::ERRNO_AGAIN),
+        }
+    }
+
+    fn poll_read(&self) -> Option<Readiness> {
+        match self.queue.borrow().len() {
+            0 => None,
+            n => Some(Readiness {
+                nbytes: n as u64,
+                hangup: false,
+            }),
+        }
+    }
+}
+
+/// Host side of a listener, opening connections for the guest to accept.
+#[derive(Clone)]
+pub struct Connector {
+    queue: Queue,
+}
+
+impl Connector {
+    pub fn connect(&self) -> Socket {
+        let (guest, host) = pair();
+        self.queue.borrow_mut().push_back(guest);
+
+        host
+    }
+}
+
+const RIGHTS_SOCKET: Rights = wasi::RIGHTS_FD_READ
+    | wasi::RIGHTS_FD_WRITE
+    | wasi::RIGHTS_FD_FDSTAT_SET_FLAGS
+    | wasi::RIGHTS_FD_FILESTAT_GET
+    | wasi::RIGHTS_POLL_FD_READWRITE
+    | wasi::RIGHTS_SOCK_SHUTDOWN;
+
+/// Opens a listener for the guest, as a runtime would preopen one.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn listen() -> (Fd, Connector) {
+    fd_table::ensure_installed();
+
+    let queue = Queue::default();
+    let mut entry = Entry::new(Listener {
+        queue: queue.clone(),
+    });
+    entry.rights_base = wasi::RIGHTS_SOCK_ACCEPT
+        | wasi::RIGHTS_FD_FDSTAT_SET_FLAGS
+        | wasi::RIGHTS_FD_FILESTAT_GET
+        | wasi::RIGHTS_POLL_FD_READWRITE;
+    entry.rights_inheriting = RIGHTS_SOCKET;
+
+    (fd_table::insert(entry), Connector { queue })
+}
+
+/// Hands one end of a connection, e.g. from [`pair`], to the guest.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn register(s: Socket) -> Fd {
+    fd_table::ensure_installed();
+
+    let mut entry = Entry::new(s);
+    entry.rights_base = RIGHTS_SOCKET;
+    entry.rights_inheriting = 0;
+
+    fd_table::insert(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recv_flags() -> Result<(), Errno> {
+        let (mut a, mut b) = pair();
+        let mut buf = [0; 4];
+
+        assert_eq!(b.recv(&mut buf, 0).err(), Some(wasi::ERRNO_AGAIN));
+
+        a.send(b"abc")?;
+        assert_eq!(b.recv(&mut buf, wasi::RIFLAGS_RECV_PEEK)?, 3);
+        assert_eq!(
+            b.recv(&mut buf, wasi::RIFLAGS_RECV_WAITALL).err(),
+            Some(wasi::ERRNO_AGAIN)
+        );
+        assert_eq!(b.recv(&mut buf, 0)?, 3);
+        assert_eq!(&buf[..3], b"abc");
+
+        a.send(b"d")?;
+        a.shutdown(wasi::SDFLAGS_WR)?;
+        assert_eq!(b.recv(&mut buf, wasi::RIFLAGS_RECV_WAITALL)?, 1);
+        assert_eq!(b.recv(&mut buf, 0)?, 0);
+        assert_eq!(a.send(b"e").err(), Some(wasi::ERRNO_PIPE));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_accept() -> Result<(), Errno> {
+        let queue = Queue::default();
+        let mut l = Listener {
+            queue: queue.clone(),
+        };
+        let c = Connector { queue };
+
+        assert!(l.poll_read().is_none());
+        assert_eq!(l.sock_accept(0).err(), Some(wasi::ERRNO_AGAIN));
+
+        let mut host = c.connect();
+        assert!(l.poll_read().is_some());
+
+        let guest = l.sock_accept(0)?;
+        host.write_all(b"hi").unwrap();
+        assert_eq!(guest.borrow().poll_read().map(|r| r.nbytes), Some(2));
+
+        drop(guest);
+        assert_eq!(
+            Write::write(&mut host, b"x").unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+
+        Ok(())
+    }
+}
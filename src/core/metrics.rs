@@ -0,0 +1,160 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    ptr::{addr_of, addr_of_mut},
+};
+
+use wasi::{Errno, Timestamp};
+
+use crate::core::{
+    clock,
+    layer::{self, Call, Layer},
+};
+
+// Types
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    pub calls: u64,
+    pub errors: BTreeMap<Errno, u64>,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    // Nanoseconds on the monotonic clock polyfill, zero if there is none
+    pub time: Timestamp,
+}
+
+impl Stats {
+    fn record(&mut self, call: &Call, ret: Errno) {
+        if ret != wasi::ERRNO_SUCCESS {
+            *self.errors.entry(ret).or_default() += 1;
+            return;
+        }
+
+        unsafe {
+            match *call {
+                Call::FdPread { rp0, .. }
+                | Call::FdRead { rp0, .. }
+                | Call::SockRecv { rp0, .. } => self.bytes_read += *rp0 as u64,
+                Call::FdPwrite { rp0, .. }
+                | Call::FdWrite { rp0, .. }
+                | Call::SockSend { rp0, .. } => self.bytes_written += *rp0 as u64,
+                _ => {}
+            }
+        }
+    }
+}
+
+pub type Snapshot = BTreeMap<&'static str, Stats>;
+
+// Layer
+
+static mut METRICS: Snapshot = BTreeMap::new();
+
+unsafe fn stats(call: &Call) -> &'static mut Stats {
+    (*addr_of_mut!(METRICS)).entry(call.name()).or_default()
+}
+
+// Start times of the calls in progress, which nest when a polyfill makes shim calls of its own
+struct Metrics {
+    started: Vec<Option<Timestamp>>,
+}
+
+impl Layer for Metrics {
+    fn enter(&mut self, call: &Call) -> Option<Errno> {
+        match call {
+            // Counted now, as it never returns
+            Call::ProcExit { .. } => unsafe { stats(call).calls += 1 },
+            _ => self.started.push(clock::now(wasi::CLOCKID_MONOTONIC).ok()),
+        }
+
+        None
+    }
+
+    fn exit(&mut self, call: &Call, ret: Errno) {
+        let start = self.started.pop().flatten();
+        let end = clock::now(wasi::CLOCKID_MONOTONIC).ok();
+
+        let stats = unsafe { stats(call) };
+        stats.calls += 1;
+        stats.record(call, ret);
+        if let (Some(start), Some(end)) = (start, end) {
+            stats.time += end.saturating_sub(start);
+        }
+    }
+}
+
+/// Starts counting shim calls. Functions that are never called are absent from the snapshot.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn install() {
+    layer::push(Metrics { started: vec![] });
+}
+
+pub fn snapshot() -> Snapshot {
+    unsafe { (*addr_of!(METRICS)).clone() }
+}
+
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn reset() {
+    (*addr_of_mut!(METRICS)).clear();
+}
+
+// Serialization
+
+/// One line per function: name, calls, bytes read, bytes written, time, then `errno=count`
+/// pairs, all separated by spaces.
+pub fn serialize(snapshot: &Snapshot) -> String {
+    let mut out = String::new();
+
+    for (name, s) in snapshot {
+        let _ = write!(
+            out,
+            "{name} {} {} {} {}",
+            s.calls, s.bytes_read, s.bytes_written, s.time
+        );
+        for (errno, n) in &s.errors {
+            let _ = write!(out, " {}={n}", errno.name());
+        }
+        out.push('\n');
+    }
+
+    out
+}
+
+/// Writes as much of the serialized snapshot as fits in `buf`, returning its full length.
+#[cfg(feature = "metrics-export")]
+#[allow(clippy::missing_safety_doc)]
+#[no_mangle]
+pub unsafe extern "C" fn wasi_shim_metrics(buf: *mut u8, buf_len: usize) -> usize {
+    let out = serialize(&snapshot());
+    let n = out.len().min(buf_len);
+    std::ptr::copy_nonoverlapping(out.as_ptr(), buf, n);
+
+    out.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let mut s = Stats::default();
+        let mut n = 5;
+        let call = Call::FdWrite {
+            fd: 1,
+            iovs: std::ptr::null(),
+            iovs_len: 0,
+            rp0: &mut n,
+        };
+
+        s.record(&call, wasi::ERRNO_SUCCESS);
+        s.record(&call, wasi::ERRNO_BADF);
+        s.record(&call, wasi::ERRNO_BADF);
+        assert_eq!(s.bytes_written, 5);
+        assert_eq!(s.errors.get(&wasi::ERRNO_BADF), Some(&2));
+
+        s.calls = 3;
+        let snapshot = Snapshot::from([("fd_write", s)]);
+        assert_eq!(serialize(&snapshot), "fd_write 3 0 5 0 BADF=2\n");
+    }
+}
@@ -1,10 +1,32 @@
 pub mod args;
 pub mod clock;
+#[cfg(any(test, feature = "conformance"))]
+pub mod conformance;
+pub mod defaults;
+pub mod embedded;
 pub mod environ;
+pub mod fault;
 pub mod fd;
+pub mod fd_table;
+#[cfg(unix)]
+pub mod hostfs;
+pub mod layer;
+mod mem;
+pub mod memfs;
+pub mod memsock;
+pub mod metrics;
+pub mod mount;
+pub mod overlay;
 pub mod path;
 pub mod poll;
+pub mod preopens;
 pub mod proc;
 pub mod random;
+pub mod record;
 pub mod sched;
+pub mod signal;
 pub mod sock;
+pub mod stdio;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod trace;
@@ -0,0 +1,554 @@
+use std::{any::Any, cell::RefCell, collections::BTreeSet, rc::Rc};
+
+use wasi::{
+    Dircookie, Errno, Fdflags, Filestat, Filetype, Fstflags, Lookupflags, Oflags, Rights, Timestamp,
+};
+
+use crate::core::{
+    fd_table::{Direntry, Handle, HandleRef},
+    preopens,
+};
+
+// Mounts
+
+type Components = Vec<String>;
+
+// Cookies at or above this mark belong to mount points listed after a backend's own entries
+const MOUNT_COOKIES: Dircookie = 1 << 63;
+
+#[derive(Default)]
+struct Mounts(Vec<(Components, HandleRef)>);
+
+// Where a guest path ends up: inside a mounted backend, or in the directories above the
+// mount points, which only exist to hold them
+enum Target {
+    Mount(HandleRef, String, bool),
+    Virtual(Components),
+}
+
+impl Mounts {
+    fn route(&self, path: Components, must_dir: bool) -> Target {
+        let found = self
+            .0
+            .iter()
+            .filter(|(at, _)| path.starts_with(at))
+            .max_by_key(|(at, _)| at.len());
+
+        match found {
+            Some((at, dir)) => {
+                let mut rest = path[at.len()..].join("/");
+                match (rest.is_empty(), must_dir) {
+                    (true, _) => rest.push('.'),
+                    (false, true) => rest.push('/'),
+                    _ => {}
+                }
+
+                Target::Mount(dir.clone(), rest, at.len() == path.len())
+            }
+            None => Target::Virtual(path),
+        }
+    }
+
+    // Whether `path` leads to a mount point
+    fn holds(&self, path: &[String]) -> bool {
+        self.0.iter().any(|(at, _)| at.starts_with(path))
+    }
+
+    // Names of the mount points directly below `path`
+    fn children(&self, path: &[String]) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .0
+            .iter()
+            .filter(|(at, _)| at.len() > path.len() && at.starts_with(path))
+            .map(|(at, _)| at[path.len()].clone())
+            .collect();
+        names.sort();
+        names.dedup();
+
+        names
+    }
+}
+
+// Guest paths are made absolute and rid of "." and ".." before being routed, so they can
+// be matched against mount points
+fn normalize(base: &[String], path: &str) -> Result<(Components, bool), Errno> {
+    if path.starts_with('/') {
+        return Err(wasi::ERRNO_PERM);
+    }
+    if path.is_empty() {
+        return Err(wasi::ERRNO_NOENT);
+    }
+
+    let mut out = base.to_vec();
+    for name in path.split('/').filter(|c| !c.is_empty()) {
+        match name {
+            "." => {}
+            ".." if out.len() == base.len() => return Err(wasi::ERRNO_PERM),
+            ".." => {
+                out.pop();
+            }
+            _ => out.push(name.to_string()),
+        }
+    }
+
+    Ok((out, path.ends_with('/')))
+}
+
+fn virtual_stat() -> Filestat {
+    Filestat {
+        dev: 0,
+        ino: 0,
+        filetype: wasi::FILETYPE_DIRECTORY,
+        nlink: 1,
+        size: 0,
+        atim: 0,
+        mtim: 0,
+        ctim: 0,
+    }
+}
+
+// Table
+
+/// Backends mounted at guest paths. Each path call goes to the backend with the longest
+/// mount point covering the path; renames and links between backends fail with `XDEV`.
+#[derive(Clone, Default)]
+pub struct MountTable {
+    mounts: Rc<RefCell<Mounts>>,
+}
+
+impl MountTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Mounts the directory `dir` at the absolute guest path `at`.
+    pub fn mount(&self, at: &str, dir: impl Handle) -> Result<(), Errno> {
+        if !at.starts_with('/') {
+            return Err(wasi::ERRNO_INVAL);
+        }
+        if dir.filetype() != wasi::FILETYPE_DIRECTORY {
+            return Err(wasi::ERRNO_NOTDIR);
+        }
+
+        let at = match at.trim_start_matches('/') {
+            "" => vec![],
+            at => normalize(&[], at)?.0,
+        };
+
+        let mut mounts = self.mounts.borrow_mut();
+        if mounts.0.iter().any(|(m, _)| *m == at) {
+            return Err(wasi::ERRNO_EXIST);
+        }
+        mounts.0.push((at, Rc::new(RefCell::new(dir))));
+
+        Ok(())
+    }
+
+    pub fn root(&self) -> MountDir {
+        MountDir {
+            mounts: self.mounts.clone(),
+            path: vec![],
+            backing: None,
+        }
+    }
+}
+
+/// A directory as seen through the mount table.
+pub struct MountDir {
+    mounts: Rc<RefCell<Mounts>>,
+    path: Components,
+    // The backend's directory, for one inside a mount that has mount points below it
+    backing: Option<HandleRef>,
+}
+
+impl MountDir {
+    fn route(&self, path: &str) -> Result<Target, Errno> {
+        let (path, must_dir) = normalize(&self.path, path)?;
+        Ok(self.mounts.borrow().route(path, must_dir))
+    }
+
+    fn holds(&self, path: &[String]) -> bool {
+        self.mounts.borrow().holds(path)
+    }
+
+    // Calls that create `path`, which cannot be done over a mount point or next to one
+    fn create(
+        &self,
+        path: &str,
+        f: impl FnOnce(&dyn Handle, &str) -> Result<(), Errno>,
+    ) -> Result<(), Errno> {
+        match self.route(path)? {
+            Target::Mount(_, _, true) => Err(wasi::ERRNO_EXIST),
+            Target::Mount(dir, rest, false) => f(&*dir.borrow(), &rest),
+            Target::Virtual(p) if self.holds(&p) => Err(wasi::ERRNO_EXIST),
+            Target::Virtual(_) => Err(wasi::ERRNO_ROFS),
+        }
+    }
+
+    // Calls that remove or replace `path`
+    fn remove(
+        &self,
+        path: &str,
+        f: impl FnOnce(&dyn Handle, &str) -> Result<(), Errno>,
+    ) -> Result<(), Errno> {
+        match self.route(path)? {
+            Target::Mount(_, _, true) => Err(wasi::ERRNO_BUSY),
+            Target::Mount(dir, rest, false) => f(&*dir.borrow(), &rest),
+            Target::Virtual(p) if self.holds(&p) => Err(wasi::ERRNO_BUSY),
+            Target::Virtual(_) => Err(wasi::ERRNO_NOENT),
+        }
+    }
+
+    // Calls that look `path` up
+    fn lookup<T>(
+        &self,
+        path: &str,
+        f: impl FnOnce(&dyn Handle, &str) -> Result<T, Errno>,
+        virt: impl FnOnce(Components) -> Result<T, Errno>,
+    ) -> Result<T, Errno> {
+        match self.route(path)? {
+            Target::Mount(dir, rest, _) => f(&*dir.borrow(), &rest),
+            Target::Virtual(p) if self.holds(&p) => virt(p),
+            Target::Virtual(_) => Err(wasi::ERRNO_NOENT),
+        }
+    }
+
+    // The other end of a rename or link: a backend and the path within it
+    fn peer(&self, other: &dyn Handle, path: &str) -> Result<(HandleRef, String), Errno> {
+        let any: &dyn Any = other;
+        let Some(other) = any.downcast_ref::<MountDir>() else {
+            return Err(wasi::ERRNO_XDEV);
+        };
+
+        match other.route(path)? {
+            Target::Mount(_, _, true) => Err(wasi::ERRNO_BUSY),
+            Target::Mount(dir, rest, false) => Ok((dir, rest)),
+            Target::Virtual(p) if other.holds(&p) => Err(wasi::ERRNO_BUSY),
+            Target::Virtual(_) => Err(wasi::ERRNO_ROFS),
+        }
+    }
+
+    fn mounted(&self) -> Option<HandleRef> {
+        match self.mounts.borrow().route(self.path.clone(), false) {
+            Target::Mount(dir, _, true) => Some(dir),
+            _ => self.backing.clone(),
+        }
+    }
+}
+
+impl Handle for MountDir {
+    fn filetype(&self) -> Filetype {
+        wasi::FILETYPE_DIRECTORY
+    }
+
+    fn filestat_get(&self) -> Result<Filestat, Errno> {
+        match self.mounted() {
+            Some(dir) => dir.borrow().filestat_get(),
+            None => Ok(virtual_stat()),
+        }
+    }
+
+    fn filestat_set_times(
+        &mut self,
+        atim: Timestamp,
+        mtim: Timestamp,
+        fst_flags: Fstflags,
+    ) -> Result<(), Errno> {
+        match self.mounted() {
+            Some(dir) => dir.borrow_mut().filestat_set_times(atim, mtim, fst_flags),
+            None => Err(wasi::ERRNO_ROFS),
+        }
+    }
+
+    // A backend mounted here lists its own entries first, then any mount points below that
+    // it does not already have, checked against its whole listing so resuming from any cookie
+    // gives the same answer
+    fn readdir(&self, cookie: Dircookie) -> Result<Vec<Direntry>, Errno> {
+        let mounted = self.mounted();
+        let names: BTreeSet<String> = match &mounted {
+            Some(dir) => dir
+                .borrow()
+                .readdir(0)?
+                .into_iter()
+                .map(|e| e.name)
+                .collect(),
+            None => BTreeSet::new(),
+        };
+
+        let base = match &mounted {
+            Some(_) => MOUNT_COOKIES,
+            None => 2,
+        };
+
+        let mut entries = match mounted {
+            Some(dir) if cookie < MOUNT_COOKIES => dir.borrow().readdir(cookie)?,
+            Some(_) => vec![],
+            None => [".", ".."]
+                .iter()
+                .enumerate()
+                .map(|(i, name)| Direntry {
+                    next: i as Dircookie + 1,
+                    ino: 0,
+                    filetype: wasi::FILETYPE_DIRECTORY,
+                    name: name.to_string(),
+                })
+                .filter(|e| e.next > cookie)
+                .collect(),
+        };
+
+        let children = self.mounts.borrow().children(&self.path);
+        for (i, name) in children.into_iter().enumerate() {
+            let next = base + i as Dircookie + 1;
+            if next > cookie && !names.contains(&name) {
+                entries.push(Direntry {
+                    next,
+                    ino: 0,
+                    filetype: wasi::FILETYPE_DIRECTORY,
+                    name,
+                });
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn path_open(
+        &self,
+        dirflags: Lookupflags,
+        path: &str,
+        oflags: Oflags,
+        rights: Rights,
+        fdflags: Fdflags,
+    ) -> Result<HandleRef, Errno> {
+        let excl = wasi::OFLAGS_CREAT | wasi::OFLAGS_EXCL;
+        let (p, must_dir) = normalize(&self.path, path)?;
+        let nested = !self.mounts.borrow().children(&p).is_empty();
+        let target = self.mounts.borrow().route(p.clone(), must_dir);
+
+        match target {
+            Target::Mount(_, _, true) if oflags & excl == excl => Err(wasi::ERRNO_EXIST),
+            // Above another mount point, the directory has to keep routing through the table
+            Target::Mount(dir, rest, _) if nested => {
+                let backing = dir
+                    .borrow()
+                    .path_open(dirflags, &rest, oflags, rights, fdflags)?;
+                if backing.borrow().filetype() != wasi::FILETYPE_DIRECTORY {
+                    return Ok(backing);
+                }
+
+                Ok(Rc::new(RefCell::new(MountDir {
+                    mounts: self.mounts.clone(),
+                    path: p,
+                    backing: Some(backing),
+                })))
+            }
+            Target::Mount(dir, rest, _) => dir
+                .borrow()
+                .path_open(dirflags, &rest, oflags, rights, fdflags),
+            Target::Virtual(p) if self.holds(&p) => match oflags {
+                o if o & excl == excl => Err(wasi::ERRNO_EXIST),
+                o if o & wasi::OFLAGS_TRUNC != 0 => Err(wasi::ERRNO_ISDIR),
+                _ => Ok(Rc::new(RefCell::new(MountDir {
+                    mounts: self.mounts.clone(),
+                    path: p,
+                    backing: None,
+                }))),
+            },
+            Target::Virtual(_) if oflags & wasi::OFLAGS_CREAT != 0 => Err(wasi::ERRNO_ROFS),
+            Target::Virtual(_) => Err(wasi::ERRNO_NOENT),
+        }
+    }
+
+    fn path_create_directory(&self, path: &str) -> Result<(), Errno> {
+        self.create(path, |dir, rest| dir.path_create_directory(rest))
+    }
+
+    fn path_filestat_get(&self, flags: Lookupflags, path: &str) -> Result<Filestat, Errno> {
+        self.lookup(
+            path,
+            |dir, rest| dir.path_filestat_get(flags, rest),
+            |_| Ok(virtual_stat()),
+        )
+    }
+
+    fn path_filestat_set_times(
+        &self,
+        flags: Lookupflags,
+        path: &str,
+        atim: Timestamp,
+        mtim: Timestamp,
+        fst_flags: Fstflags,
+    ) -> Result<(), Errno> {
+        self.lookup(
+            path,
+            |dir, rest| dir.path_filestat_set_times(flags, rest, atim, mtim, fst_flags),
+            |_| Err(wasi::ERRNO_ROFS),
+        )
+    }
+
+    fn path_link(
+        &self,
+        old_flags: Lookupflags,
+        old_path: &str,
+        new_dir: &dyn Handle,
+        new_path: &str,
+    ) -> Result<(), Errno> {
+        let (to, new_rest) = self.peer(new_dir, new_path)?;
+
+        self.lookup(
+            old_path,
+            |dir, rest| {
+                let to = to.borrow();
+                match std::ptr::addr_eq(dir, &*to) {
+                    true => dir.path_link(old_flags, rest, dir, &new_rest),
+                    false => Err(wasi::ERRNO_XDEV),
+                }
+            },
+            |_| Err(wasi::ERRNO_PERM),
+        )
+    }
+
+    fn path_readlink(&self, path: &str) -> Result<String, Errno> {
+        self.lookup(
+            path,
+            |dir, rest| dir.path_readlink(rest),
+            |_| Err(wasi::ERRNO_INVAL),
+        )
+    }
+
+    fn path_remove_directory(&self, path: &str) -> Result<(), Errno> {
+        self.remove(path, |dir, rest| dir.path_remove_directory(rest))
+    }
+
+    fn path_rename(
+        &self,
+        old_path: &str,
+        new_dir: &dyn Handle,
+        new_path: &str,
+    ) -> Result<(), Errno> {
+        let (to, new_rest) = self.peer(new_dir, new_path)?;
+
+        self.remove(old_path, |dir, rest| {
+            let to = to.borrow();
+            match std::ptr::addr_eq(dir, &*to) {
+                true => dir.path_rename(rest, dir, &new_rest),
+                false => Err(wasi::ERRNO_XDEV),
+            }
+        })
+    }
+
+    fn path_symlink(&self, old_path: &str, new_path: &str) -> Result<(), Errno> {
+        self.create(new_path, |dir, rest| dir.path_symlink(old_path, rest))
+    }
+
+    fn path_unlink_file(&self, path: &str) -> Result<(), Errno> {
+        self.remove(path, |dir, rest| dir.path_unlink_file(rest))
+    }
+}
+
+/// Serves the guest's `/` from `table`.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn install(table: &MountTable) {
+    preopens::install();
+    preopens::register("/", table.root());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::memfs::MemFs;
+
+    fn names(dir: &dyn Handle, cookie: Dircookie) -> Result<Vec<String>, Errno> {
+        Ok(dir.readdir(cookie)?.into_iter().map(|e| e.name).collect())
+    }
+
+    #[test]
+    fn test_routing() -> Result<(), Errno> {
+        let (root, tmp) = (MemFs::new(), MemFs::new());
+        root.create_dir_all("tmp")?;
+        root.write_file("a", b"root")?;
+
+        let table = MountTable::new();
+        table.mount("/", root.root())?;
+        table.mount("/tmp", tmp.root())?;
+        assert_eq!(
+            table.mount("/tmp/", MemFs::new().root()).err(),
+            Some(wasi::ERRNO_EXIST)
+        );
+
+        let dir = table.root();
+        dir.path_open(0, "tmp/b", wasi::OFLAGS_CREAT, 0, 0)?;
+        assert_eq!(tmp.read_file("b")?, b"");
+        assert_eq!(dir.path_filestat_get(0, "tmp/../a")?.size, 4);
+        assert_eq!(
+            dir.path_filestat_get(0, "../a").err(),
+            Some(wasi::ERRNO_PERM)
+        );
+
+        assert_eq!(
+            dir.path_rename("a", &dir, "tmp/a").err(),
+            Some(wasi::ERRNO_XDEV)
+        );
+        assert_eq!(
+            dir.path_link(0, "tmp/b", &dir, "c").err(),
+            Some(wasi::ERRNO_XDEV)
+        );
+        dir.path_rename("tmp/b", &dir, "tmp/c")?;
+        assert_eq!(tmp.read_file("c")?, b"");
+
+        // A mount point over one of the backend's own entries is listed once, from any cookie
+        let all = dir.readdir(0)?;
+        assert_eq!(all.iter().filter(|e| e.name == "tmp").count(), 1);
+        let after = all.iter().find(|e| e.name == "tmp").unwrap().next;
+        assert!(!names(&dir, after)?.contains(&"tmp".to_string()));
+
+        // A directory above a nested mount point still routes through the table
+        let nested = MemFs::new();
+        root.create_dir_all("data/b")?;
+        table.mount("/data/a", nested.root())?;
+        let data = dir.path_open(0, "data", wasi::OFLAGS_DIRECTORY, 0, 0)?;
+        assert_eq!(names(&*data.borrow(), 0)?, [".", "..", "b", "a"]);
+        data.borrow()
+            .path_open(0, "a/x", wasi::OFLAGS_CREAT, 0, 0)?;
+        assert_eq!(nested.read_file("x")?, b"");
+        assert_eq!(root.read_file("data/a/x").err(), Some(wasi::ERRNO_NOENT));
+
+        assert_eq!(
+            dir.path_remove_directory("tmp").err(),
+            Some(wasi::ERRNO_BUSY)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_virtual_dirs() -> Result<(), Errno> {
+        let table = MountTable::new();
+        table.mount("/data/a", MemFs::new().root())?;
+        table.mount("/data/b", MemFs::new().root())?;
+        table.mount("/assets", MemFs::new().root())?;
+
+        let dir = table.root();
+        assert_eq!(names(&dir, 0)?, [".", "..", "assets", "data"]);
+        assert_eq!(names(&dir, 3)?, ["data"]);
+
+        let data = dir.path_open(0, "data", wasi::OFLAGS_DIRECTORY, 0, 0)?;
+        assert_eq!(names(&*data.borrow(), 0)?, [".", "..", "a", "b"]);
+        assert_eq!(
+            data.borrow().path_create_directory("c").err(),
+            Some(wasi::ERRNO_ROFS)
+        );
+        data.borrow().path_create_directory("a/c")?;
+
+        assert_eq!(
+            dir.path_open(0, "missing", 0, 0, 0).err(),
+            Some(wasi::ERRNO_NOENT)
+        );
+        assert_eq!(
+            dir.path_filestat_get(0, "data")?.filetype,
+            wasi::FILETYPE_DIRECTORY
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,819 @@
+use std::{
+    any::Any,
+    cell::RefCell,
+    collections::BTreeSet,
+    rc::{Rc, Weak},
+};
+
+use wasi::{
+    Advice, Dircookie, Errno, Fdflags, Filedelta, Filesize, Filestat, Filetype, Fstflags,
+    Lookupflags, Oflags, Rights, Size, Timestamp, Whence,
+};
+
+use crate::core::{
+    fd_table::{Direntry, Handle, HandleRef, Readiness},
+    memfs::MemFs,
+    preopens,
+};
+
+// Cookies at or above this mark belong to entries of the lower layer
+const LOWER_COOKIES: Dircookie = 1 << 63;
+
+const MAX_SYMLINKS: usize = 40;
+
+// Filesystem
+
+/// Copy-on-write view of a read-only lower directory. Changes go to an in-memory upper layer,
+/// and removals of lower entries are recorded as whiteouts. A whiteout on a directory that
+/// exists in the upper layer makes it opaque, hiding whatever the lower layer has below it.
+#[derive(Clone)]
+pub struct OverlayFs {
+    lower: HandleRef,
+    upper: MemFs,
+    whiteouts: Rc<RefCell<BTreeSet<String>>>,
+    // Files open on the lower layer, which have to be copied up before their path goes away
+    files: Rc<RefCell<Vec<Weak<RefCell<OverlayFile>>>>>,
+}
+
+impl OverlayFs {
+    pub fn new(lower: impl Handle) -> Self {
+        Self {
+            lower: Rc::new(RefCell::new(lower)),
+            upper: MemFs::new(),
+            whiteouts: Rc::default(),
+            files: Rc::default(),
+        }
+    }
+
+    /// The layer holding everything written so far.
+    pub fn upper(&self) -> MemFs {
+        self.upper.clone()
+    }
+
+    pub fn root(&self) -> OverlayDir {
+        OverlayDir {
+            fs: self.clone(),
+            path: vec![],
+        }
+    }
+
+    // Whether a whiteout covers `path` or one of its parents
+    fn hidden(&self, path: &[String]) -> bool {
+        let whiteouts = self.whiteouts.borrow();
+        (1..=path.len()).any(|n| whiteouts.contains(&path[..n].join("/")))
+    }
+
+    fn upper_stat(&self, path: &[String]) -> Option<Filestat> {
+        self.upper.root().path_filestat_get(0, &key(path)).ok()
+    }
+
+    fn lower_stat(&self, path: &[String]) -> Option<Filestat> {
+        match self.hidden(path) {
+            true => None,
+            false => self.lower.borrow().path_filestat_get(0, &key(path)).ok(),
+        }
+    }
+
+    fn stat(&self, path: &[String]) -> Result<Filestat, Errno> {
+        self.upper_stat(path)
+            .or_else(|| self.lower_stat(path))
+            .ok_or(wasi::ERRNO_NOENT)
+    }
+
+    fn readlink(&self, path: &[String]) -> Result<String, Errno> {
+        match self.upper_stat(path) {
+            Some(_) => self.upper.root().path_readlink(&key(path)),
+            None if self.lower_stat(path).is_some() => {
+                self.lower.borrow().path_readlink(&key(path))
+            }
+            None => Err(wasi::ERRNO_NOENT),
+        }
+    }
+
+    // Resolves `path` one component at a time onto `stack`, following symlinks through either
+    // layer since a link in one may lead into the other. Like `..`, link targets cannot climb
+    // above the `base` components of the directory the walk started from
+    fn walk(
+        &self,
+        base: usize,
+        stack: &mut Vec<String>,
+        path: &str,
+        follow: bool,
+        depth: &mut usize,
+    ) -> Result<(), Errno> {
+        if path.starts_with('/') {
+            return Err(wasi::ERRNO_PERM);
+        }
+
+        let names: Vec<_> = path.split('/').filter(|c| !c.is_empty()).collect();
+        let must_dir = path.ends_with('/');
+        for (i, name) in names.iter().enumerate() {
+            match *name {
+                "." => continue,
+                ".." if stack.len() == base => return Err(wasi::ERRNO_PERM),
+                ".." => {
+                    stack.pop();
+                    continue;
+                }
+                _ => stack.push(name.to_string()),
+            }
+
+            let last = i == names.len() - 1;
+            let is_link = self
+                .stat(stack)
+                .is_ok_and(|s| s.filetype == wasi::FILETYPE_SYMBOLIC_LINK);
+            if is_link && (!last || follow || must_dir) {
+                *depth += 1;
+                if *depth > MAX_SYMLINKS {
+                    return Err(wasi::ERRNO_LOOP);
+                }
+
+                let target = self.readlink(stack)?;
+                stack.pop();
+                self.walk(base, stack, &target, true, depth)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn is_dir(&self, path: &[String]) -> bool {
+        self.stat(path)
+            .is_ok_and(|s| s.filetype == wasi::FILETYPE_DIRECTORY)
+    }
+
+    // The parent of `path` has to be a directory before anything can be made in it, and gets
+    // copied up so the upper layer can hold the new entry
+    fn prepare_parent(&self, path: &[String]) -> Result<(), Errno> {
+        let Some((_, parent)) = path.split_last() else {
+            return Err(wasi::ERRNO_EXIST);
+        };
+
+        match self.stat(parent)?.filetype {
+            wasi::FILETYPE_DIRECTORY => self.upper.create_dir_all(&key(parent)),
+            _ => Err(wasi::ERRNO_NOTDIR),
+        }
+    }
+
+    // Gives `path` an upper copy, taking along everything below it for a directory
+    fn copy_up(&self, path: &[String]) -> Result<(), Errno> {
+        let stat = self.stat(path)?;
+        match stat.filetype {
+            wasi::FILETYPE_DIRECTORY => {
+                self.upper.create_dir_all(&key(path))?;
+
+                for e in self.entries(path, 0)? {
+                    if e.name != "." && e.name != ".." {
+                        self.copy_up(&[path, &[e.name]].concat())?;
+                    }
+                }
+            }
+            _ if self.upper_stat(path).is_some() => {}
+            wasi::FILETYPE_SYMBOLIC_LINK => {
+                let target = self.lower.borrow().path_readlink(&key(path))?;
+                self.prepare_parent(path)?;
+                self.upper.root().path_symlink(&target, &key(path))?;
+            }
+            _ => {
+                let file =
+                    self.lower
+                        .borrow()
+                        .path_open(0, &key(path), 0, wasi::RIGHTS_FD_READ, 0)?;
+                let size = usize::try_from(stat.size).map_err(|_| wasi::ERRNO_FBIG)?;
+                let mut data = vec![0; size];
+                let mut n = 0;
+                while n < data.len() {
+                    match file.borrow_mut().pread(&mut data[n..], n as Filesize)? {
+                        0 => break,
+                        m => n += m,
+                    }
+                }
+                data.truncate(n);
+
+                self.prepare_parent(path)?;
+                self.upper.write_file(&key(path), &data)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Moves the files open at or below `path` over to upper copies, which they keep once the
+    // path is removed or replaced, as an open file would
+    fn detach(&self, path: &[String]) -> Result<(), Errno> {
+        let files: Vec<_> = {
+            let mut files = self.files.borrow_mut();
+            files.retain(|f| f.strong_count() > 0);
+            files.iter().filter_map(Weak::upgrade).collect()
+        };
+
+        for file in files {
+            let mut file = file.borrow_mut();
+            if file.path.starts_with(path) {
+                file.upper()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Upper entries keep their own cookies. Lower entries follow with theirs above
+    // `LOWER_COOKIES`, leaving out anything shadowed by the upper layer or whited out
+    fn entries(&self, path: &[String], cookie: Dircookie) -> Result<Vec<Direntry>, Errno> {
+        let is_dir = |s: &Filestat| s.filetype == wasi::FILETYPE_DIRECTORY;
+        let upper = self.upper_stat(path);
+        let lower = match upper {
+            Some(s) if !is_dir(&s) => None,
+            _ => self.lower_stat(path).filter(is_dir),
+        };
+        let upper = upper.filter(is_dir);
+
+        let mut entries = vec![];
+        let mut names = BTreeSet::new();
+        if upper.is_some() {
+            let dir = self.upper.root().path_open(0, &key(path), 0, 0, 0)?;
+            for e in dir.borrow().readdir(0)? {
+                names.insert(e.name.clone());
+                if e.next > cookie && cookie < LOWER_COOKIES {
+                    entries.push(e);
+                }
+            }
+        }
+
+        if lower.is_some() {
+            let dir = self.lower.borrow().path_open(0, &key(path), 0, 0, 0)?;
+            for mut e in dir.borrow().readdir(0)? {
+                let child = [path, &[e.name.clone()]].concat();
+                if names.contains(&e.name) || self.hidden(&child) {
+                    continue;
+                }
+
+                e.next |= LOWER_COOKIES;
+                if e.next > cookie {
+                    entries.push(e);
+                }
+            }
+        }
+
+        if upper.is_none() && lower.is_none() {
+            return Err(wasi::ERRNO_NOTDIR);
+        }
+
+        Ok(entries)
+    }
+}
+
+fn key(path: &[String]) -> String {
+    match path.is_empty() {
+        true => ".".to_string(),
+        false => path.join("/"),
+    }
+}
+
+// Handles
+
+// Reads come from the lower file until the first change, which copies it up and moves over to
+// the upper copy. Other descriptors already open on the lower file keep seeing the original,
+// until its path is removed or replaced, which moves them over as well
+struct OverlayFile {
+    fs: OverlayFs,
+    path: Vec<String>,
+    fdflags: Fdflags,
+    handle: HandleRef,
+    copied: bool,
+}
+
+impl OverlayFile {
+    fn upper(&mut self) -> Result<HandleRef, Errno> {
+        if !self.copied {
+            let pos = self.handle.borrow_mut().seek(0, wasi::WHENCE_CUR)?;
+            self.fs.copy_up(&self.path)?;
+
+            let handle = self.fs.upper.root().path_open(
+                0,
+                &key(&self.path),
+                0,
+                wasi::RIGHTS_FD_READ | wasi::RIGHTS_FD_WRITE,
+                self.fdflags,
+            )?;
+            handle
+                .borrow_mut()
+                .seek(pos as Filedelta, wasi::WHENCE_SET)?;
+
+            self.handle = handle;
+            self.copied = true;
+        }
+
+        Ok(self.handle.clone())
+    }
+}
+
+impl Handle for OverlayFile {
+    fn filetype(&self) -> Filetype {
+        self.handle.borrow().filetype()
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<Size, Errno> {
+        self.handle.borrow_mut().read(buf)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<Size, Errno> {
+        self.upper()?.borrow_mut().write(buf)
+    }
+
+    fn pread(&mut self, buf: &mut [u8], offset: Filesize) -> Result<Size, Errno> {
+        self.handle.borrow_mut().pread(buf, offset)
+    }
+
+    fn pwrite(&mut self, buf: &[u8], offset: Filesize) -> Result<Size, Errno> {
+        self.upper()?.borrow_mut().pwrite(buf, offset)
+    }
+
+    fn seek(&mut self, offset: Filedelta, whence: Whence) -> Result<Filesize, Errno> {
+        self.handle.borrow_mut().seek(offset, whence)
+    }
+
+    fn filestat_get(&self) -> Result<Filestat, Errno> {
+        self.handle.borrow().filestat_get()
+    }
+
+    fn filestat_set_size(&mut self, size: Filesize) -> Result<(), Errno> {
+        self.upper()?.borrow_mut().filestat_set_size(size)
+    }
+
+    fn filestat_set_times(
+        &mut self,
+        atim: Timestamp,
+        mtim: Timestamp,
+        fst_flags: Fstflags,
+    ) -> Result<(), Errno> {
+        self.upper()?
+            .borrow_mut()
+            .filestat_set_times(atim, mtim, fst_flags)
+    }
+
+    fn advise(&mut self, offset: Filesize, len: Filesize, advice: Advice) -> Result<(), Errno> {
+        self.handle.borrow_mut().advise(offset, len, advice)
+    }
+
+    fn allocate(&mut self, offset: Filesize, len: Filesize) -> Result<(), Errno> {
+        self.upper()?.borrow_mut().allocate(offset, len)
+    }
+
+    fn sync(&mut self) -> Result<(), Errno> {
+        self.handle.borrow_mut().sync()
+    }
+
+    fn datasync(&mut self) -> Result<(), Errno> {
+        self.handle.borrow_mut().datasync()
+    }
+
+    fn poll_read(&self) -> Option<Readiness> {
+        self.handle.borrow().poll_read()
+    }
+}
+
+pub struct OverlayDir {
+    fs: OverlayFs,
+    path: Vec<String>,
+}
+
+impl OverlayDir {
+    // A symlink at the end of `path` is followed with `LOOKUPFLAGS_SYMLINK_FOLLOW` or a
+    // trailing slash; any before it always are
+    fn resolve_with(&self, flags: Lookupflags, path: &str) -> Result<Vec<String>, Errno> {
+        if path.is_empty() {
+            return Err(wasi::ERRNO_NOENT);
+        }
+
+        let follow = flags & wasi::LOOKUPFLAGS_SYMLINK_FOLLOW != 0;
+        let mut stack = self.path.clone();
+        self.fs
+            .walk(self.path.len(), &mut stack, path, follow, &mut 0)?;
+
+        if path.ends_with('/') && self.fs.stat(&stack).is_ok() && !self.fs.is_dir(&stack) {
+            return Err(wasi::ERRNO_NOTDIR);
+        }
+
+        Ok(stack)
+    }
+
+    fn resolve(&self, path: &str) -> Result<Vec<String>, Errno> {
+        self.resolve_with(0, path)
+    }
+
+    fn peer<'a>(&self, other: &'a dyn Handle) -> Result<&'a OverlayDir, Errno> {
+        if other.filetype() != wasi::FILETYPE_DIRECTORY {
+            return Err(wasi::ERRNO_NOTDIR);
+        }
+
+        let any: &dyn Any = other;
+        match any.downcast_ref::<OverlayDir>() {
+            Some(d) if Rc::ptr_eq(&d.fs.whiteouts, &self.fs.whiteouts) => Ok(d),
+            _ => Err(wasi::ERRNO_XDEV),
+        }
+    }
+}
+
+impl Handle for OverlayDir {
+    fn filetype(&self) -> Filetype {
+        wasi::FILETYPE_DIRECTORY
+    }
+
+    fn filestat_get(&self) -> Result<Filestat, Errno> {
+        self.fs.stat(&self.path)
+    }
+
+    fn filestat_set_times(
+        &mut self,
+        atim: Timestamp,
+        mtim: Timestamp,
+        fst_flags: Fstflags,
+    ) -> Result<(), Errno> {
+        self.path_filestat_set_times(0, ".", atim, mtim, fst_flags)
+    }
+
+    fn readdir(&self, cookie: Dircookie) -> Result<Vec<Direntry>, Errno> {
+        self.fs.entries(&self.path, cookie)
+    }
+
+    fn path_open(
+        &self,
+        dirflags: Lookupflags,
+        path: &str,
+        oflags: Oflags,
+        rights: Rights,
+        fdflags: Fdflags,
+    ) -> Result<HandleRef, Errno> {
+        let path = self.resolve_with(dirflags, path)?;
+        let fs = &self.fs;
+        let stat = match fs.stat(&path) {
+            Ok(_) if oflags & wasi::OFLAGS_CREAT != 0 && oflags & wasi::OFLAGS_EXCL != 0 => {
+                return Err(wasi::ERRNO_EXIST)
+            }
+            Ok(stat) => stat,
+            Err(wasi::ERRNO_NOENT) if oflags & wasi::OFLAGS_CREAT != 0 => {
+                fs.prepare_parent(&path)?;
+                return fs
+                    .upper
+                    .root()
+                    .path_open(dirflags, &key(&path), oflags, rights, fdflags);
+            }
+            Err(err) => return Err(err),
+        };
+
+        match stat.filetype {
+            wasi::FILETYPE_DIRECTORY if oflags & wasi::OFLAGS_TRUNC != 0 => Err(wasi::ERRNO_ISDIR),
+            wasi::FILETYPE_DIRECTORY => Ok(Rc::new(RefCell::new(OverlayDir {
+                fs: fs.clone(),
+                path,
+            }))),
+            _ if oflags & wasi::OFLAGS_DIRECTORY != 0 => Err(wasi::ERRNO_NOTDIR),
+            _ if fs.upper_stat(&path).is_some() => {
+                fs.upper
+                    .root()
+                    .path_open(dirflags, &key(&path), oflags, rights, fdflags)
+            }
+            _ if oflags & wasi::OFLAGS_TRUNC != 0 => {
+                fs.prepare_parent(&path)?;
+                fs.upper.root().path_open(
+                    dirflags,
+                    &key(&path),
+                    oflags | wasi::OFLAGS_CREAT,
+                    rights,
+                    fdflags,
+                )
+            }
+            _ => {
+                let handle = fs.lower.borrow().path_open(
+                    dirflags,
+                    &key(&path),
+                    0,
+                    wasi::RIGHTS_FD_READ,
+                    0,
+                )?;
+                let file = Rc::new(RefCell::new(OverlayFile {
+                    fs: fs.clone(),
+                    path,
+                    fdflags,
+                    handle,
+                    copied: false,
+                }));
+                fs.files.borrow_mut().push(Rc::downgrade(&file));
+
+                Ok(file)
+            }
+        }
+    }
+
+    fn path_create_directory(&self, path: &str) -> Result<(), Errno> {
+        let path = self.resolve(path)?;
+        if self.fs.stat(&path).is_ok() {
+            return Err(wasi::ERRNO_EXIST);
+        }
+
+        self.fs.prepare_parent(&path)?;
+        self.fs.upper.root().path_create_directory(&key(&path))
+    }
+
+    fn path_filestat_get(&self, flags: Lookupflags, path: &str) -> Result<Filestat, Errno> {
+        self.fs.stat(&self.resolve_with(flags, path)?)
+    }
+
+    fn path_filestat_set_times(
+        &self,
+        flags: Lookupflags,
+        path: &str,
+        atim: Timestamp,
+        mtim: Timestamp,
+        fst_flags: Fstflags,
+    ) -> Result<(), Errno> {
+        let path = self.resolve_with(flags, path)?;
+        self.fs.copy_up(&path)?;
+
+        self.fs
+            .upper
+            .root()
+            .path_filestat_set_times(flags, &key(&path), atim, mtim, fst_flags)
+    }
+
+    fn path_link(
+        &self,
+        old_flags: Lookupflags,
+        old_path: &str,
+        new_dir: &dyn Handle,
+        new_path: &str,
+    ) -> Result<(), Errno> {
+        let new_dir = self.peer(new_dir)?;
+        let old = self.resolve_with(old_flags, old_path)?;
+        let new = new_dir.resolve(new_path)?;
+
+        if self.fs.is_dir(&old) {
+            return Err(wasi::ERRNO_PERM);
+        }
+        if self.fs.stat(&new).is_ok() {
+            return Err(wasi::ERRNO_EXIST);
+        }
+
+        self.fs.copy_up(&old)?;
+        self.fs.prepare_parent(&new)?;
+
+        let upper = self.fs.upper.root();
+        upper.path_link(old_flags, &key(&old), &upper, &key(&new))
+    }
+
+    fn path_readlink(&self, path: &str) -> Result<String, Errno> {
+        self.fs.readlink(&self.resolve(path)?)
+    }
+
+    fn path_remove_directory(&self, path: &str) -> Result<(), Errno> {
+        let path = self.resolve(path)?;
+        if path.is_empty() {
+            return Err(wasi::ERRNO_BUSY);
+        }
+        if !self.fs.is_dir(&path) {
+            self.fs.stat(&path)?;
+            return Err(wasi::ERRNO_NOTDIR);
+        }
+
+        let entries = self.fs.entries(&path, 0)?;
+        if entries.iter().any(|e| e.name != "." && e.name != "..") {
+            return Err(wasi::ERRNO_NOTEMPTY);
+        }
+
+        if self.fs.upper_stat(&path).is_some() {
+            self.fs.upper.root().path_remove_directory(&key(&path))?;
+        }
+        if self.fs.lower_stat(&path).is_some() {
+            self.fs.whiteouts.borrow_mut().insert(path.join("/"));
+        }
+
+        Ok(())
+    }
+
+    fn path_rename(
+        &self,
+        old_path: &str,
+        new_dir: &dyn Handle,
+        new_path: &str,
+    ) -> Result<(), Errno> {
+        let new_dir = self.peer(new_dir)?;
+        let (old, new) = (self.resolve(old_path)?, new_dir.resolve(new_path)?);
+        if old.is_empty() || new.is_empty() {
+            return Err(wasi::ERRNO_BUSY);
+        }
+        if old == new {
+            return self.fs.stat(&old).map(|_| ());
+        }
+
+        let is_dir = self.fs.stat(&old)?.filetype == wasi::FILETYPE_DIRECTORY;
+        if new.starts_with(&old) {
+            return Err(wasi::ERRNO_INVAL);
+        }
+
+        // The target is checked against the merged view, then replaced in the upper layer
+        match (is_dir, self.fs.stat(&new).map(|s| s.filetype)) {
+            (true, Ok(wasi::FILETYPE_DIRECTORY)) => {
+                let entries = self.fs.entries(&new, 0)?;
+                if entries.iter().any(|e| e.name != "." && e.name != "..") {
+                    return Err(wasi::ERRNO_NOTEMPTY);
+                }
+            }
+            (true, Ok(_)) => return Err(wasi::ERRNO_NOTDIR),
+            (false, Ok(wasi::FILETYPE_DIRECTORY)) => return Err(wasi::ERRNO_ISDIR),
+            (_, Ok(_)) | (_, Err(wasi::ERRNO_NOENT)) => {}
+            (_, Err(err)) => return Err(err),
+        }
+
+        self.fs.detach(&old)?;
+        self.fs.detach(&new)?;
+        self.fs.copy_up(&old)?;
+        self.fs.prepare_parent(&new)?;
+
+        let upper = self.fs.upper.root();
+        upper.path_rename(&key(&old), &upper, &key(&new))?;
+
+        let mut whiteouts = self.fs.whiteouts.borrow_mut();
+        whiteouts.insert(old.join("/"));
+        if is_dir {
+            whiteouts.insert(new.join("/"));
+        }
+
+        Ok(())
+    }
+
+    fn path_symlink(&self, old_path: &str, new_path: &str) -> Result<(), Errno> {
+        let path = self.resolve(new_path)?;
+        if self.fs.stat(&path).is_ok() {
+            return Err(wasi::ERRNO_EXIST);
+        }
+
+        self.fs.prepare_parent(&path)?;
+        self.fs.upper.root().path_symlink(old_path, &key(&path))
+    }
+
+    fn path_unlink_file(&self, path: &str) -> Result<(), Errno> {
+        let path = self.resolve(path)?;
+        if self.fs.is_dir(&path) {
+            return Err(wasi::ERRNO_ISDIR);
+        }
+        self.fs.stat(&path)?;
+        self.fs.detach(&path)?;
+
+        if self.fs.upper_stat(&path).is_some() {
+            self.fs.upper.root().path_unlink_file(&key(&path))?;
+        }
+        if self.fs.lower_stat(&path).is_some() {
+            self.fs.whiteouts.borrow_mut().insert(path.join("/"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Serves the guest's `/` from `fs`.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn install(fs: OverlayFs) {
+    preopens::install();
+    preopens::register("/", fs.root());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::embedded::{EmbeddedFs, Entry};
+
+    static ENTRIES: &[Entry] = &[
+        ("etc/app.conf", Some(b"debug = false\n")),
+        ("etc/motd", Some(b"hi")),
+        ("share/a", Some(b"a")),
+    ];
+
+    fn names(dir: &dyn Handle) -> Result<Vec<String>, Errno> {
+        let mut names: Vec<_> = dir.readdir(0)?.into_iter().map(|e| e.name).collect();
+        names.sort();
+        Ok(names)
+    }
+
+    #[test]
+    fn test_copy_on_write() -> Result<(), Errno> {
+        let fs = OverlayFs::new(EmbeddedFs::new(ENTRIES).root());
+        let root = fs.root();
+
+        let file = root.path_open(0, "etc/app.conf", 0, 0, 0)?;
+        let mut buf = [0; 5];
+        assert_eq!(file.borrow_mut().read(&mut buf)?, 5);
+        assert_eq!(
+            fs.upper().read_file("etc/app.conf").err(),
+            Some(wasi::ERRNO_NOENT)
+        );
+
+        // Writing continues from the position reached in the lower file
+        file.borrow_mut().write(b"=")?;
+        assert_eq!(fs.upper().read_file("etc/app.conf")?, b"debug== false\n");
+        assert_eq!(root.path_filestat_get(0, "etc/app.conf")?.size, 14);
+
+        root.path_open(0, "etc/motd", wasi::OFLAGS_TRUNC, 0, 0)?;
+        assert_eq!(root.path_filestat_get(0, "etc/motd")?.size, 0);
+
+        root.path_open(0, "etc/new", wasi::OFLAGS_CREAT, 0, 0)?;
+        let etc = root.path_open(0, "etc", wasi::OFLAGS_DIRECTORY, 0, 0)?;
+        assert_eq!(
+            names(&*etc.borrow())?,
+            [".", "..", "app.conf", "motd", "new"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_whiteouts() -> Result<(), Errno> {
+        let fs = OverlayFs::new(EmbeddedFs::new(ENTRIES).root());
+        let root = fs.root();
+
+        root.path_unlink_file("etc/motd")?;
+        assert_eq!(
+            root.path_filestat_get(0, "etc/motd").err(),
+            Some(wasi::ERRNO_NOENT)
+        );
+
+        root.path_rename("etc/app.conf", &root, "app.conf")?;
+        assert_eq!(fs.upper().read_file("app.conf")?, b"debug = false\n");
+        assert_eq!(
+            root.path_remove_directory("share").err(),
+            Some(wasi::ERRNO_NOTEMPTY)
+        );
+        root.path_remove_directory("etc")?;
+        assert_eq!(names(&root)?, [".", "..", "app.conf", "share"]);
+
+        // A directory made again where one was removed starts out empty
+        root.path_unlink_file("share/a")?;
+        root.path_remove_directory("share")?;
+        root.path_create_directory("share")?;
+        let share = root.path_open(0, "share", wasi::OFLAGS_DIRECTORY, 0, 0)?;
+        assert_eq!(names(&*share.borrow())?, [".", ".."]);
+
+        root.path_rename("share", &root, "etc")?;
+        assert_eq!(names(&root)?, [".", "..", "app.conf", "etc"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_symlinks() -> Result<(), Errno> {
+        let fs = OverlayFs::new(EmbeddedFs::new(ENTRIES).root());
+        let root = fs.root();
+        let follow = wasi::LOOKUPFLAGS_SYMLINK_FOLLOW;
+
+        // Links in the upper layer lead into the lower one
+        root.path_symlink("../etc", "share/etc")?;
+        assert_eq!(
+            root.path_filestat_get(0, "share/etc")?.filetype,
+            wasi::FILETYPE_SYMBOLIC_LINK
+        );
+        assert_eq!(
+            root.path_filestat_get(follow, "share/etc")?.filetype,
+            wasi::FILETYPE_DIRECTORY
+        );
+        assert_eq!(
+            root.path_open(0, "share/etc", wasi::OFLAGS_DIRECTORY, 0, 0)
+                .err(),
+            Some(wasi::ERRNO_NOTDIR)
+        );
+        let etc = root.path_open(follow, "share/etc", wasi::OFLAGS_DIRECTORY, 0, 0)?;
+        assert_eq!(names(&*etc.borrow())?, [".", "..", "app.conf", "motd"]);
+        assert_eq!(
+            root.path_filestat_get(0, "share/etc/")?.filetype,
+            wasi::FILETYPE_DIRECTORY
+        );
+
+        // Later components go on from the target, `..` included
+        let file = root.path_open(0, "share/etc/app.conf", 0, 0, 0)?;
+        let mut buf = [0; 5];
+        assert_eq!(file.borrow_mut().read(&mut buf)?, 5);
+        assert_eq!(&buf, b"debug");
+        assert_eq!(root.path_filestat_get(0, "share/etc/../share/a")?.size, 1);
+        root.path_open(0, "share/etc/new", wasi::OFLAGS_CREAT, 0, 0)?;
+        assert!(fs.upper().read_file("etc/new").is_ok());
+
+        // Links to lower files are followed before the target is copied up
+        root.path_symlink("etc/motd", "motd")?;
+        root.path_filestat_set_times(follow, "motd", 0, 5, wasi::FSTFLAGS_MTIM)?;
+        assert_eq!(root.path_filestat_get(0, "etc/motd")?.mtim, 5);
+        root.path_link(follow, "motd", &root, "hard")?;
+        assert_eq!(fs.upper().read_file("hard")?, b"hi");
+
+        root.path_symlink("loop", "loop")?;
+        assert_eq!(
+            root.path_filestat_get(follow, "loop").err(),
+            Some(wasi::ERRNO_LOOP)
+        );
+        root.path_symlink("/etc", "abs")?;
+        assert_eq!(
+            root.path_filestat_get(follow, "abs").err(),
+            Some(wasi::ERRNO_PERM)
+        );
+
+        Ok(())
+    }
+}
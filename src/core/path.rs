@@ -146,162 +146,82 @@ pub mod set {
 
 pub mod shims {
     use super::*;
-
-    #[no_mangle]
-    unsafe extern "C" fn __shim_path_create_directory(
-        fd: Fd,
-        path: *const u8,
-        path_len: i32,
-    ) -> Errno {
-        match POLYFILLS.create_directory {
-            Some(f) => f(fd, path, path_len),
-            None => unimplemented!("path_create_directory"),
-        }
-    }
-
-    #[no_mangle]
-    unsafe extern "C" fn __shim_path_filestat_get(
-        fd: Fd,
-        flags: Lookupflags,
-        path: *const u8,
-        path_len: i32,
-        rp0: *mut Filestat,
-    ) -> Errno {
-        match POLYFILLS.filestat_get {
-            Some(f) => f(fd, flags, path, path_len, rp0),
-            None => unimplemented!("path_filestat_get"),
-        }
-    }
-
-    #[no_mangle]
-    unsafe extern "C" fn __shim_path_filestat_set_times(
-        fd: Fd,
-        flags: Lookupflags,
-        path: *const u8,
-        path_len: i32,
-        atim: Timestamp,
-        mtim: Timestamp,
-        fst_flags: Fstflags,
-    ) -> Errno {
-        match POLYFILLS.filestat_set_times {
-            Some(f) => f(fd, flags, path, path_len, atim, mtim, fst_flags),
-            None => unimplemented!("path_filestat_set_times"),
-        }
-    }
-
-    #[no_mangle]
-    unsafe extern "C" fn __shim_path_link(
-        old_fd: Fd,
-        old_flags: Lookupflags,
-        old_path: *const u8,
-        old_path_len: i32,
-        new_fd: Fd,
-        new_path: *const u8,
-        new_path_len: i32,
-    ) -> Errno {
-        match POLYFILLS.link {
-            Some(f) => f(
-                old_fd,
-                old_flags,
-                old_path,
-                old_path_len,
-                new_fd,
-                new_path,
-                new_path_len,
-            ),
-            None => unimplemented!("path_link"),
-        }
-    }
-
-    #[no_mangle]
-    unsafe extern "C" fn __shim_path_open(
-        fd: Fd,
-        dirflags: Lookupflags,
-        path: *const u8,
-        path_len: i32,
-        oflags: Oflags,
-        fs_rights_base: Rights,
-        fs_rights_inheriting: Rights,
-        fdflags: Fdflags,
-        rp0: *mut Fd,
-    ) -> Errno {
-        match POLYFILLS.open {
-            Some(f) => f(
-                fd,
-                dirflags,
-                path,
-                path_len,
-                oflags,
-                fs_rights_base,
-                fs_rights_inheriting,
-                fdflags,
-                rp0,
-            ),
-            None => unimplemented!("path_open"),
-        }
-    }
-
-    #[no_mangle]
-    unsafe extern "C" fn __shim_path_readlink(
-        fd: Fd,
-        path: *const u8,
-        path_len: i32,
-        buf: *mut u8,
-        buf_len: Size,
-        rp0: *mut Size,
-    ) -> Errno {
-        match POLYFILLS.readlink {
-            Some(f) => f(fd, path, path_len, buf, buf_len, rp0),
-            None => unimplemented!("path_readlink"),
-        }
-    }
-
-    #[no_mangle]
-    unsafe extern "C" fn __shim_path_remove_directory(
-        fd: Fd,
-        path: *const u8,
-        path_len: i32,
-    ) -> Errno {
-        match POLYFILLS.remove_directory {
-            Some(f) => f(fd, path, path_len),
-            None => unimplemented!("path_remove_directory"),
-        }
-    }
-
-    #[no_mangle]
-    unsafe extern "C" fn __shim_path_rename(
-        fd: Fd,
-        old_path: *const u8,
-        old_path_len: i32,
-        new_fd: Fd,
-        new_path: *const u8,
-        new_path_len: i32,
-    ) -> Errno {
-        match POLYFILLS.rename {
-            Some(f) => f(fd, old_path, old_path_len, new_fd, new_path, new_path_len),
-            None => unimplemented!("path_rename"),
-        }
-    }
-
-    #[no_mangle]
-    unsafe extern "C" fn __shim_path_symlink(
-        old_path: *const u8,
-        old_path_len: i32,
-        fd: Fd,
-        new_path: *const u8,
-        new_path_len: i32,
-    ) -> Errno {
-        match POLYFILLS.symlink {
-            Some(f) => f(old_path, old_path_len, fd, new_path, new_path_len),
-            None => unimplemented!("path_symlink"),
-        }
-    }
-
-    #[no_mangle]
-    unsafe extern "C" fn __shim_path_unlink_file(fd: Fd, path: *const u8, path_len: i32) -> Errno {
-        match POLYFILLS.unlink_file {
-            Some(f) => f(fd, path, path_len),
-            None => unimplemented!("path_unlink_file"),
-        }
+    use crate::core::layer::shims;
+
+    shims! {
+        __shim_path_create_directory => PathCreateDirectory {
+            fd: Fd,
+            path: *const u8,
+            path_len: i32,
+        } => create_directory;
+        __shim_path_filestat_get => PathFilestatGet {
+            fd: Fd,
+            flags: Lookupflags,
+            path: *const u8,
+            path_len: i32,
+            rp0: *mut Filestat,
+        } => filestat_get;
+        __shim_path_filestat_set_times => PathFilestatSetTimes {
+            fd: Fd,
+            flags: Lookupflags,
+            path: *const u8,
+            path_len: i32,
+            atim: Timestamp,
+            mtim: Timestamp,
+            fst_flags: Fstflags,
+        } => filestat_set_times;
+        __shim_path_link => PathLink {
+            old_fd: Fd,
+            old_flags: Lookupflags,
+            old_path: *const u8,
+            old_path_len: i32,
+            new_fd: Fd,
+            new_path: *const u8,
+            new_path_len: i32,
+        } => link;
+        __shim_path_open => PathOpen {
+            fd: Fd,
+            dirflags: Lookupflags,
+            path: *const u8,
+            path_len: i32,
+            oflags: Oflags,
+            fs_rights_base: Rights,
+            fs_rights_inheriting: Rights,
+            fdflags: Fdflags,
+            rp0: *mut Fd,
+        } => open;
+        __shim_path_readlink => PathReadlink {
+            fd: Fd,
+            path: *const u8,
+            path_len: i32,
+            buf: *mut u8,
+            buf_len: Size,
+            rp0: *mut Size,
+        } => readlink;
+        __shim_path_remove_directory => PathRemoveDirectory {
+            fd: Fd,
+            path: *const u8,
+            path_len: i32,
+        } => remove_directory;
+        __shim_path_rename => PathRename {
+            fd: Fd,
+            old_path: *const u8,
+            old_path_len: i32,
+            new_fd: Fd,
+            new_path: *const u8,
+            new_path_len: i32,
+        } => rename;
+        __shim_path_symlink => PathSymlink {
+            old_path: *const u8,
+            old_path_len: i32,
+            fd: Fd,
+            new_path: *const u8,
+            new_path_len: i32,
+        } => symlink;
+        __shim_path_unlink_file => PathUnlinkFile {
+            fd: Fd,
+            path: *const u8,
+            path_len: i32,
+        } => unlink_file;
     }
 }
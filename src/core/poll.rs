@@ -1,4 +1,8 @@
-use wasi::{Errno, Event, Size, Subscription};
+use std::ptr::addr_of;
+
+use wasi::{Clockid, Errno, Event, EventFdReadwrite, Size, Subscription, Timestamp};
+
+use crate::core::{clock, fd_table, mem};
 
 // Types
 
@@ -22,21 +26,242 @@ pub mod set {
     }
 }
 
+// Backends
+
+pub type SleepFn = fn(id: Clockid, duration: Timestamp);
+
+pub enum Sleep {
+    Host(SleepFn),
+    // Moves the virtual clock on instead of waiting, see `clock::set_manual`
+    Virtual,
+}
+
+static mut SLEEP: Sleep = Sleep::Virtual;
+
+const CLOCK: u8 = wasi::EVENTTYPE_CLOCK.raw();
+const FD_READ: u8 = wasi::EVENTTYPE_FD_READ.raw();
+const FD_WRITE: u8 = wasi::EVENTTYPE_FD_WRITE.raw();
+
+/// Serves `poll_oneoff` from the clock polyfills and the fd table, waiting out timeouts with
+/// `sleep`.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn install(sleep: Sleep) {
+    SLEEP = sleep;
+
+    set::poll_oneoff(oneoff);
+}
+
+fn oneoff(
+    in_: *const Subscription,
+    out: *mut Event,
+    nsubscriptions: Size,
+    rp0: *mut Size,
+) -> Errno {
+    if nsubscriptions == 0 {
+        return wasi::ERRNO_INVAL;
+    }
+
+    let subs = unsafe { std::slice::from_raw_parts(in_, nsubscriptions) };
+    let sleep = |id, d| match unsafe { &*addr_of!(SLEEP) } {
+        Sleep::Host(f) => f(id, d),
+        Sleep::Virtual => unsafe { clock::advance(d) },
+    };
+
+    mem::errno(poll(subs, clock::now, sleep).map(|events| unsafe {
+        std::ptr::copy_nonoverlapping(events.as_ptr(), out, events.len());
+        *rp0 = events.len();
+    }))
+}
+
+// Subscriptions are checked once, and if none is ready the nearest timeout is slept through and
+// they are checked a second time. A timeout that was due within the sleep fires on that second
+// pass even if its clock did not move, so a clock that only moves when told to cannot hang here
+fn poll(
+    subs: &[Subscription],
+    now: impl Fn(Clockid) -> Result<Timestamp, Errno>,
+    mut sleep: impl FnMut(Clockid, Timestamp),
+) -> Result<Vec<Event>, Errno> {
+    let deadlines: Vec<Result<Timestamp, Errno>> = subs
+        .iter()
+        .map(|s| {
+            if s.u.tag != CLOCK {
+                return Ok(0);
+            }
+
+            let c = unsafe { s.u.u.clock };
+            match c.flags & wasi::SUBCLOCKFLAGS_SUBSCRIPTION_CLOCK_ABSTIME {
+                0 => Ok(now(c.id)?.saturating_add(c.timeout)),
+                _ => Ok(c.timeout),
+            }
+        })
+        .collect();
+
+    let mut waits = vec![None; subs.len()];
+    let mut slept = None;
+
+    loop {
+        let mut events = vec![];
+
+        for (i, s) in subs.iter().enumerate() {
+            let event = |error, nbytes, flags| Event {
+                userdata: s.userdata,
+                error,
+                type_: match s.u.tag {
+                    FD_READ => wasi::EVENTTYPE_FD_READ,
+                    FD_WRITE => wasi::EVENTTYPE_FD_WRITE,
+                    _ => wasi::EVENTTYPE_CLOCK,
+                },
+                fd_readwrite: EventFdReadwrite { nbytes, flags },
+            };
+
+            match s.u.tag {
+                CLOCK => {
+                    let c = unsafe { s.u.u.clock };
+                    let remaining =
+                        match deadlines[i].and_then(|d| Ok(d.saturating_sub(now(c.id)?))) {
+                            Ok(remaining) => remaining,
+                            Err(err) => {
+                                events.push(event(err, 0, 0));
+                                continue;
+                            }
+                        };
+
+                    match (remaining, waits[i], slept) {
+                        (0, _, _) => events.push(event(wasi::ERRNO_SUCCESS, 0, 0)),
+                        (_, Some(wait), Some(slept)) if wait <= slept => {
+                            events.push(event(wasi::ERRNO_SUCCESS, 0, 0))
+                        }
+                        _ => waits[i] = waits[i].or(Some(remaining)),
+                    }
+                }
+                FD_READ | FD_WRITE => {
+                    let fd = unsafe { s.u.u.fd_read.file_descriptor };
+                    let ready = fd_table::handle(fd, wasi::RIGHTS_POLL_FD_READWRITE).map(|h| {
+                        let h = h.borrow();
+                        match s.u.tag {
+                            FD_READ => h.poll_read(),
+                            _ => h.poll_write(),
+                        }
+                    });
+
+                    match ready {
+                        Ok(Some(r)) => {
+                            let flags = match r.hangup {
+                                true => wasi::EVENTRWFLAGS_FD_READWRITE_HANGUP,
+                                false => 0,
+                            };
+                            events.push(event(wasi::ERRNO_SUCCESS, r.nbytes, flags))
+                        }
+                        Ok(None) => {}
+                        Err(err) => events.push(event(err, 0, 0)),
+                    }
+                }
+                _ => events.push(event(wasi::ERRNO_INVAL, 0, 0)),
+            }
+        }
+
+        if !events.is_empty() || slept.is_some() {
+            return Ok(events);
+        }
+
+        // Nothing can become ready without a timeout to wait for
+        let (i, wait) = waits
+            .iter()
+            .enumerate()
+            .filter_map(|(i, w)| w.map(|w| (i, w)))
+            .min_by_key(|(_, w)| *w)
+            .ok_or(wasi::ERRNO_DEADLK)?;
+
+        sleep(unsafe { subs[i].u.u.clock.id }, wait);
+        slept = Some(wait);
+    }
+}
+
 // Shims
 
 pub mod shims {
+    use super::*;
+    use crate::core::layer::shims;
+
+    shims! {
+        __shim_poll_oneoff => PollOneoff {
+            in_: *const Subscription,
+            out: *mut Event,
+            nsubscriptions: Size,
+            rp0: *mut Size,
+        } => oneoff;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use wasi::{SubscriptionClock, SubscriptionFdReadwrite, SubscriptionU, SubscriptionUU};
+
     use super::*;
 
-    #[no_mangle]
-    unsafe extern "C" fn __shim_poll_oneoff(
-        in_: *const Subscription,
-        out: *mut Event,
-        nsubscriptions: Size,
-        rp0: *mut Size,
-    ) -> Errno {
-        match POLYFILLS.oneoff {
-            Some(f) => f(in_, out, nsubscriptions, rp0),
-            None => unimplemented!("poll_oneoff"),
+    fn clock(userdata: u64, timeout: Timestamp, flags: u16) -> Subscription {
+        Subscription {
+            userdata,
+            u: SubscriptionU {
+                tag: wasi::EVENTTYPE_CLOCK.raw(),
+                u: SubscriptionUU {
+                    clock: SubscriptionClock {
+                        id: wasi::CLOCKID_MONOTONIC,
+                        timeout,
+                        precision: 0,
+                        flags,
+                    },
+                },
+            },
         }
     }
+
+    #[test]
+    fn test_poll_clocks() -> Result<(), Errno> {
+        let t = Cell::new(100);
+        let now = |_| Ok(t.get());
+        let sleep = |_, d| t.set(t.get() + d);
+
+        // The nearest timeout fires, the later one is left pending
+        let subs = [clock(1, 50, 0), clock(2, 30, 0)];
+        let events = poll(&subs, now, sleep)?;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].userdata, 2);
+        assert_eq!(t.get(), 130);
+
+        // An absolute timeout in the past is ready straight away
+        let abstime = wasi::SUBCLOCKFLAGS_SUBSCRIPTION_CLOCK_ABSTIME;
+        let events = poll(&[clock(3, 10, abstime)], now, sleep)?;
+        assert_eq!(events[0].userdata, 3);
+        assert_eq!(t.get(), 130);
+
+        // A clock that never moves still lets the timeout fire
+        let events = poll(&[clock(4, 20, 0)], |_| Ok(0), |_, _| {})?;
+        assert_eq!(events[0].userdata, 4);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_poll_bad_fd() -> Result<(), Errno> {
+        let sub = Subscription {
+            userdata: 7,
+            u: SubscriptionU {
+                tag: wasi::EVENTTYPE_FD_READ.raw(),
+                u: SubscriptionUU {
+                    fd_read: SubscriptionFdReadwrite {
+                        file_descriptor: 99,
+                    },
+                },
+            },
+        };
+
+        let events = poll(&[sub], |_| Ok(0), |_, _| {})?;
+        assert_eq!(events[0].error, wasi::ERRNO_BADF);
+        assert_eq!(events[0].type_, wasi::EVENTTYPE_FD_READ);
+
+        Ok(())
+    }
 }
@@ -0,0 +1,142 @@
+use std::{
+    ptr::{self, addr_of, addr_of_mut},
+    rc::Rc,
+};
+
+use wasi::{Errno, Fd, Prestat, Size};
+
+use crate::core::{
+    fd,
+    fd_table::{self, Entry, Handle, HandleRef},
+    mem,
+};
+
+// Registry
+
+static mut PREOPENS: Vec<(String, HandleRef)> = Vec::new();
+
+/// Registers the `fd_prestat_*` polyfills on top of the fd table.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn install() {
+    fd_table::install();
+
+    fd::set::fd_prestat_get(fd_prestat_get);
+    fd::set::fd_prestat_dir_name(fd_prestat_dir_name);
+}
+
+/// Opens `dir` as a new descriptor advertised to the guest under `guest_path`.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn register(guest_path: &str, dir: impl Handle) -> Fd {
+    let entry = Entry::new(dir);
+    (*addr_of_mut!(PREOPENS)).push((guest_path.to_string(), entry.handle.clone()));
+
+    fd_table::insert(entry)
+}
+
+// Preopens are matched by handle rather than by number, so they follow the descriptor through
+// renumbering and vanish once it is closed. Anything else is BADF, which is what ends the
+// guest's scan over descriptors
+unsafe fn lookup(fd: Fd) -> Result<&'static str, Errno> {
+    let h = fd_table::get(fd).map_err(|_| wasi::ERRNO_BADF)?;
+
+    (*addr_of!(PREOPENS))
+        .iter()
+        .find(|(_, p)| Rc::ptr_eq(p, &h))
+        .map(|(path, _)| path.as_str())
+        .ok_or(wasi::ERRNO_BADF)
+}
+
+// Polyfills
+
+fn fd_prestat_get(fd: Fd, rp0: *mut Prestat) -> Errno {
+    mem::errno(unsafe { lookup(fd) }.map(|path| {
+        let stat = Prestat {
+            tag: wasi::PREOPENTYPE_DIR.raw(),
+            u: wasi::PrestatU {
+                dir: wasi::PrestatDir {
+                    pr_name_len: path.len(),
+                },
+            },
+        };
+
+        unsafe { *rp0 = stat };
+    }))
+}
+
+fn fd_prestat_dir_name(fd: Fd, path: *mut u8, path_len: Size) -> Errno {
+    mem::errno(unsafe { lookup(fd) }.and_then(|name| {
+        if path_len < name.len() {
+            return Err(wasi::ERRNO_NAMETOOLONG);
+        }
+
+        unsafe { ptr::copy_nonoverlapping(name.as_ptr(), path, name.len()) };
+        Ok(())
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{memfs::MemFs, testing};
+
+    extern "C" {
+        fn __shim_fd_close(fd: Fd) -> Errno;
+        fn __shim_fd_prestat_get(fd: Fd, rp0: *mut Prestat) -> Errno;
+        fn __shim_fd_prestat_dir_name(fd: Fd, path: *mut u8, path_len: Size) -> Errno;
+        fn __shim_fd_renumber(fd: Fd, to: Fd) -> Errno;
+    }
+
+    // What a guest sees scanning descriptors from 3 until BADF
+    unsafe fn scan() -> Vec<(Fd, String)> {
+        let mut found = vec![];
+        for fd in 3.. {
+            let mut stat = Prestat {
+                tag: 0,
+                u: wasi::PrestatU {
+                    dir: wasi::PrestatDir { pr_name_len: 0 },
+                },
+            };
+            match __shim_fd_prestat_get(fd, &mut stat) {
+                wasi::ERRNO_SUCCESS => {}
+                err => {
+                    assert_eq!(err, wasi::ERRNO_BADF);
+                    return found;
+                }
+            }
+
+            let mut name = vec![0; stat.u.dir.pr_name_len];
+            let errno = __shim_fd_prestat_dir_name(fd, name.as_mut_ptr(), name.len());
+            assert_eq!(errno, wasi::ERRNO_SUCCESS);
+            found.push((fd, String::from_utf8(name).unwrap()));
+        }
+
+        found
+    }
+
+    #[test]
+    fn test_prestat() {
+        let _lock = testing::LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+        unsafe {
+            fd_table::clear();
+            (*addr_of_mut!(PREOPENS)).clear();
+            install();
+
+            register("/", MemFs::new().root());
+            register("/data", MemFs::new().root());
+            assert_eq!(scan(), [(3, "/".to_string()), (4, "/data".to_string())]);
+
+            let mut name = [0; 4];
+            assert_eq!(
+                __shim_fd_prestat_dir_name(4, name.as_mut_ptr(), name.len()),
+                wasi::ERRNO_NAMETOOLONG
+            );
+
+            // Preopens follow their descriptor
+            assert_eq!(__shim_fd_renumber(4, 3), wasi::ERRNO_SUCCESS);
+            assert_eq!(scan(), [(3, "/data".to_string())]);
+            assert_eq!(__shim_fd_close(3), wasi::ERRNO_SUCCESS);
+            assert_eq!(scan(), []);
+        }
+    }
+}
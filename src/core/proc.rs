@@ -1,3 +1,5 @@
+use std::ptr::addr_of_mut;
+
 use wasi::{Errno, Exitcode, Signal};
 
 // Types
@@ -31,24 +33,89 @@ pub mod set {
     }
 }
 
+// Backends
+
+pub type ExitHookFn = fn(code: Exitcode);
+
+/// Exit code passed to `proc_exit`, or -1 while the guest has not exited. Exported so the host
+/// can tell a `proc_exit` trap apart from any other trap and pick up the code.
+#[no_mangle]
+pub static mut WASI_SHIM_EXIT_CODE: i64 = -1;
+
+static mut EXIT_HOOK: Option<ExitHookFn> = None;
+
+/// Serves `proc_exit` by recording the exit code, handing it to `hook` if given, and trapping
+/// with a `proc_exit(<code>)` panic. At-exit handlers have already run by then.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn install(hook: Option<ExitHookFn>) {
+    EXIT_HOOK = hook;
+
+    set::proc_exit(exit);
+}
+
+pub fn exit_code() -> Option<Exitcode> {
+    match unsafe { WASI_SHIM_EXIT_CODE } {
+        -1 => None,
+        code => Some(code as Exitcode),
+    }
+}
+
+fn exit(rval: Exitcode) -> ! {
+    unsafe {
+        WASI_SHIM_EXIT_CODE = rval as i64;
+
+        if let Some(f) = EXIT_HOOK {
+            f(rval);
+        }
+    }
+
+    panic!("proc_exit({rval})")
+}
+
+// Exit handlers
+
+static mut AT_EXIT: Vec<fn()> = Vec::new();
+
+/// Registers `f` to run when the guest calls `proc_exit`, before the polyfill does. Handlers run
+/// in reverse order of registration.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn at_exit(f: fn()) {
+    (*addr_of_mut!(AT_EXIT)).push(f);
+}
+
+// Handlers are taken out first, so a handler that ends up exiting again does not loop
+unsafe fn run_at_exit() {
+    let hs = std::mem::take(&mut *addr_of_mut!(AT_EXIT));
+    for f in hs.into_iter().rev() {
+        f();
+    }
+}
+
+// Helpers
+
+// Ends the guest through whatever `proc_exit` polyfill is registered, as the guest itself would
+pub(crate) fn terminate(rval: Exitcode) -> ! {
+    unsafe { shims::__shim_proc_exit(rval) }
+}
+
 // Shims
 
 pub mod shims {
     use super::*;
+    use crate::core::layer::{self, shims, Call};
+
+    shims! {
+        __shim_proc_raise => ProcRaise { sig: Signal } => raise;
+    }
 
     #[no_mangle]
-    unsafe extern "C" fn __shim_proc_exit(rval: Exitcode) -> ! {
+    pub(crate) unsafe extern "C" fn __shim_proc_exit(rval: Exitcode) -> ! {
+        layer::enter(Call::ProcExit { rval });
+        run_at_exit();
+
         match POLYFILLS.exit {
             Some(f) => f(rval),
             None => unimplemented!("proc_exit"),
         }
     }
-
-    #[no_mangle]
-    unsafe extern "C" fn __shim_proc_raise(sig: Signal) -> Errno {
-        match POLYFILLS.raise {
-            Some(f) => f(sig),
-            None => unimplemented!("proc_raise"),
-        }
-    }
 }
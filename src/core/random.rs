@@ -1,8 +1,13 @@
+use std::ptr::addr_of_mut;
+
 use wasi::{Errno, Size};
 
+use crate::core::mem;
+
 // Types
 
 pub type RandomGetFn = fn(buf: *mut u8, buf_len: Size) -> Errno;
+pub type EntropyFn = fn(buf: &mut [u8]);
 
 // Polyfills
 
@@ -21,16 +26,228 @@ pub mod set {
     }
 }
 
+// Backends
+
+pub const STATE_LEN: usize = 48;
+
+const BLOCK_LEN: usize = 64;
+
+// ChaCha20 keystream with a 64-bit block counter and a zero nonce
+struct ChaCha {
+    key: [u32; 8],
+    counter: u64,
+    block: [u8; BLOCK_LEN],
+    // Bytes of `block` already handed out
+    offset: usize,
+}
+
+impl ChaCha {
+    fn new(seed: [u8; 32]) -> Self {
+        let mut key = [0; 8];
+        for (k, b) in key.iter_mut().zip(seed.chunks_exact(4)) {
+            *k = u32::from_le_bytes(b.try_into().unwrap());
+        }
+
+        Self {
+            key,
+            counter: 0,
+            block: [0; BLOCK_LEN],
+            offset: BLOCK_LEN,
+        }
+    }
+
+    fn block(&self, counter: u64) -> [u8; BLOCK_LEN] {
+        let mut init = [0u32; 16];
+        init[..4].copy_from_slice(&[0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]);
+        init[4..12].copy_from_slice(&self.key);
+        init[12] = counter as u32;
+        init[13] = (counter >> 32) as u32;
+
+        let mut x = init;
+        for _ in 0..10 {
+            quarter_round(&mut x, 0, 4, 8, 12);
+            quarter_round(&mut x, 1, 5, 9, 13);
+            quarter_round(&mut x, 2, 6, 10, 14);
+            quarter_round(&mut x, 3, 7, 11, 15);
+            quarter_round(&mut x, 0, 5, 10, 15);
+            quarter_round(&mut x, 1, 6, 11, 12);
+            quarter_round(&mut x, 2, 7, 8, 13);
+            quarter_round(&mut x, 3, 4, 9, 14);
+        }
+
+        let mut out = [0; BLOCK_LEN];
+        for (i, b) in out.chunks_exact_mut(4).enumerate() {
+            b.copy_from_slice(&x[i].wrapping_add(init[i]).to_le_bytes());
+        }
+
+        out
+    }
+
+    fn fill(&mut self, buf: &mut [u8]) {
+        let mut n = 0;
+        while n < buf.len() {
+            if self.offset == BLOCK_LEN {
+                self.block = self.block(self.counter);
+                self.counter = self.counter.wrapping_add(1);
+                self.offset = 0;
+            }
+
+            let m = (buf.len() - n).min(BLOCK_LEN - self.offset);
+            buf[n..n + m].copy_from_slice(&self.block[self.offset..self.offset + m]);
+            self.offset += m;
+            n += m;
+        }
+    }
+
+    // The new key is drawn from the current stream with the seed mixed in, so neither the old
+    // state nor the seed alone determines it
+    fn reseed(&mut self, seed: [u8; 32]) {
+        let mut next = [0; 32];
+        self.fill(&mut next);
+        for (n, s) in next.iter_mut().zip(seed) {
+            *n ^= s;
+        }
+
+        *self = Self::new(next);
+    }
+
+    fn export(&self) -> [u8; STATE_LEN] {
+        let mut out = [0; STATE_LEN];
+        for (b, k) in out.chunks_exact_mut(4).zip(self.key) {
+            b.copy_from_slice(&k.to_le_bytes());
+        }
+        out[32..40].copy_from_slice(&self.counter.to_le_bytes());
+        out[40..48].copy_from_slice(&(self.offset as u64).to_le_bytes());
+
+        out
+    }
+
+    fn import(state: &[u8; STATE_LEN]) -> Result<Self, Errno> {
+        let mut c = Self::new(state[..32].try_into().unwrap());
+        c.counter = u64::from_le_bytes(state[32..40].try_into().unwrap());
+        c.offset = u64::from_le_bytes(state[40..48].try_into().unwrap()) as usize;
+
+        match c.offset {
+            BLOCK_LEN => {}
+            _ if c.offset > BLOCK_LEN || c.counter == 0 => return Err(wasi::ERRNO_INVAL),
+            _ => c.block = c.block(c.counter - 1),
+        }
+
+        Ok(c)
+    }
+}
+
+fn quarter_round(x: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = (x[d] ^ x[a]).rotate_left(16);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = (x[b] ^ x[c]).rotate_left(12);
+    x[a] = x[a].wrapping_add(x[b]);
+    x[d] = (x[d] ^ x[a]).rotate_left(8);
+    x[c] = x[c].wrapping_add(x[d]);
+    x[b] = (x[b] ^ x[c]).rotate_left(7);
+}
+
+static mut RNG: Option<ChaCha> = None;
+
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn set_seed(seed: [u8; 32]) {
+    install(ChaCha::new(seed));
+}
+
+/// Seeds the generator once from `entropy`; every byte after that is derived from the seed.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn set_entropy(entropy: EntropyFn) {
+    let mut seed = [0; 32];
+    entropy(&mut seed);
+
+    set_seed(seed);
+}
+
+/// Mixes `seed` into the generator, or seeds it if it is not set yet.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn reseed(seed: [u8; 32]) {
+    match &mut *addr_of_mut!(RNG) {
+        Some(rng) => rng.reseed(seed),
+        None => set_seed(seed),
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn export_state() -> Option<[u8; STATE_LEN]> {
+    (*addr_of_mut!(RNG)).as_ref().map(|rng| rng.export())
+}
+
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn import_state(state: &[u8; STATE_LEN]) -> Result<(), Errno> {
+    install(ChaCha::import(state)?);
+    Ok(())
+}
+
+unsafe fn install(rng: ChaCha) {
+    RNG = Some(rng);
+
+    set::random_get(chacha_get);
+}
+
+fn chacha_get(buf: *mut u8, buf_len: Size) -> Errno {
+    let rng = unsafe { (*addr_of_mut!(RNG)).as_mut().unwrap() };
+    rng.fill(unsafe { mem::bytes_mut(buf, buf_len) });
+
+    wasi::ERRNO_SUCCESS
+}
+
 // Shims
 
 pub mod shims {
     use super::*;
+    use crate::core::layer::shims;
 
-    #[no_mangle]
-    unsafe extern "C" fn __shim_random_get(buf: *mut u8, buf_len: Size) -> Errno {
-        match POLYFILLS.get {
-            Some(f) => f(buf, buf_len),
-            None => unimplemented!("random_get"),
-        }
+    shims! {
+        __shim_random_get => RandomGet { buf: *mut u8, buf_len: Size } => get;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chacha_keystream() {
+        // RFC 8439, appendix A.1, test vector #1
+        let mut rng = ChaCha::new([0; 32]);
+
+        let mut buf = [0; 16];
+        rng.fill(&mut buf);
+        assert_eq!(
+            buf,
+            [
+                0x76, 0xb8, 0xe0, 0xad, 0xa0, 0xf1, 0x3d, 0x90, 0x40, 0x5d, 0x6a, 0xe5, 0x53, 0x86,
+                0xbd, 0x28
+            ]
+        );
+    }
+
+    #[test]
+    fn test_export_import() -> Result<(), Errno> {
+        let mut a = ChaCha::new([7; 32]);
+
+        let mut skip = [0; 100];
+        a.fill(&mut skip);
+
+        let mut b = ChaCha::import(&a.export())?;
+
+        let (mut x, mut y) = ([0; 80], [0; 80]);
+        a.fill(&mut x);
+        b.fill(&mut y);
+        assert_eq!(x, y);
+
+        a.reseed([1; 32]);
+        b.reseed([2; 32]);
+        a.fill(&mut x);
+        b.fill(&mut y);
+        assert_ne!(x, y);
+
+        Ok(())
     }
 }
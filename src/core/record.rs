@@ -0,0 +1,570 @@
+use std::{mem::size_of, ptr::addr_of};
+
+use wasi::{Errno, Event, Iovec, Size};
+
+use crate::core::{
+    fd,
+    layer::{self, Call, Layer},
+    mem, trace,
+};
+
+// Trace format
+//
+// A header, then one entry per call in the order the calls were made, including calls that
+// polyfills make through the shims while serving another. Each entry is the call as `trace`
+// prints it, its nesting depth, the errno plus one or 0 for a call that never returned, and,
+// for successful calls, everything the polyfill wrote to guest memory. Numbers are LEB128
+// varints and byte strings are prefixed with their length.
+
+const MAGIC: &[u8; 5] = b"WSRT\x02";
+
+struct Entry {
+    call: String,
+    depth: usize,
+    // None until the call returns
+    errno: Option<Errno>,
+    outputs: Vec<u8>,
+}
+
+impl Entry {
+    fn encode(&self, out: &mut Vec<u8>) {
+        put_bytes(out, self.call.as_bytes());
+        put_varint(out, self.depth as u64);
+        put_varint(out, self.errno.map_or(0, |e| e.raw() as u64 + 1));
+        put_bytes(out, &self.outputs);
+    }
+
+    fn decode(data: &mut &[u8]) -> Option<Self> {
+        Some(Self {
+            call: String::from_utf8(take_bytes(data)?.to_vec()).ok()?,
+            depth: usize::try_from(take_varint(data)?).ok()?,
+            errno: match take_varint(data)? {
+                0 => None,
+                v => Some(errno(v - 1)?),
+            },
+            outputs: take_bytes(data)?.to_vec(),
+        })
+    }
+}
+
+fn errno(v: u64) -> Option<Errno> {
+    match u16::try_from(v) {
+        // Errno is a transparent u16, and every value up to the last one is defined
+        Ok(v) if v <= wasi::ERRNO_NOTCAPABLE.raw() => {
+            Some(unsafe { std::mem::transmute::<u16, Errno>(v) })
+        }
+        _ => None,
+    }
+}
+
+fn put_varint(out: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        out.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    out.push(v as u8);
+}
+
+fn put_bytes(out: &mut Vec<u8>, b: &[u8]) {
+    put_varint(out, b.len() as u64);
+    out.extend_from_slice(b);
+}
+
+fn take_varint(data: &mut &[u8]) -> Option<u64> {
+    let mut v = 0;
+    for shift in (0..64).step_by(7) {
+        let (b, rest) = data.split_first()?;
+        *data = rest;
+        v |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Some(v);
+        }
+    }
+    None
+}
+
+fn take_bytes<'a>(data: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len = usize::try_from(take_varint(data)?).ok()?;
+    if len > data.len() {
+        return None;
+    }
+
+    let (b, rest) = data.split_at(len);
+    *data = rest;
+    Some(b)
+}
+
+// Guest memory
+
+// A run of bytes, or a list of pointers into a buffer kept as offsets so a replay can run at
+// different addresses
+enum Region {
+    Bytes(*mut u8, usize),
+    Ptrs(*mut *mut u8, usize, *mut u8),
+}
+
+fn value<T>(p: *mut T) -> Region {
+    Region::Bytes(p as *mut u8, size_of::<T>())
+}
+
+// Sizes of the last successful `args_sizes_get` and `environ_sizes_get`, which say how much
+// the following gets write
+#[derive(Default)]
+struct Sizes {
+    args: (Size, Size),
+    environ: (Size, Size),
+}
+
+impl Sizes {
+    // Fixed-size results
+    fn results(call: &Call) -> Vec<Region> {
+        match *call {
+            Call::ArgsSizesGet { rp0, rp1 } | Call::EnvironSizesGet { rp0, rp1 } => {
+                vec![value(rp0), value(rp1)]
+            }
+            Call::ClockResGet { rp0, .. } | Call::ClockTimeGet { rp0, .. } => vec![value(rp0)],
+            Call::FdFdstatGet { rp0, .. } => vec![value(rp0)],
+            Call::FdFilestatGet { rp0, .. } | Call::PathFilestatGet { rp0, .. } => {
+                vec![value(rp0)]
+            }
+            Call::FdPrestatGet { rp0, .. } => vec![value(rp0)],
+            Call::FdPread { rp0, .. }
+            | Call::FdPwrite { rp0, .. }
+            | Call::FdRead { rp0, .. }
+            | Call::FdReaddir { rp0, .. }
+            | Call::FdWrite { rp0, .. }
+            | Call::PathReadlink { rp0, .. }
+            | Call::PollOneoff { rp0, .. }
+            | Call::SockSend { rp0, .. } => vec![value(rp0)],
+            Call::FdSeek { rp0, .. } | Call::FdTell { rp0, .. } => vec![value(rp0)],
+            Call::PathOpen { rp0, .. } | Call::SockAccept { rp0, .. } => vec![value(rp0)],
+            Call::SockRecv { rp0, rp1, .. } => vec![value(rp0), value(rp1)],
+            _ => vec![],
+        }
+    }
+
+    // Buffers, sized by the results already in place
+    unsafe fn buffers(&self, call: &Call) -> Vec<Region> {
+        match *call {
+            Call::ArgsGet { argv, argv_buf } => vec![
+                Region::Ptrs(argv, self.args.0, argv_buf),
+                Region::Bytes(argv_buf, self.args.1),
+            ],
+            Call::EnvironGet {
+                environ,
+                environ_buf,
+            } => vec![
+                Region::Ptrs(environ, self.environ.0, environ_buf),
+                Region::Bytes(environ_buf, self.environ.1),
+            ],
+            Call::FdPrestatDirName { path, path_len, .. } => vec![Region::Bytes(path, path_len)],
+            Call::FdPread { iovs, len, rp0, .. } => scatter(iovs, len, *rp0),
+            Call::FdRead {
+                iovs,
+                iovs_len,
+                rp0,
+                ..
+            } => scatter(iovs, iovs_len, *rp0),
+            Call::SockRecv {
+                ri_data,
+                ri_data_len,
+                rp0,
+                ..
+            } => scatter(ri_data, ri_data_len, *rp0),
+            Call::FdReaddir {
+                buf, buf_len, rp0, ..
+            }
+            | Call::PathReadlink {
+                buf, buf_len, rp0, ..
+            } => vec![Region::Bytes(buf, (*rp0).min(buf_len))],
+            Call::PollOneoff {
+                out,
+                nsubscriptions,
+                rp0,
+                ..
+            } => vec![Region::Bytes(
+                out as *mut u8,
+                (*rp0).min(nsubscriptions) * size_of::<Event>(),
+            )],
+            Call::RandomGet { buf, buf_len } => vec![Region::Bytes(buf, buf_len)],
+            _ => vec![],
+        }
+    }
+
+    unsafe fn note(&mut self, call: &Call) {
+        match *call {
+            Call::ArgsSizesGet { rp0, rp1 } => self.args = (*rp0, *rp1),
+            Call::EnvironSizesGet { rp0, rp1 } => self.environ = (*rp0, *rp1),
+            _ => {}
+        }
+    }
+
+    unsafe fn save(&mut self, call: &Call, out: &mut Vec<u8>) {
+        for r in Self::results(call) {
+            save(&r, out);
+        }
+        for r in self.buffers(call) {
+            save(&r, out);
+        }
+
+        self.note(call);
+    }
+
+    unsafe fn load(&mut self, call: &Call, mut data: &[u8]) -> Option<()> {
+        for r in Self::results(call) {
+            load(&r, &mut data)?;
+        }
+        for r in self.buffers(call) {
+            load(&r, &mut data)?;
+        }
+
+        self.note(call);
+        Some(())
+    }
+}
+
+unsafe fn scatter(iovs: *const Iovec, iovs_len: i32, mut n: Size) -> Vec<Region> {
+    let mut regions = vec![];
+
+    for iov in mem::iovecs(iovs, iovs_len) {
+        let len = n.min(iov.buf_len);
+        regions.push(Region::Bytes(iov.buf, len));
+        n -= len;
+    }
+
+    regions
+}
+
+unsafe fn save(r: &Region, out: &mut Vec<u8>) {
+    match *r {
+        Region::Bytes(p, len) => out.extend_from_slice(mem::bytes(p, len)),
+        Region::Ptrs(ptrs, n, base) => {
+            for i in 0..n {
+                put_varint(out, (*ptrs.add(i)).offset_from(base) as u64);
+            }
+        }
+    }
+}
+
+unsafe fn load(r: &Region, data: &mut &[u8]) -> Option<()> {
+    match *r {
+        Region::Bytes(p, len) => {
+            if len > data.len() {
+                return None;
+            }
+
+            let (b, rest) = data.split_at(len);
+            mem::bytes_mut(p, len).copy_from_slice(b);
+            *data = rest;
+        }
+        Region::Ptrs(ptrs, n, base) => {
+            for i in 0..n {
+                *ptrs.add(i) = base.add(take_varint(data)? as usize);
+            }
+        }
+    }
+
+    Some(())
+}
+
+// Recording
+
+// Entries are held back while a call is in progress, since the calls nested in it come after
+// it in the trace but complete before it
+struct Recorder<F: FnMut(&[u8])> {
+    sink: F,
+    sizes: Sizes,
+    pending: Vec<Entry>,
+    // Positions in `pending` of the calls in progress
+    open: Vec<usize>,
+}
+
+impl<F: FnMut(&[u8])> Recorder<F> {
+    fn new(sink: F) -> Self {
+        Self {
+            sink,
+            sizes: Sizes::default(),
+            pending: vec![],
+            open: vec![],
+        }
+    }
+
+    fn flush(&mut self) {
+        let mut out = vec![];
+        for entry in self.pending.drain(..) {
+            entry.encode(&mut out);
+        }
+
+        (self.sink)(&out);
+    }
+}
+
+impl<F: FnMut(&[u8])> Layer for Recorder<F> {
+    fn enter(&mut self, call: &Call) -> Option<Errno> {
+        self.pending.push(Entry {
+            call: trace::describe(call),
+            depth: self.open.len(),
+            errno: None,
+            outputs: vec![],
+        });
+
+        // The process ends here, along with any call still in progress
+        match call {
+            Call::ProcExit { .. } => self.flush(),
+            _ => self.open.push(self.pending.len() - 1),
+        }
+
+        None
+    }
+
+    fn exit(&mut self, call: &Call, ret: Errno) {
+        let Some(i) = self.open.pop() else {
+            return;
+        };
+
+        let mut outputs = vec![];
+        if ret == wasi::ERRNO_SUCCESS {
+            unsafe { self.sizes.save(call, &mut outputs) };
+        }
+        self.pending[i].errno = Some(ret);
+        self.pending[i].outputs = outputs;
+
+        if self.open.is_empty() {
+            self.flush();
+        }
+    }
+}
+
+/// Records every shim call to `sink`, which first gets the trace header and then one chunk per
+/// call. Push it after any other layers so it sees what the polyfills return.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn record(mut sink: impl FnMut(&[u8]) + 'static) {
+    sink(MAGIC);
+    layer::push(Recorder::new(sink));
+}
+
+// Replay
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Divergence {
+    // Position of the call in the trace
+    pub index: usize,
+    // None when the trace ran out
+    pub expected: Option<String>,
+    pub actual: String,
+}
+
+static mut DIVERGENCE: Option<Divergence> = None;
+
+struct Replayer {
+    trace: Vec<u8>,
+    pos: usize,
+    index: usize,
+    sizes: Sizes,
+    // Calls in progress that were passed through to the polyfills
+    depth: usize,
+    diverged: Option<Divergence>,
+}
+
+impl Replayer {
+    fn new(trace: Vec<u8>) -> Result<Self, Errno> {
+        if !trace.starts_with(MAGIC) {
+            return Err(wasi::ERRNO_ILSEQ);
+        }
+
+        Ok(Self {
+            trace,
+            pos: MAGIC.len(),
+            index: 0,
+            sizes: Sizes::default(),
+            depth: 0,
+            diverged: None,
+        })
+    }
+
+    fn next(&mut self) -> Option<Entry> {
+        let mut data = &self.trace[self.pos..];
+        let entry = Entry::decode(&mut data)?;
+        self.pos = self.trace.len() - data.len();
+
+        Some(entry)
+    }
+
+    // Drops the entries of calls nested in one at `depth` that is served from the trace, as
+    // they won't be made this time
+    fn skip_nested(&mut self, depth: usize) {
+        loop {
+            let mut data = &self.trace[self.pos..];
+            match Entry::decode(&mut data) {
+                Some(e) if e.depth > depth => self.pos = self.trace.len() - data.len(),
+                _ => return,
+            }
+        }
+    }
+
+    fn diverge(&mut self, expected: Option<String>, actual: String) {
+        let d = Divergence {
+            index: self.index,
+            expected,
+            actual,
+        };
+
+        let msg = format!(
+            "replay diverged at call {}: expected {}, got {}\n",
+            d.index,
+            d.expected.as_deref().unwrap_or("end of trace"),
+            d.actual
+        );
+        let _ = fd::write(2, msg.as_bytes());
+
+        unsafe { DIVERGENCE = Some(d.clone()) };
+        self.diverged = Some(d);
+    }
+}
+
+impl Layer for Replayer {
+    // Once diverged, calls go through to the polyfills. So do calls that never returned when
+    // recorded, such as `proc_exit`, so that the calls nested in them are made again
+    fn enter(&mut self, call: &Call) -> Option<Errno> {
+        if self.diverged.is_some() {
+            return None;
+        }
+
+        let actual = trace::describe(call);
+        let entry = match self.next() {
+            Some(e) if e.call == actual && e.depth == self.depth => e,
+            other => {
+                self.diverge(other.map(|e| e.call), actual);
+                return None;
+            }
+        };
+        self.index += 1;
+
+        let Some(errno) = entry.errno else {
+            self.depth += 1;
+            return None;
+        };
+        self.skip_nested(entry.depth);
+
+        if errno == wasi::ERRNO_SUCCESS
+            && unsafe { self.sizes.load(call, &entry.outputs) }.is_none()
+        {
+            self.diverge(Some(entry.call), actual);
+            return None;
+        }
+
+        Some(errno)
+    }
+
+    fn exit(&mut self, _call: &Call, _ret: Errno) {
+        if self.diverged.is_none() {
+            self.depth = self.depth.saturating_sub(1);
+        }
+    }
+}
+
+/// Serves shim calls from a trace made by [`record`] instead of the polyfills, until a call
+/// does not match the trace.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn replay(trace: Vec<u8>) -> Result<(), Errno> {
+    layer::push(Replayer::new(trace)?);
+    Ok(())
+}
+
+/// The first call that did not match the trace being replayed, if any.
+pub fn divergence() -> Option<Divergence> {
+    unsafe { (*addr_of!(DIVERGENCE)).clone() }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+
+    #[test]
+    fn test_varint() {
+        let mut out = vec![];
+        put_varint(&mut out, 300);
+        assert_eq!(out, [0xac, 0x02]);
+        assert_eq!(take_varint(&mut &out[..]), Some(300));
+        assert_eq!(take_varint(&mut &[0x80][..]), None);
+    }
+
+    #[test]
+    fn test_record_replay() {
+        let trace = Rc::new(RefCell::new(vec![]));
+        let sink = trace.clone();
+        let mut rec = Recorder::new(move |b: &[u8]| sink.borrow_mut().extend_from_slice(b));
+        trace.borrow_mut().extend_from_slice(MAGIC);
+
+        let read = |iov: &Iovec, n: &mut Size| Call::FdRead {
+            fd: 3,
+            iovs: iov,
+            iovs_len: 1,
+            rp0: n,
+        };
+        let iovec = |buf: &mut [u8; 4]| Iovec {
+            buf: buf.as_mut_ptr(),
+            buf_len: 4,
+        };
+
+        let (mut buf, mut n) = (*b"abcd", 3);
+        let iov = iovec(&mut buf);
+        let call = read(&iov, &mut n);
+        rec.enter(&call);
+        rec.exit(&call, wasi::ERRNO_SUCCESS);
+        let call = Call::FdClose { fd: 3 };
+        rec.enter(&call);
+        rec.exit(&call, wasi::ERRNO_BADF);
+
+        let mut rep = Replayer::new(trace.take()).unwrap();
+        let (mut buf, mut n) = ([0; 4], 0);
+        let iov = iovec(&mut buf);
+        assert_eq!(rep.enter(&read(&iov, &mut n)), Some(wasi::ERRNO_SUCCESS));
+        assert_eq!((&buf, n), (b"abc\0", 3));
+
+        assert_eq!(rep.enter(&Call::FdClose { fd: 4 }), None);
+        assert_eq!(
+            rep.diverged,
+            Some(Divergence {
+                index: 1,
+                expected: Some("fd_close(3)".to_string()),
+                actual: "fd_close(4)".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_nested_calls() {
+        let trace = Rc::new(RefCell::new(vec![]));
+        let sink = trace.clone();
+        let mut rec = Recorder::new(move |b: &[u8]| sink.borrow_mut().extend_from_slice(b));
+        trace.borrow_mut().extend_from_slice(MAGIC);
+
+        // fd_close(3) makes sched_yield() while in progress, then a proc_raise(SIGTERM) ends
+        // the process through proc_exit
+        let close = || Call::FdClose { fd: 3 };
+        let sched = || Call::SchedYield;
+        let raise = || Call::ProcRaise {
+            sig: wasi::SIGNAL_TERM,
+        };
+        let exit = || Call::ProcExit { rval: 143 };
+
+        rec.enter(&close());
+        rec.enter(&sched());
+        rec.exit(&sched(), wasi::ERRNO_SUCCESS);
+        rec.exit(&close(), wasi::ERRNO_SUCCESS);
+        rec.enter(&raise());
+        rec.enter(&exit());
+
+        // The outer call is served, so its nested call is not expected. The call that never
+        // returned goes through, and so does the exit nested in it
+        let mut rep = Replayer::new(trace.take()).unwrap();
+        assert_eq!(rep.enter(&close()), Some(wasi::ERRNO_SUCCESS));
+        assert_eq!(rep.enter(&raise()), None);
+        assert_eq!(rep.enter(&exit()), None);
+        assert_eq!(rep.diverged, None);
+        assert_eq!(rep.index, 3);
+    }
+}
@@ -25,12 +25,9 @@ pub mod set {
 
 pub mod shims {
     use super::*;
+    use crate::core::layer::shims;
 
-    #[no_mangle]
-    unsafe extern "C" fn __shim_sched_yield() -> Errno {
-        match POLYFILLS.sched_yield {
-            Some(f) => f(),
-            None => unimplemented!("sched_yield"),
-        }
+    shims! {
+        __shim_sched_yield => SchedYield {} => sched_yield;
     }
 }
@@ -0,0 +1,128 @@
+use std::{
+    collections::BTreeMap,
+    ptr::{addr_of, addr_of_mut},
+};
+
+use wasi::{Errno, Signal};
+
+use crate::core::{fd, proc};
+
+// Types
+
+pub type HandlerFn = fn(sig: Signal);
+
+#[derive(Clone, Copy)]
+pub enum Action {
+    Default,
+    Ignore,
+    Handler(HandlerFn),
+}
+
+#[derive(Debug, PartialEq)]
+enum DefaultAction {
+    Terminate,
+    Ignore,
+}
+
+// Stopping and continuing have no meaning for a single guest, so those signals are ignored
+fn default_action(sig: Signal) -> DefaultAction {
+    match sig {
+        wasi::SIGNAL_CHLD
+        | wasi::SIGNAL_CONT
+        | wasi::SIGNAL_STOP
+        | wasi::SIGNAL_TSTP
+        | wasi::SIGNAL_TTIN
+        | wasi::SIGNAL_TTOU
+        | wasi::SIGNAL_URG
+        | wasi::SIGNAL_WINCH => DefaultAction::Ignore,
+        _ => DefaultAction::Terminate,
+    }
+}
+
+// Registry
+
+static mut ACTIONS: BTreeMap<Signal, Action> = BTreeMap::new();
+
+/// Serves `proc_raise` from the actions set with [`set_action`].
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn install() {
+    proc::set::proc_raise(raise);
+}
+
+/// Sets the action for `sig`, returning the previous one. `KILL` and `STOP` cannot be caught or
+/// ignored, as in POSIX.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn set_action(sig: Signal, action: Action) -> Result<Action, Errno> {
+    if sig.raw() == 0 || sig.raw() > wasi::SIGNAL_SYS.raw() {
+        return Err(wasi::ERRNO_INVAL);
+    }
+
+    if let (wasi::SIGNAL_KILL | wasi::SIGNAL_STOP, Action::Ignore | Action::Handler(_)) =
+        (sig, action)
+    {
+        return Err(wasi::ERRNO_INVAL);
+    }
+
+    Ok((*addr_of_mut!(ACTIONS))
+        .insert(sig, action)
+        .unwrap_or(Action::Default))
+}
+
+// Polyfills
+
+fn raise(sig: Signal) -> Errno {
+    match sig.raw() {
+        0 => return wasi::ERRNO_SUCCESS,
+        n if n > wasi::SIGNAL_SYS.raw() => return wasi::ERRNO_INVAL,
+        _ => {}
+    }
+
+    let action = unsafe { (*addr_of!(ACTIONS)).get(&sig).copied() };
+    match (action.unwrap_or(Action::Default), default_action(sig)) {
+        (Action::Handler(f), _) => f(sig),
+        (Action::Ignore, _) | (Action::Default, DefaultAction::Ignore) => {}
+        (Action::Default, DefaultAction::Terminate) => {
+            let msg = format!("terminated by signal SIG{}\n", sig.name());
+            let _ = fd::write(2, msg.as_bytes());
+
+            // Shells report death by signal as 128 plus the signal number
+            proc::terminate(128 + sig.raw() as u32);
+        }
+    }
+
+    wasi::ERRNO_SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_actions() {
+        assert_eq!(default_action(wasi::SIGNAL_ABRT), DefaultAction::Terminate);
+        assert_eq!(default_action(wasi::SIGNAL_TERM), DefaultAction::Terminate);
+        assert_eq!(default_action(wasi::SIGNAL_CHLD), DefaultAction::Ignore);
+        assert_eq!(default_action(wasi::SIGNAL_WINCH), DefaultAction::Ignore);
+    }
+
+    #[test]
+    fn test_raise() -> Result<(), Errno> {
+        static mut RAISED: Option<Signal> = None;
+
+        unsafe {
+            set_action(wasi::SIGNAL_USR1, Action::Handler(|sig| RAISED = Some(sig)))?;
+            assert_eq!(
+                set_action(wasi::SIGNAL_KILL, Action::Ignore).err(),
+                Some(wasi::ERRNO_INVAL)
+            );
+        }
+
+        assert_eq!(raise(wasi::SIGNAL_USR1), wasi::ERRNO_SUCCESS);
+        assert_eq!(unsafe { RAISED }, Some(wasi::SIGNAL_USR1));
+
+        assert_eq!(raise(wasi::SIGNAL_CHLD), wasi::ERRNO_SUCCESS);
+        assert_eq!(raise(wasi::SIGNAL_NONE), wasi::ERRNO_SUCCESS);
+
+        Ok(())
+    }
+}
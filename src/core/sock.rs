@@ -67,49 +67,25 @@ pub mod set {
 
 pub mod shims {
     use super::*;
-
-    #[no_mangle]
-    unsafe extern "C" fn __shim_sock_accept(fd: Fd, flags: Fdflags, rp0: *mut Fd) -> Errno {
-        match POLYFILLS.accept {
-            Some(f) => f(fd, flags, rp0),
-            None => unimplemented!("sock_accept"),
-        }
-    }
-
-    #[no_mangle]
-    unsafe extern "C" fn __shim_sock_recv(
-        fd: Fd,
-        ri_data: *const Iovec,
-        ri_data_len: i32,
-        ri_flags: Riflags,
-        rp0: *mut Size,
-        rp1: *mut Roflags,
-    ) -> Errno {
-        match POLYFILLS.recv {
-            Some(f) => f(fd, ri_data, ri_data_len, ri_flags, rp0, rp1),
-            None => unimplemented!("sock_recv"),
-        }
-    }
-
-    #[no_mangle]
-    unsafe extern "C" fn __shim_sock_send(
-        fd: Fd,
-        si_data: *const Ciovec,
-        si_data_len: i32,
-        si_flags: Siflags,
-        rp0: *mut Size,
-    ) -> Errno {
-        match POLYFILLS.send {
-            Some(f) => f(fd, si_data, si_data_len, si_flags, rp0),
-            None => unimplemented!("sock_send"),
-        }
-    }
-
-    #[no_mangle]
-    unsafe extern "C" fn __shim_sock_shutdown(fd: Fd, how: Sdflags) -> Errno {
-        match POLYFILLS.shutdown {
-            Some(f) => f(fd, how),
-            None => unimplemented!("sock_shutdown"),
-        }
+    use crate::core::layer::shims;
+
+    shims! {
+        __shim_sock_accept => SockAccept { fd: Fd, flags: Fdflags, rp0: *mut Fd } => accept;
+        __shim_sock_recv => SockRecv {
+            fd: Fd,
+            ri_data: *const Iovec,
+            ri_data_len: i32,
+            ri_flags: Riflags,
+            rp0: *mut Size,
+            rp1: *mut Roflags,
+        } => recv;
+        __shim_sock_send => SockSend {
+            fd: Fd,
+            si_data: *const Ciovec,
+            si_data_len: i32,
+            si_flags: Siflags,
+            rp0: *mut Size,
+        } => send;
+        __shim_sock_shutdown => SockShutdown { fd: Fd, how: Sdflags } => shutdown;
     }
 }
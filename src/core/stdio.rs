@@ -0,0 +1,308 @@
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io::Read,
+    ptr::{addr_of, addr_of_mut},
+    rc::Rc,
+};
+
+use wasi::{Errno, Fd, Filetype, Rights, Size};
+
+use crate::core::{
+    fd,
+    fd_table::{self, Entry, Handle},
+    proc,
+};
+
+// Types
+
+pub type HostPrintFn = unsafe extern "C" fn(ptr: *const u8, len: usize);
+pub type CallbackFn = Box<dyn FnMut(&[u8])>;
+
+pub enum Sink {
+    Callback(CallbackFn),
+    Ring(RingBuffer),
+    Host(HostPrintFn),
+}
+
+impl Sink {
+    fn emit(&mut self, buf: &[u8]) {
+        match self {
+            Sink::Callback(f) => f(buf),
+            Sink::Ring(r) => r.push(buf),
+            Sink::Host(f) => unsafe { f(buf.as_ptr(), buf.len()) },
+        }
+    }
+}
+
+// Keeps the last `capacity` bytes written, clones share the same buffer
+#[derive(Clone)]
+pub struct RingBuffer {
+    buf: Rc<RefCell<VecDeque<u8>>>,
+    capacity: usize,
+}
+
+impl RingBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            buf: Rc::new(RefCell::new(VecDeque::with_capacity(capacity))),
+            capacity,
+        }
+    }
+
+    pub fn contents(&self) -> Vec<u8> {
+        self.buf.borrow().iter().copied().collect()
+    }
+
+    pub fn take(&self) -> Vec<u8> {
+        self.buf.borrow_mut().drain(..).collect()
+    }
+
+    fn push(&self, data: &[u8]) {
+        let mut buf = self.buf.borrow_mut();
+
+        let data = &data[data.len().saturating_sub(self.capacity)..];
+        let excess = (buf.len() + data.len()).saturating_sub(self.capacity);
+        buf.drain(..excess);
+        buf.extend(data);
+    }
+}
+
+pub struct Options {
+    pub line_buffered: bool,
+    pub max_line: Option<usize>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            line_buffered: true,
+            max_line: None,
+        }
+    }
+}
+
+// Handles
+
+const RIGHTS_STDIO: Rights = wasi::RIGHTS_FD_FDSTAT_SET_FLAGS
+    | wasi::RIGHTS_FD_FILESTAT_GET
+    | wasi::RIGHTS_POLL_FD_READWRITE;
+
+struct Input {
+    src: Box<dyn Read>,
+}
+
+impl Handle for Input {
+    fn filetype(&self) -> Filetype {
+        wasi::FILETYPE_CHARACTER_DEVICE
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<Size, Errno> {
+        self.src.read(buf).map_err(fd::io_errno)
+    }
+}
+
+struct Output {
+    sink: Sink,
+    opts: Options,
+    pending: Vec<u8>,
+    // Length of the current line so far, including anything cut off by `max_line`
+    col: usize,
+}
+
+impl Output {
+    fn new(sink: Sink, opts: Options) -> Self {
+        Self {
+            sink,
+            opts,
+            pending: vec![],
+            col: 0,
+        }
+    }
+
+    fn flush(&mut self) {
+        if !self.pending.is_empty() {
+            self.sink.emit(&self.pending);
+            self.pending.clear();
+        }
+    }
+}
+
+impl Handle for Output {
+    fn filetype(&self) -> Filetype {
+        wasi::FILETYPE_CHARACTER_DEVICE
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<Size, Errno> {
+        for seg in buf.split_inclusive(|b| *b == b'\n') {
+            let (line, newline) = match seg.split_last() {
+                Some((b'\n', line)) => (line, true),
+                _ => (seg, false),
+            };
+
+            let room = match self.opts.max_line {
+                Some(max) => max.saturating_sub(self.col),
+                None => line.len(),
+            };
+            self.pending
+                .extend_from_slice(&line[..line.len().min(room)]);
+            self.col += line.len();
+
+            if newline {
+                self.pending.push(b'\n');
+                self.col = 0;
+
+                if self.opts.line_buffered {
+                    self.flush();
+                }
+            }
+        }
+
+        if !self.opts.line_buffered {
+            self.flush();
+        }
+
+        Ok(buf.len())
+    }
+
+    fn sync(&mut self) -> Result<(), Errno> {
+        self.flush();
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<(), Errno> {
+        self.sync()
+    }
+}
+
+// State
+
+static mut OUTPUTS: [Option<Rc<RefCell<Output>>>; 2] = [None, None];
+static mut HOOKED: bool = false;
+
+/// Serves fd 0 from `src`.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn set_stdin(src: impl Read + 'static) {
+    fd_table::ensure_installed();
+
+    let mut entry = Entry::new(Input { src: Box::new(src) });
+    entry.rights_base = RIGHTS_STDIO | wasi::RIGHTS_FD_READ;
+    entry.rights_inheriting = 0;
+
+    fd_table::insert_at(0, entry);
+}
+
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn set_stdout(sink: Sink, opts: Options) {
+    set_output(1, sink, opts);
+}
+
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn set_stderr(sink: Sink, opts: Options) {
+    set_output(2, sink, opts);
+}
+
+unsafe fn set_output(fd: Fd, sink: Sink, opts: Options) {
+    fd_table::ensure_installed();
+
+    if !HOOKED {
+        proc::at_exit(flush);
+        HOOKED = true;
+    }
+
+    let out = Rc::new(RefCell::new(Output::new(sink, opts)));
+    (*addr_of_mut!(OUTPUTS))[fd as usize - 1] = Some(out.clone());
+
+    fd_table::insert_at(
+        fd,
+        Entry {
+            handle: out,
+            flags: 0,
+            rights_base: RIGHTS_STDIO
+                | wasi::RIGHTS_FD_WRITE
+                | wasi::RIGHTS_FD_SYNC
+                | wasi::RIGHTS_FD_DATASYNC,
+            rights_inheriting: 0,
+        },
+    );
+}
+
+/// Writes out any partial lines still held back by line buffering. Runs on `proc_exit`.
+pub fn flush() {
+    for out in unsafe { (*addr_of!(OUTPUTS)).iter().flatten() } {
+        out.borrow_mut().flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasi::Iovec;
+
+    use super::*;
+    use crate::core::testing;
+
+    #[test]
+    fn test_line_buffering() -> Result<(), Errno> {
+        let ring = RingBuffer::new(64);
+        let mut out = Output::new(Sink::Ring(ring.clone()), Options::default());
+
+        out.write(b"hello ")?;
+        assert_eq!(ring.contents(), b"");
+
+        out.write(b"world\nbye")?;
+        assert_eq!(ring.take(), b"hello world\n");
+
+        out.close()?;
+        assert_eq!(ring.take(), b"bye");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_line() -> Result<(), Errno> {
+        let ring = RingBuffer::new(64);
+        let opts = Options {
+            line_buffered: false,
+            max_line: Some(4),
+        };
+        let mut out = Output::new(Sink::Ring(ring.clone()), opts);
+
+        out.write(b"abc")?;
+        out.write(b"defg\nhi\n")?;
+        assert_eq!(ring.contents(), b"abcd\nhi\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ring_buffer() {
+        let ring = RingBuffer::new(4);
+
+        ring.push(b"abc");
+        ring.push(b"de");
+        assert_eq!(ring.contents(), b"bcde");
+
+        ring.push(b"0123456");
+        assert_eq!(ring.contents(), b"3456");
+    }
+
+    #[test]
+    fn test_keeps_polyfills() {
+        let _polyfills = testing::Polyfills::lock();
+
+        fn notsup(_: Fd, _: *const Iovec, _: i32, _: *mut Size) -> Errno {
+            wasi::ERRNO_NOTSUP
+        }
+
+        let ring = RingBuffer::new(8);
+        let written = unsafe {
+            set_stdout(Sink::Ring(ring.clone()), Options::default());
+            fd::set::fd_write(notsup);
+            set_stderr(Sink::Ring(ring.clone()), Options::default());
+            set_stdin(&b""[..]);
+            fd::write(2, b"x")
+        };
+        assert_eq!(written, Err(wasi::ERRNO_NOTSUP));
+        assert_eq!(ring.contents(), b"");
+    }
+}
@@ -0,0 +1,515 @@
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    marker::PhantomData,
+    rc::Rc,
+    sync::{Mutex, MutexGuard},
+    thread,
+};
+
+use wasi::{Errno, Fd, Filesize, Filestat, Prestat, Size, Whence};
+
+use crate::core::{
+    layer::{self, Call, Layer},
+    mem, trace,
+};
+
+// Replies
+
+/// What an expected call hands back to the guest through its result pointers.
+pub trait Returns: Clone + 'static {
+    #[allow(clippy::missing_safety_doc)]
+    unsafe fn write(&self, call: &Call);
+}
+
+impl Returns for () {
+    unsafe fn write(&self, _call: &Call) {}
+}
+
+// New fds from path_open and sock_accept
+impl Returns for Fd {
+    unsafe fn write(&self, call: &Call) {
+        if let Call::PathOpen { rp0, .. } | Call::SockAccept { rp0, .. } = *call {
+            *rp0 = *self;
+        }
+    }
+}
+
+// Bytes written
+impl Returns for Size {
+    unsafe fn write(&self, call: &Call) {
+        if let Call::FdWrite { rp0, .. } | Call::FdPwrite { rp0, .. } | Call::SockSend { rp0, .. } =
+            *call
+        {
+            *rp0 = *self;
+        }
+    }
+}
+
+// Offsets from fd_seek and fd_tell
+impl Returns for Filesize {
+    unsafe fn write(&self, call: &Call) {
+        if let Call::FdSeek { rp0, .. } | Call::FdTell { rp0, .. } = *call {
+            *rp0 = *self;
+        }
+    }
+}
+
+impl Returns for Filestat {
+    unsafe fn write(&self, call: &Call) {
+        if let Call::FdFilestatGet { rp0, .. } | Call::PathFilestatGet { rp0, .. } = *call {
+            *rp0 = *self;
+        }
+    }
+}
+
+// Data read, cut short to fit the guest's buffers
+impl Returns for Vec<u8> {
+    unsafe fn write(&self, call: &Call) {
+        let (iovs, iovs_len, rp0) = match *call {
+            Call::FdRead {
+                iovs,
+                iovs_len,
+                rp0,
+                ..
+            } => (iovs, iovs_len, rp0),
+            Call::FdPread { iovs, len, rp0, .. } => (iovs, len, rp0),
+            Call::SockRecv {
+                ri_data,
+                ri_data_len,
+                rp0,
+                ..
+            } => (ri_data, ri_data_len, rp0),
+            _ => return,
+        };
+
+        let mut src = &self[..];
+        for buf in mem::bufs_mut(iovs, iovs_len) {
+            let n = buf.len().min(src.len());
+            buf[..n].copy_from_slice(&src[..n]);
+            src = &src[n..];
+        }
+
+        *rp0 = self.len() - src.len();
+    }
+}
+
+// Expectations
+
+type MatchFn = Box<dyn Fn(&Call, &Preopens) -> bool>;
+type ReplyFn = Box<dyn Fn(&Call) -> Errno>;
+
+struct Expectation {
+    desc: String,
+    matches: MatchFn,
+    reply: ReplyFn,
+    times: usize,
+}
+
+pub struct Expect<T> {
+    state: Rc<RefCell<State>>,
+    _reply: PhantomData<T>,
+}
+
+impl<T: Returns> Expect<T> {
+    /// Expects the call `n` times in a row instead of once.
+    pub fn times(self, n: usize) -> Self {
+        if let Some(e) = self.state.borrow_mut().expected.back_mut() {
+            e.times = n;
+        }
+        self
+    }
+
+    /// Sets what the call returns, which is success with nothing written by default.
+    pub fn returning(self, r: Result<T, Errno>) -> Self {
+        if let Some(e) = self.state.borrow_mut().expected.back_mut() {
+            e.reply = Box::new(move |call| match &r {
+                Ok(v) => {
+                    unsafe { v.write(call) };
+                    wasi::ERRNO_SUCCESS
+                }
+                Err(err) => *err,
+            });
+        }
+        self
+    }
+}
+
+// Guest paths of the directories the mock preopens, by fd
+#[derive(Default)]
+struct Preopens(Vec<(String, Fd)>);
+
+impl Preopens {
+    unsafe fn resolve(&self, fd: Fd, path: *const u8, path_len: i32) -> Option<String> {
+        let path = mem::str(path, path_len).ok()?;
+
+        match self.0.iter().find(|(_, f)| *f == fd) {
+            Some((dir, _)) if dir.ends_with('/') => Some(format!("{dir}{path}")),
+            Some((dir, _)) => Some(format!("{dir}/{path}")),
+            None => Some(path.to_string()),
+        }
+    }
+
+    // Answers the preopen scan made at startup, so tests need not expect it
+    unsafe fn prestat(&self, call: &Call) -> Option<Errno> {
+        match *call {
+            Call::FdPrestatGet { fd, rp0 } => Some(match self.0.iter().find(|(_, f)| *f == fd) {
+                Some((dir, _)) => {
+                    *rp0 = Prestat {
+                        tag: wasi::PREOPENTYPE_DIR.raw(),
+                        u: wasi::PrestatU {
+                            dir: wasi::PrestatDir {
+                                pr_name_len: dir.len(),
+                            },
+                        },
+                    };
+                    wasi::ERRNO_SUCCESS
+                }
+                None => wasi::ERRNO_BADF,
+            }),
+            Call::FdPrestatDirName { fd, path, path_len } => {
+                Some(match self.0.iter().find(|(_, f)| *f == fd) {
+                    Some((dir, _)) if dir.len() <= path_len => {
+                        mem::bytes_mut(path, dir.len()).copy_from_slice(dir.as_bytes());
+                        wasi::ERRNO_SUCCESS
+                    }
+                    Some(_) => wasi::ERRNO_NAMETOOLONG,
+                    None => wasi::ERRNO_BADF,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    preopens: Preopens,
+    expected: VecDeque<Expectation>,
+    failures: Vec<String>,
+}
+
+// Layer
+
+struct Mock(Rc<RefCell<State>>);
+
+impl Layer for Mock {
+    fn enter(&mut self, call: &Call) -> Option<Errno> {
+        let mut state = self.0.borrow_mut();
+        let State {
+            preopens,
+            expected,
+            failures,
+        } = &mut *state;
+
+        if let Some(errno) = unsafe { preopens.prestat(call) } {
+            return Some(errno);
+        }
+
+        let actual = trace::describe(call);
+        let Some(e) = expected.front_mut() else {
+            failures.push(format!("unexpected {actual}"));
+            return Some(wasi::ERRNO_NOTCAPABLE);
+        };
+
+        if !(e.matches)(call, preopens) {
+            failures.push(format!("expected {}, got {actual}", e.desc));
+            return Some(wasi::ERRNO_NOTCAPABLE);
+        }
+
+        let errno = (e.reply)(call);
+        e.times = e.times.saturating_sub(1);
+        if e.times == 0 {
+            expected.pop_front();
+        }
+
+        Some(errno)
+    }
+}
+
+// Mock
+
+// Shim calls go through global layers, so only one mock can be live at a time
+pub(crate) static LOCK: Mutex<()> = Mutex::new(());
+
+// Holds `LOCK` for a test that swaps polyfills, and puts the fd table's back when dropped, so
+// a failed assertion doesn't leak them into later tests
+#[cfg(test)]
+pub(crate) struct Polyfills {
+    _lock: MutexGuard<'static, ()>,
+}
+
+#[cfg(test)]
+impl Polyfills {
+    pub(crate) fn lock() -> Self {
+        Self {
+            _lock: LOCK.lock().unwrap_or_else(|e| e.into_inner()),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Drop for Polyfills {
+    fn drop(&mut self) {
+        unsafe { crate::core::fd_table::install() };
+    }
+}
+
+/// Serves every shim call from a list of expected calls, which must be made in order. Any
+/// call that was not expected fails with `NOTCAPABLE` and is reported by [`MockWasi::verify`],
+/// which also runs on drop.
+pub struct MockWasi {
+    state: Rc<RefCell<State>>,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl MockWasi {
+    /// Replaces any installed layers with the mock until it is dropped.
+    pub fn new() -> Self {
+        let lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let state = Rc::new(RefCell::new(State::default()));
+
+        unsafe {
+            layer::clear();
+            layer::push(Mock(state.clone()));
+        }
+
+        Self { state, _lock: lock }
+    }
+
+    /// Preopens `dir` as `fd`. Paths relative to it are matched as if joined to `dir`.
+    pub fn preopen(&mut self, dir: &str, fd: Fd) -> &mut Self {
+        self.state
+            .borrow_mut()
+            .preopens
+            .0
+            .push((dir.to_string(), fd));
+        self
+    }
+
+    fn expect<T>(
+        &mut self,
+        desc: String,
+        matches: impl Fn(&Call, &Preopens) -> bool + 'static,
+    ) -> Expect<T> {
+        self.state.borrow_mut().expected.push_back(Expectation {
+            desc,
+            matches: Box::new(matches),
+            reply: Box::new(|_| wasi::ERRNO_SUCCESS),
+            times: 1,
+        });
+
+        Expect {
+            state: self.state.clone(),
+            _reply: PhantomData,
+        }
+    }
+
+    /// Expects a call to the WASI function `name`, with any arguments.
+    pub fn expect_call(&mut self, name: &'static str) -> Expect<()> {
+        self.expect(name.to_string(), move |call, _| call.name() == name)
+    }
+
+    pub fn expect_fd_close(&mut self, fd: Fd) -> Expect<()> {
+        self.expect(
+            format!("fd_close({fd})"),
+            move |call, _| matches!(*call, Call::FdClose { fd: f } if f == fd),
+        )
+    }
+
+    pub fn expect_fd_filestat_get(&mut self, fd: Fd) -> Expect<Filestat> {
+        self.expect(
+            format!("fd_filestat_get({fd})"),
+            move |call, _| matches!(*call, Call::FdFilestatGet { fd: f, .. } if f == fd),
+        )
+    }
+
+    pub fn expect_fd_read(&mut self, fd: Fd) -> Expect<Vec<u8>> {
+        self.expect(
+            format!("fd_read({fd})"),
+            move |call, _| matches!(*call, Call::FdRead { fd: f, .. } if f == fd),
+        )
+    }
+
+    pub fn expect_fd_seek(&mut self, fd: Fd, offset: i64, whence: Whence) -> Expect<Filesize> {
+        self.expect(
+            format!("fd_seek({fd}, {offset}, {})", whence.name()),
+            move |call, _| {
+                matches!(*call, Call::FdSeek { fd: f, offset: o, whence: w, .. }
+                    if f == fd && o == offset && w == whence)
+            },
+        )
+    }
+
+    /// Expects `data` to be written to `fd` in one call.
+    pub fn expect_fd_write(&mut self, fd: Fd, data: &[u8]) -> Expect<Size> {
+        let data = data.to_vec();
+
+        self.expect(
+            format!("fd_write({fd}, {:?})", String::from_utf8_lossy(&data)),
+            move |call, _| match *call {
+                Call::FdWrite {
+                    fd: f,
+                    iovs,
+                    iovs_len,
+                    ..
+                } if f == fd => unsafe { mem::bufs(iovs, iovs_len) }
+                    .flatten()
+                    .copied()
+                    .eq(data.iter().copied()),
+                _ => false,
+            },
+        )
+    }
+
+    pub fn expect_path_create_directory(&mut self, path: &str) -> Expect<()> {
+        self.expect_path("path_create_directory", path)
+    }
+
+    pub fn expect_path_filestat_get(&mut self, path: &str) -> Expect<Filestat> {
+        self.expect_path("path_filestat_get", path)
+    }
+
+    pub fn expect_path_open(&mut self, path: &str) -> Expect<Fd> {
+        self.expect_path("path_open", path)
+    }
+
+    pub fn expect_path_remove_directory(&mut self, path: &str) -> Expect<()> {
+        self.expect_path("path_remove_directory", path)
+    }
+
+    pub fn expect_path_unlink_file(&mut self, path: &str) -> Expect<()> {
+        self.expect_path("path_unlink_file", path)
+    }
+
+    fn expect_path<T>(&mut self, name: &'static str, path: &str) -> Expect<T> {
+        let path = path.to_string();
+
+        self.expect(format!("{name}({path:?})"), move |call, preopens| {
+            let (fd, p, len) = match *call {
+                Call::PathCreateDirectory { fd, path, path_len }
+                | Call::PathRemoveDirectory { fd, path, path_len }
+                | Call::PathUnlinkFile { fd, path, path_len }
+                | Call::PathFilestatGet {
+                    fd, path, path_len, ..
+                }
+                | Call::PathOpen {
+                    fd, path, path_len, ..
+                } => (fd, path, path_len),
+                _ => return false,
+            };
+
+            call.name() == name
+                && unsafe { preopens.resolve(fd, p, len) }.as_deref() == Some(path.as_str())
+        })
+    }
+
+    /// Panics if a call was unexpected or an expected call was not made.
+    pub fn verify(&self) {
+        let state = self.state.borrow();
+
+        let mut problems = state.failures.clone();
+        problems.extend(state.expected.iter().map(|e| format!("missing {}", e.desc)));
+
+        if !problems.is_empty() {
+            panic!(
+                "mock WASI calls did not match:\n  {}",
+                problems.join("\n  ")
+            );
+        }
+    }
+}
+
+impl Default for MockWasi {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for MockWasi {
+    fn drop(&mut self) {
+        unsafe { layer::clear() };
+
+        if !thread::panicking() {
+            self.verify();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use wasi::{Iovec, Lookupflags, Oflags, Rights};
+
+    use super::*;
+
+    extern "C" {
+        fn __shim_path_open(
+            fd: Fd,
+            dirflags: Lookupflags,
+            path: *const u8,
+            path_len: i32,
+            oflags: Oflags,
+            fs_rights_base: Rights,
+            fs_rights_inheriting: Rights,
+            fdflags: wasi::Fdflags,
+            rp0: *mut Fd,
+        ) -> Errno;
+        fn __shim_fd_read(fd: Fd, iovs: *const Iovec, iovs_len: i32, rp0: *mut Size) -> Errno;
+        fn __shim_fd_close(fd: Fd) -> Errno;
+    }
+
+    unsafe fn open(dir: Fd, path: &str) -> Result<Fd, Errno> {
+        let mut fd = 0;
+        let ret = __shim_path_open(
+            dir,
+            0,
+            path.as_ptr(),
+            path.len() as i32,
+            0,
+            0,
+            0,
+            0,
+            &mut fd,
+        );
+
+        match ret {
+            wasi::ERRNO_SUCCESS => Ok(fd),
+            err => Err(err),
+        }
+    }
+
+    #[test]
+    fn test_mock() {
+        let mut mock = MockWasi::new();
+        mock.preopen("/data", 3);
+        mock.expect_path_open("/data/x").returning(Ok(4));
+        mock.expect_fd_read(4).returning(Ok(b"hello".to_vec()));
+        mock.expect_fd_close(4);
+
+        unsafe {
+            assert_eq!(open(3, "x"), Ok(4));
+
+            let mut buf = [0; 8];
+            let iov = Iovec {
+                buf: buf.as_mut_ptr(),
+                buf_len: 3,
+            };
+            let mut n = 0;
+            assert_eq!(__shim_fd_read(4, &iov, 1, &mut n), wasi::ERRNO_SUCCESS);
+            assert_eq!(&buf[..n], b"hel");
+
+            assert_eq!(__shim_fd_close(4), wasi::ERRNO_SUCCESS);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "expected path_open(\"/data/x\"), got path_open(3, 0, \"y\"")]
+    fn test_mock_mismatch() {
+        let mut mock = MockWasi::new();
+        mock.preopen("/data", 3);
+        mock.expect_path_open("/data/x")
+            .returning(Err(wasi::ERRNO_NOENT));
+
+        assert_eq!(unsafe { open(3, "y") }, Err(wasi::ERRNO_NOTCAPABLE));
+    }
+}
@@ -0,0 +1,415 @@
+use wasi::{Errno, Iovec};
+
+use crate::core::{
+    fd,
+    layer::{self, Call, Layer},
+    mem,
+};
+
+// Layer
+
+struct Trace<F: FnMut(&str)> {
+    sink: F,
+}
+
+impl<F: FnMut(&str)> Layer for Trace<F> {
+    fn enter(&mut self, call: &Call) -> Option<Errno> {
+        // There is no return to wait for
+        if let Call::ProcExit { .. } = call {
+            (self.sink)(&describe(call));
+        }
+
+        None
+    }
+
+    fn exit(&mut self, call: &Call, ret: Errno) {
+        let line = match ret {
+            wasi::ERRNO_SUCCESS => match unsafe { result(call) } {
+                Some(r) => format!("{} = {r}", describe(call)),
+                None => format!("{} = {}", describe(call), ret.name()),
+            },
+            _ => format!("{} = {} ({})", describe(call), ret.name(), ret.raw()),
+        };
+
+        (self.sink)(&line);
+    }
+}
+
+/// Logs every shim call, along with what it returned, as one line to `sink`.
+#[allow(clippy::missing_safety_doc)]
+pub unsafe fn install(sink: impl FnMut(&str) + 'static) {
+    layer::push(Trace { sink });
+}
+
+/// A sink writing each line to stderr through the registered `fd_write` polyfill, so the trace
+/// itself is not traced.
+pub fn stderr(line: &str) {
+    let _ = fd::write(2, format!("{line}\n").as_bytes());
+}
+
+// Decoding
+
+pub(crate) fn describe(call: &Call) -> String {
+    format!("{}({})", call.name(), unsafe { args(call) }.join(", "))
+}
+
+unsafe fn args(call: &Call) -> Vec<String> {
+    match *call {
+        Call::ClockResGet { id, .. } => vec![id.name().to_string()],
+        Call::ClockTimeGet { id, precision, .. } => {
+            vec![id.name().to_string(), precision.to_string()]
+        }
+        Call::FdAdvise {
+            fd,
+            offset,
+            len,
+            advice,
+        } => vec![
+            fd.to_string(),
+            offset.to_string(),
+            len.to_string(),
+            advice.name().to_string(),
+        ],
+        Call::FdAllocate { fd, offset, len } => {
+            vec![fd.to_string(), offset.to_string(), len.to_string()]
+        }
+        Call::FdFdstatSetFlags { fd, flags } => vec![fd.to_string(), fdflags(flags)],
+        Call::FdFdstatSetRights {
+            fd,
+            fs_rights_base,
+            fs_rights_inheriting,
+        } => vec![
+            fd.to_string(),
+            format!("{fs_rights_base:#x}"),
+            format!("{fs_rights_inheriting:#x}"),
+        ],
+        Call::FdFilestatSetSize { fd, size } => vec![fd.to_string(), size.to_string()],
+        Call::FdFilestatSetTimes {
+            fd,
+            atim,
+            mtim,
+            fst_flags,
+        } => vec![
+            fd.to_string(),
+            atim.to_string(),
+            mtim.to_string(),
+            fstflags(fst_flags),
+        ],
+        Call::FdPread {
+            fd,
+            iovs,
+            len,
+            offset,
+            ..
+        } => vec![fd.to_string(), iovecs(iovs, len), offset.to_string()],
+        Call::FdPwrite {
+            fd,
+            iovs,
+            iovs_len,
+            offset,
+            ..
+        } => vec![fd.to_string(), iovecs(iovs, iovs_len), offset.to_string()],
+        Call::FdRead {
+            fd, iovs, iovs_len, ..
+        }
+        | Call::FdWrite {
+            fd, iovs, iovs_len, ..
+        } => vec![fd.to_string(), iovecs(iovs, iovs_len)],
+        Call::FdReaddir {
+            fd,
+            buf_len,
+            cookie,
+            ..
+        } => vec![fd.to_string(), buf_len.to_string(), cookie.to_string()],
+        Call::FdRenumber { fd, to } => vec![fd.to_string(), to.to_string()],
+        Call::FdSeek {
+            fd, offset, whence, ..
+        } => vec![
+            fd.to_string(),
+            offset.to_string(),
+            whence.name().to_string(),
+        ],
+        Call::FdClose { fd }
+        | Call::FdDatasync { fd }
+        | Call::FdFdstatGet { fd, .. }
+        | Call::FdFilestatGet { fd, .. }
+        | Call::FdPrestatDirName { fd, .. }
+        | Call::FdPrestatGet { fd, .. }
+        | Call::FdSync { fd }
+        | Call::FdTell { fd, .. } => vec![fd.to_string()],
+        Call::PathCreateDirectory { fd, path, path_len }
+        | Call::PathRemoveDirectory { fd, path, path_len }
+        | Call::PathUnlinkFile { fd, path, path_len }
+        | Call::PathReadlink {
+            fd, path, path_len, ..
+        } => vec![fd.to_string(), str(path, path_len)],
+        Call::PathFilestatGet {
+            fd,
+            flags,
+            path,
+            path_len,
+            ..
+        } => vec![fd.to_string(), lookupflags(flags), str(path, path_len)],
+        Call::PathFilestatSetTimes {
+            fd,
+            flags,
+            path,
+            path_len,
+            atim,
+            mtim,
+            fst_flags,
+        } => vec![
+            fd.to_string(),
+            lookupflags(flags),
+            str(path, path_len),
+            atim.to_string(),
+            mtim.to_string(),
+            fstflags(fst_flags),
+        ],
+        Call::PathLink {
+            old_fd,
+            old_flags,
+            old_path,
+            old_path_len,
+            new_fd,
+            new_path,
+            new_path_len,
+        } => vec![
+            old_fd.to_string(),
+            lookupflags(old_flags),
+            str(old_path, old_path_len),
+            new_fd.to_string(),
+            str(new_path, new_path_len),
+        ],
+        Call::PathOpen {
+            fd,
+            dirflags,
+            path,
+            path_len,
+            oflags,
+            fs_rights_base,
+            fs_rights_inheriting,
+            fdflags: flags,
+            ..
+        } => vec![
+            fd.to_string(),
+            lookupflags(dirflags),
+            str(path, path_len),
+            self::oflags(oflags),
+            format!("{fs_rights_base:#x}"),
+            format!("{fs_rights_inheriting:#x}"),
+            fdflags(flags),
+        ],
+        Call::PathRename {
+            fd,
+            old_path,
+            old_path_len,
+            new_fd,
+            new_path,
+            new_path_len,
+        } => vec![
+            fd.to_string(),
+            str(old_path, old_path_len),
+            new_fd.to_string(),
+            str(new_path, new_path_len),
+        ],
+        Call::PathSymlink {
+            old_path,
+            old_path_len,
+            fd,
+            new_path,
+            new_path_len,
+        } => vec![
+            str(old_path, old_path_len),
+            fd.to_string(),
+            str(new_path, new_path_len),
+        ],
+        Call::PollOneoff { nsubscriptions, .. } => vec![nsubscriptions.to_string()],
+        Call::ProcExit { rval } => vec![rval.to_string()],
+        Call::ProcRaise { sig } => vec![sig.name().to_string()],
+        Call::RandomGet { buf_len, .. } => vec![buf_len.to_string()],
+        Call::SockAccept { fd, flags, .. } => vec![fd.to_string(), fdflags(flags)],
+        Call::SockRecv {
+            fd,
+            ri_data,
+            ri_data_len,
+            ri_flags,
+            ..
+        } => vec![
+            fd.to_string(),
+            iovecs(ri_data, ri_data_len),
+            names(
+                ri_flags as u64,
+                &[
+                    (wasi::RIFLAGS_RECV_PEEK as u64, "RECV_PEEK"),
+                    (wasi::RIFLAGS_RECV_WAITALL as u64, "RECV_WAITALL"),
+                ],
+            ),
+        ],
+        Call::SockSend {
+            fd,
+            si_data,
+            si_data_len,
+            ..
+        } => vec![fd.to_string(), iovecs(si_data as *const Iovec, si_data_len)],
+        Call::SockShutdown { fd, how } => vec![
+            fd.to_string(),
+            names(
+                how as u64,
+                &[
+                    (wasi::SDFLAGS_RD as u64, "RD"),
+                    (wasi::SDFLAGS_WR as u64, "WR"),
+                ],
+            ),
+        ],
+        Call::ArgsGet { .. }
+        | Call::ArgsSizesGet { .. }
+        | Call::EnvironGet { .. }
+        | Call::EnvironSizesGet { .. }
+        | Call::SchedYield => vec![],
+    }
+}
+
+// What a successful call handed back through its result pointers, where that is worth showing
+unsafe fn result(call: &Call) -> Option<String> {
+    match *call {
+        Call::ClockResGet { rp0, .. } | Call::ClockTimeGet { rp0, .. } => Some((*rp0).to_string()),
+        Call::FdPread { rp0, .. }
+        | Call::FdPwrite { rp0, .. }
+        | Call::FdRead { rp0, .. }
+        | Call::FdReaddir { rp0, .. }
+        | Call::FdWrite { rp0, .. }
+        | Call::PathReadlink { rp0, .. }
+        | Call::PollOneoff { rp0, .. }
+        | Call::SockRecv { rp0, .. }
+        | Call::SockSend { rp0, .. } => Some((*rp0).to_string()),
+        Call::FdSeek { rp0, .. } | Call::FdTell { rp0, .. } => Some((*rp0).to_string()),
+        Call::PathOpen { rp0, .. } | Call::SockAccept { rp0, .. } => Some(format!("fd {}", *rp0)),
+        _ => None,
+    }
+}
+
+unsafe fn str(buf: *const u8, len: i32) -> String {
+    match mem::str(buf, len) {
+        Ok(s) => format!("{s:?}"),
+        Err(_) => format!("<{len} bytes>"),
+    }
+}
+
+unsafe fn iovecs(iovs: *const Iovec, iovs_len: i32) -> String {
+    let lens: Vec<String> = mem::iovecs(iovs, iovs_len)
+        .iter()
+        .map(|iov| iov.buf_len.to_string())
+        .collect();
+
+    format!("[{}]", lens.join(", "))
+}
+
+fn names(flags: u64, known: &[(u64, &str)]) -> String {
+    let mut out: Vec<String> = known
+        .iter()
+        .filter(|(bit, _)| flags & bit != 0)
+        .map(|(_, name)| name.to_string())
+        .collect();
+
+    let rest = known.iter().fold(flags, |f, (bit, _)| f & !bit);
+    if rest != 0 {
+        out.push(format!("{rest:#x}"));
+    }
+
+    match out.is_empty() {
+        true => "0".to_string(),
+        false => out.join("|"),
+    }
+}
+
+fn fdflags(flags: wasi::Fdflags) -> String {
+    names(
+        flags as u64,
+        &[
+            (wasi::FDFLAGS_APPEND as u64, "APPEND"),
+            (wasi::FDFLAGS_DSYNC as u64, "DSYNC"),
+            (wasi::FDFLAGS_NONBLOCK as u64, "NONBLOCK"),
+            (wasi::FDFLAGS_RSYNC as u64, "RSYNC"),
+            (wasi::FDFLAGS_SYNC as u64, "SYNC"),
+        ],
+    )
+}
+
+fn fstflags(flags: wasi::Fstflags) -> String {
+    names(
+        flags as u64,
+        &[
+            (wasi::FSTFLAGS_ATIM as u64, "ATIM"),
+            (wasi::FSTFLAGS_ATIM_NOW as u64, "ATIM_NOW"),
+            (wasi::FSTFLAGS_MTIM as u64, "MTIM"),
+            (wasi::FSTFLAGS_MTIM_NOW as u64, "MTIM_NOW"),
+        ],
+    )
+}
+
+fn lookupflags(flags: wasi::Lookupflags) -> String {
+    names(
+        flags as u64,
+        &[(wasi::LOOKUPFLAGS_SYMLINK_FOLLOW as u64, "SYMLINK_FOLLOW")],
+    )
+}
+
+fn oflags(flags: wasi::Oflags) -> String {
+    names(
+        flags as u64,
+        &[
+            (wasi::OFLAGS_CREAT as u64, "CREAT"),
+            (wasi::OFLAGS_DIRECTORY as u64, "DIRECTORY"),
+            (wasi::OFLAGS_EXCL as u64, "EXCL"),
+            (wasi::OFLAGS_TRUNC as u64, "TRUNC"),
+        ],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use wasi::Ciovec;
+
+    use super::*;
+
+    #[test]
+    fn test_describe() {
+        let path = "a/b.txt";
+        let call = Call::PathOpen {
+            fd: 3,
+            dirflags: wasi::LOOKUPFLAGS_SYMLINK_FOLLOW,
+            path: path.as_ptr(),
+            path_len: path.len() as i32,
+            oflags: wasi::OFLAGS_CREAT | wasi::OFLAGS_TRUNC,
+            fs_rights_base: 0x40,
+            fs_rights_inheriting: 0,
+            fdflags: 0,
+            rp0: &mut 5,
+        };
+
+        assert_eq!(
+            describe(&call),
+            r#"path_open(3, SYMLINK_FOLLOW, "a/b.txt", CREAT|TRUNC, 0x40, 0x0, 0)"#
+        );
+        assert_eq!(unsafe { result(&call) }.as_deref(), Some("fd 5"));
+
+        let data = [Ciovec {
+            buf: b"hi".as_ptr(),
+            buf_len: 2,
+        }; 2];
+        let call = Call::FdWrite {
+            fd: 1,
+            iovs: data.as_ptr() as *const Iovec,
+            iovs_len: 2,
+            rp0: &mut 4,
+        };
+        assert_eq!(describe(&call), "fd_write(1, [2, 2])");
+    }
+
+    #[test]
+    fn test_names() {
+        assert_eq!(fdflags(0), "0");
+        assert_eq!(fdflags(wasi::FDFLAGS_APPEND | 0x40), "APPEND|0x40");
+    }
+}
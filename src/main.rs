@@ -1,11 +1,21 @@
-use std::{borrow::BorrowMut, collections::HashMap, path::PathBuf, sync::Arc};
+use std::{
+    borrow::BorrowMut,
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-use anyhow::{Context, Error};
+use anyhow::{anyhow, Context, Error};
 use clap::Parser;
 use walrus::{
-    ir::{Instr, InstrSeqId},
+    ir::{
+        BinaryOp, Const, ExtendedLoad, Instr, InstrSeqId, LoadKind, MemArg, StoreKind, UnaryOp,
+        Value,
+    },
     passes::gc,
-    ElementItems, ExportItem, FunctionId, FunctionKind, ImportKind, LocalFunction, Module,
+    ConstExpr, DataKind, ElementItems, ExportItem, FunctionBuilder, FunctionId, FunctionKind,
+    ImportKind, LocalFunction, MemoryId, Module, ValType,
 };
 
 #[derive(Parser)]
@@ -15,12 +25,46 @@ struct Cli {
 
     #[arg(short, long)]
     pub output: Option<PathBuf>,
+
+    /// Bake a host directory into the module as a read-only preopen, e.g. /assets=./assets
+    #[arg(long = "embed-dir", value_name = "GUEST=HOST", value_parser = parse_mapping)]
+    pub embed_dirs: Vec<(String, PathBuf)>,
+
+    /// Bake an argument into the module, in order and starting with the program name
+    #[arg(long = "arg", value_name = "ARG")]
+    pub args: Vec<String>,
+
+    /// Bake an environment variable into the module
+    #[arg(long = "env", value_name = "KEY=VALUE", value_parser = parse_env)]
+    pub env: Vec<String>,
+}
+
+fn parse_mapping(s: &str) -> Result<(String, PathBuf), String> {
+    match s.split_once('=') {
+        Some((guest, host)) if guest.starts_with('/') && !host.is_empty() => {
+            Ok((guest.to_string(), PathBuf::from(host)))
+        }
+        _ => Err(format!(
+            "expected GUEST=HOST with an absolute guest path, got {s:?}"
+        )),
+    }
+}
+
+fn parse_env(s: &str) -> Result<String, String> {
+    match s.split_once('=') {
+        Some((key, _)) if !key.is_empty() => Ok(s.to_string()),
+        _ => Err(format!("expected KEY=VALUE, got {s:?}")),
+    }
 }
 
 fn main() -> Result<(), Error> {
     let cli = Cli::parse();
 
     let s = StripSeq(vec![
+        Arc::new(EmbedDirs(cli.embed_dirs)),
+        Arc::new(BakeStrings("args", cli.args)),
+        Arc::new(BakeStrings("environ", cli.env)),
+        Arc::new(ExitReturn),
         Arc::new(CallReplace(vec!["__shim_".to_string()])),
         Arc::new(StartEntry),
         Arc::new(StartExport),
@@ -40,8 +84,10 @@ fn main() -> Result<(), Error> {
 }
 
 const PREFIX_INIT: &str = "_initialize";
+const PREFIX_START: &str = "_start";
 const PREFIX_P1: &str = "wasi_snapshot_preview1";
 const PREFIX_UNSTABLE: &str = "wasi_unstable";
+const EMBEDDED_TABLE: &str = "__wasi_shim_embedded";
 
 trait Strip: Send + Sync {
     fn strip(&self, m: &mut Module) -> Result<(), Error>;
@@ -101,26 +147,31 @@ impl Strip for CallReplace {
             })
             .collect();
 
-        m.elements.iter_mut().for_each(|el| {
-            if let ElementItems::Functions(fids) = el.items.borrow_mut() {
-                for fid in fids {
-                    if let Some(rid) = rids.get(fid) {
-                        *fid = *rid;
-                    }
-                }
-            }
-        });
-
-        m.funcs.iter_mut().for_each(|f| {
-            if let FunctionKind::Local(f) = &mut f.kind {
-                process_block(f, f.entry_block(), &rids);
-            }
-        });
+        replace_calls(m, &rids);
 
         Ok(())
     }
 }
 
+// Points every call and table entry for a function in `rids` at its replacement
+fn replace_calls(m: &mut Module, rids: &HashMap<FunctionId, FunctionId>) {
+    m.elements.iter_mut().for_each(|el| {
+        if let ElementItems::Functions(fids) = el.items.borrow_mut() {
+            for fid in fids {
+                if let Some(rid) = rids.get(fid) {
+                    *fid = *rid;
+                }
+            }
+        }
+    });
+
+    m.funcs.iter_mut().for_each(|f| {
+        if let FunctionKind::Local(f) = &mut f.kind {
+            process_block(f, f.entry_block(), rids);
+        }
+    });
+}
+
 fn process_block(f: &mut LocalFunction, id: InstrSeqId, rids: &HashMap<FunctionId, FunctionId>) {
     let mut ids = vec![];
 
@@ -164,6 +215,357 @@ fn process_block(f: &mut LocalFunction, id: InstrSeqId, rids: &HashMap<FunctionI
     }
 }
 
+struct EmbedDirs(
+    Vec<(String, PathBuf)>, // Guest mount points and host directories
+);
+
+impl Strip for EmbedDirs {
+    fn strip(&self, m: &mut Module) -> Result<(), Error> {
+        if self.0.is_empty() {
+            return Ok(());
+        }
+
+        let fid = m
+            .funcs
+            .by_name(EMBEDDED_TABLE)
+            .ok_or_else(|| anyhow!("module has no {EMBEDDED_TABLE} to point at embedded files"))?;
+
+        let mut rows = vec![];
+        for (guest, host) in self.0.iter() {
+            let guest = guest.trim_end_matches('/');
+            let guest = if guest.is_empty() { "/" } else { guest };
+
+            rows.push((guest.to_string(), String::new(), None));
+            list_dir(host, "", &mut |path, data| {
+                rows.push((guest.to_string(), path, data))
+            })
+            .with_context(|| format!("failed to read {}", host.display()))?;
+        }
+
+        let (_, base) = append_data(m, |base| table(&rows, base))?;
+
+        // Point the placeholder at the table
+        let f = match &mut m.funcs.get_mut(fid).kind {
+            FunctionKind::Local(f) => f,
+            _ => return Err(anyhow!("{EMBEDDED_TABLE} is not a local function")),
+        };
+        let entry = f.entry_block();
+        f.block_mut(entry).instrs.clear();
+        f.builder_mut().instr_seq(entry).i32_const(base as i32);
+
+        Ok(())
+    }
+}
+
+// Places the bytes made by `build` for a given base address past the end of the initial
+// memory, which the allocator only reaches by growing it. The base is never 0, so it can't be
+// mistaken for a null pointer
+fn append_data(
+    m: &mut Module,
+    build: impl FnOnce(u64) -> Result<Vec<u8>, Error>,
+) -> Result<(MemoryId, u64), Error> {
+    let mem = m.memories.iter().next().context("module has no memory")?;
+    if mem.memory64 || mem.page_size_log2.is_some() {
+        return Err(anyhow!("baked data needs a 32-bit memory with 64KiB pages"));
+    }
+    let (mid, pages) = (mem.id(), mem.initial.max(1));
+
+    let base = pages * 65536;
+    let bytes = build(base)?;
+
+    let mem = m.memories.get_mut(mid);
+    mem.initial = pages + (bytes.len() as u64).div_ceil(65536);
+    if mem.maximum.is_some_and(|max| max < mem.initial) {
+        return Err(anyhow!("baked data exceeds the maximum memory size"));
+    }
+
+    let did = m.data.add(
+        DataKind::Active {
+            memory: mid,
+            offset: ConstExpr::Value(Value::I32(base as i32)),
+        },
+        bytes,
+    );
+    m.memories.get_mut(mid).data_segments.insert(did);
+
+    Ok((mid, base))
+}
+
+// Lists `dir` in name order, with `None` for directories
+fn list_dir(
+    dir: &Path,
+    prefix: &str,
+    f: &mut impl FnMut(String, Option<Vec<u8>>),
+) -> Result<(), Error> {
+    let mut entries: Vec<_> = fs::read_dir(dir)?.collect::<Result<_, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+
+    for e in entries {
+        let name = e
+            .file_name()
+            .into_string()
+            .map_err(|name| anyhow!("file name {name:?} is not UTF-8"))?;
+        let path = format!("{prefix}{name}");
+
+        if e.path().is_dir() {
+            f(path.clone(), None);
+            list_dir(&e.path(), &format!("{path}/"), f)?;
+        } else {
+            f(path, Some(fs::read(e.path())?));
+        }
+    }
+
+    Ok(())
+}
+
+// Lays out the table read by `core::embedded::injected` at address `base`: a count, a row of
+// six words per entry, then the strings and file contents the rows point at
+fn table(rows: &[(String, String, Option<Vec<u8>>)], base: u64) -> Result<Vec<u8>, Error> {
+    let header = 4 + rows.len() * 24;
+    let mut words = vec![rows.len() as u64];
+    let mut heap = vec![];
+
+    let push = |heap: &mut Vec<u8>, bytes: &[u8]| {
+        let at = base + (header + heap.len()) as u64;
+        heap.extend_from_slice(bytes);
+        [at, bytes.len() as u64]
+    };
+
+    for (guest, path, data) in rows {
+        words.extend(push(&mut heap, guest.as_bytes()));
+        words.extend(push(&mut heap, path.as_bytes()));
+        words.extend(match data {
+            Some(data) => push(&mut heap, data),
+            None => [0, 0],
+        });
+    }
+
+    let mut out = Vec::with_capacity(header + heap.len());
+    for w in words {
+        let w = u32::try_from(w).map_err(|_| anyhow!("embedded files exceed 4GiB"))?;
+        out.extend_from_slice(&w.to_le_bytes());
+    }
+    out.extend(heap);
+
+    Ok(out)
+}
+
+struct BakeStrings(
+    &'static str, // Function family, "args" or "environ"
+    Vec<String>,  // Strings
+);
+
+impl Strip for BakeStrings {
+    fn strip(&self, m: &mut Module) -> Result<(), Error> {
+        if self.1.is_empty() {
+            return Ok(());
+        }
+
+        let (get, sizes_get) = (format!("{}_get", self.0), format!("{}_sizes_get", self.0));
+
+        let imps: Vec<(String, FunctionId)> = m
+            .imports
+            .iter()
+            .filter(|i| [PREFIX_P1, PREFIX_UNSTABLE].contains(&i.module.as_ref()))
+            .filter(|i| i.name == get || i.name == sizes_get)
+            .flat_map(|i| match i.kind {
+                ImportKind::Function(id) => Some((i.name.to_owned(), id)),
+                _ => None,
+            })
+            .collect();
+
+        if imps.is_empty() {
+            return Ok(());
+        }
+        for (name, fid) in imps.iter() {
+            let ty = m.types.get(m.funcs.get(*fid).ty());
+            if ty.params() != [ValType::I32, ValType::I32] || ty.results() != [ValType::I32] {
+                return Err(anyhow!("import {name} has an unexpected type"));
+            }
+        }
+
+        // Strings are laid out as the guest expects them in its buffer, each ending in NUL
+        let mut buf = vec![];
+        let mut offsets = vec![];
+        for s in self.1.iter() {
+            offsets.push(buf.len() as i32);
+            buf.extend_from_slice(s.as_bytes());
+            buf.push(0);
+        }
+
+        let len = buf.len() as i32;
+        let (mid, base) = append_data(m, |_| Ok(buf))?;
+
+        let word = |offset| MemArg { align: 4, offset };
+        let params = [ValType::I32, ValType::I32];
+
+        // (rp0, rp1): the string count and the buffer size
+        let mut f = FunctionBuilder::new(&mut m.types, &params, &[ValType::I32]);
+        let (rp0, rp1) = (m.locals.add(ValType::I32), m.locals.add(ValType::I32));
+        f.name(format!("__baked_{sizes_get}"))
+            .func_body()
+            .local_get(rp0)
+            .i32_const(offsets.len() as i32)
+            .store(mid, StoreKind::I32 { atomic: false }, word(0))
+            .local_get(rp1)
+            .i32_const(len)
+            .store(mid, StoreKind::I32 { atomic: false }, word(0))
+            .i32_const(0);
+        let sizes_fid = f.finish(vec![rp0, rp1], &mut m.funcs);
+
+        // (ptrs, buf): copy the strings into buf, then point each of ptrs at one of them
+        let mut f = FunctionBuilder::new(&mut m.types, &params, &[ValType::I32]);
+        let (ptrs, out, i) = (
+            m.locals.add(ValType::I32),
+            m.locals.add(ValType::I32),
+            m.locals.add(ValType::I32),
+        );
+        let mut body = f.name(format!("__baked_{get}")).func_body();
+        body.block(None, |done| {
+            let done_id = done.id();
+            done.loop_(None, |copy| {
+                let copy_id = copy.id();
+                copy.local_get(i)
+                    .i32_const(len)
+                    .binop(BinaryOp::I32GeU)
+                    .br_if(done_id)
+                    .local_get(out)
+                    .local_get(i)
+                    .binop(BinaryOp::I32Add)
+                    .local_get(i)
+                    .load(
+                        mid,
+                        LoadKind::I32_8 {
+                            kind: ExtendedLoad::ZeroExtend,
+                        },
+                        MemArg {
+                            align: 1,
+                            offset: base as u32,
+                        },
+                    )
+                    .store(
+                        mid,
+                        StoreKind::I32_8 { atomic: false },
+                        MemArg {
+                            align: 1,
+                            offset: 0,
+                        },
+                    )
+                    .local_get(i)
+                    .i32_const(1)
+                    .binop(BinaryOp::I32Add)
+                    .local_set(i)
+                    .br(copy_id);
+            });
+        });
+        for (n, offset) in offsets.iter().enumerate() {
+            body.local_get(ptrs)
+                .local_get(out)
+                .i32_const(*offset)
+                .binop(BinaryOp::I32Add)
+                .store(mid, StoreKind::I32 { atomic: false }, word(n as u32 * 4));
+        }
+        body.i32_const(0);
+        let get_fid = f.finish(vec![ptrs, out], &mut m.funcs);
+
+        let rids: HashMap<FunctionId, FunctionId> = imps
+            .iter()
+            .map(|(name, fid)| match *name == get {
+                true => (*fid, get_fid),
+                false => (*fid, sizes_fid),
+            })
+            .collect();
+        replace_calls(m, &rids);
+
+        Ok(())
+    }
+}
+
+struct ExitReturn;
+
+impl Strip for ExitReturn {
+    fn strip(&self, m: &mut Module) -> Result<(), Error> {
+        // Functions that end the process without any further cleanup
+        let exits: Vec<FunctionId> = m
+            .funcs
+            .iter()
+            .filter(|f| match &f.kind {
+                FunctionKind::Import(i) => {
+                    let i = m.imports.get(i.import);
+                    [PREFIX_P1, PREFIX_UNSTABLE].contains(&i.module.as_ref())
+                        && i.name == "proc_exit"
+                }
+                _ => f.name.as_ref().is_some_and(|name| {
+                    ["__wasi_proc_exit", "__shim_proc_exit"].contains(&name.as_ref())
+                }),
+            })
+            .map(|f| f.id())
+            .collect();
+
+        // Find entrypoint
+        let fid = m.exports.iter().find_map(|e| match e.item {
+            ExportItem::Function(fid) if e.name == PREFIX_START => Some(fid),
+            _ => None,
+        });
+
+        let Some(fid) = fid else {
+            return Ok(());
+        };
+
+        let f = match &mut m.funcs.get_mut(fid).kind {
+            FunctionKind::Local(f) => f,
+            _ => return Ok(()),
+        };
+
+        let entry = f.entry_block();
+        let instrs = &mut f.block_mut(entry).instrs;
+
+        // Look past trailing traps, which only go once the call before them is an exit
+        let Some(last) = instrs
+            .iter()
+            .rposition(|(i, _)| !matches!(i, Instr::Unreachable(_)))
+        else {
+            return Ok(());
+        };
+
+        let exit = match &instrs[last] {
+            (Instr::Call(i), _) if exits.contains(&i.func) => i.func,
+            _ => return Ok(()),
+        };
+        instrs.truncate(last);
+
+        // A constant zero exit code is dropped along with the call
+        if let Some((
+            Instr::Const(Const {
+                value: Value::I32(0),
+            }),
+            _,
+        )) = instrs.last()
+        {
+            instrs.pop();
+            return Ok(());
+        }
+
+        // Otherwise return when the exit code turns out to be zero at runtime
+        let t = m.locals.add(ValType::I32);
+        f.builder_mut()
+            .instr_seq(entry)
+            .local_tee(t)
+            .unop(UnaryOp::I32Eqz)
+            .if_else(
+                None,
+                |then| {
+                    then.return_();
+                },
+                |_| {},
+            )
+            .local_get(t)
+            .call(exit);
+
+        Ok(())
+    }
+}
+
 struct StartEntry;
 
 impl Strip for StartEntry {
@@ -222,7 +624,11 @@ mod tests {
     use anyhow::{anyhow, Error};
     use walrus::Module;
 
-    use crate::{CallReplace, StartEntry, StartExport, Strip, Unused};
+    use walrus::{ir::Instr, ExportItem, FunctionKind};
+
+    use crate::{
+        BakeStrings, CallReplace, EmbedDirs, ExitReturn, StartEntry, StartExport, Strip, Unused,
+    };
 
     #[test]
     fn test_add_start_entry() -> Result<(), Error> {
@@ -310,4 +716,184 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_exit_return() -> Result<(), Error> {
+        let wat = r#"
+            (module
+                (import "wasi_snapshot_preview1" "proc_exit"
+                    (func $proc_exit (param i32)))
+
+                (func $main (result i32) i32.const 3)
+
+                (func $_start
+                    i32.const 0
+                    call $proc_exit
+                    unreachable
+                )
+
+                (func $_start_dynamic
+                    call $main
+                    call $proc_exit
+                )
+
+                (export "_start" (func $_start))
+            )
+        "#;
+
+        let bs = wat::parse_str(wat)?;
+        let mut m = Module::from_buffer(&bs)?;
+
+        ExitReturn.strip(&mut m)?;
+
+        let body = |m: &Module, name| match &m.funcs.by_name(name).map(|fid| &m.funcs.get(fid).kind)
+        {
+            Some(FunctionKind::Local(f)) => f.block(f.entry_block()).instrs.len(),
+            _ => 0,
+        };
+        assert_eq!(body(&m, "_start"), 0);
+
+        // Point the export at the dynamic variant, which keeps the call behind a zero check
+        let fid = m.funcs.by_name("_start_dynamic").unwrap();
+        let eid = m.exports.iter().find(|e| e.name == "_start").unwrap().id();
+        m.exports.get_mut(eid).item = ExportItem::Function(fid);
+
+        ExitReturn.strip(&mut m)?;
+
+        let FunctionKind::Local(f) = &m.funcs.get(fid).kind else {
+            return Err(anyhow!("_start_dynamic is not local"));
+        };
+        let instrs = &f.block(f.entry_block()).instrs;
+        assert!(matches!(instrs[3].0, Instr::IfElse(_)));
+        assert!(matches!(instrs.last().unwrap().0, Instr::Call(_)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_embed_dirs() -> Result<(), Error> {
+        let wat = r#"
+            (module
+                (memory 1)
+                (func $__wasi_shim_embedded (result i32) i32.const 0)
+            )
+        "#;
+
+        let dir = std::env::temp_dir().join(format!("wasi-shim-embed-cli-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("sub"))?;
+        std::fs::write(dir.join("a.txt"), b"hello")?;
+
+        let bs = wat::parse_str(wat)?;
+        let mut m = Module::from_buffer(&bs)?;
+
+        let ret = EmbedDirs(vec![("/assets".to_string(), dir.clone())]).strip(&mut m);
+        let _ = std::fs::remove_dir_all(&dir);
+        ret?;
+
+        let mem = m.memories.iter().next().unwrap();
+        assert_eq!(mem.initial, 2);
+
+        // Rows for the mount point itself, "a.txt" and "sub"
+        let data = m.data.iter().next().unwrap();
+        assert_eq!(&data.value[..4], &3u32.to_le_bytes());
+        assert!(data.value.ends_with(b"/assetssub"));
+
+        let fid = m.funcs.by_name("__wasi_shim_embedded").unwrap();
+        let FunctionKind::Local(f) = &m.funcs.get(fid).kind else {
+            return Err(anyhow!("__wasi_shim_embedded is not local"));
+        };
+        let instrs = &f.block(f.entry_block()).instrs;
+        assert!(matches!(
+            instrs[0].0,
+            Instr::Const(walrus::ir::Const {
+                value: walrus::ir::Value::I32(65536)
+            })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bake_strings() -> Result<(), Error> {
+        let wat = r#"
+            (module
+                (import "wasi_snapshot_preview1" "args_sizes_get"
+                    (func $args_sizes_get (param i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "args_get"
+                    (func $args_get (param i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "environ_get"
+                    (func $environ_get (param i32 i32) (result i32)))
+
+                (memory 1)
+                (func $main (export "main")
+                    (drop (call $args_sizes_get (i32.const 0) (i32.const 4)))
+                    (drop (call $args_get (i32.const 8) (i32.const 16)))
+                    (drop (call $environ_get (i32.const 8) (i32.const 16)))
+                )
+            )
+        "#;
+
+        let bs = wat::parse_str(wat)?;
+        let mut m = Module::from_buffer(&bs)?;
+
+        BakeStrings("args", vec!["prog".to_string(), "-v".to_string()]).strip(&mut m)?;
+        BakeStrings("environ", vec![]).strip(&mut m)?;
+        Unused.strip(&mut m)?;
+
+        assert!(m
+            .imports
+            .find("wasi_snapshot_preview1", "args_get")
+            .is_none());
+        assert!(m
+            .imports
+            .find("wasi_snapshot_preview1", "args_sizes_get")
+            .is_none());
+        assert!(m
+            .imports
+            .find("wasi_snapshot_preview1", "environ_get")
+            .is_some());
+
+        let data = m.data.iter().next().unwrap();
+        assert_eq!(data.value, b"prog\0-v\0");
+        assert!(m.funcs.by_name("__baked_args_get").is_some());
+        assert_eq!(m.memories.iter().next().unwrap().initial, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_exit_return_keeps_traps() -> Result<(), Error> {
+        let wat = r#"
+            (module
+                (func $f (param i32))
+
+                (func $_start
+                    i32.const 1
+                    call $f
+                    unreachable
+                )
+
+                (export "_start" (func $_start))
+            )
+        "#;
+
+        let bs = wat::parse_str(wat)?;
+        let mut m = Module::from_buffer(&bs)?;
+
+        ExitReturn.strip(&mut m)?;
+
+        let fid = m.funcs.by_name("_start").unwrap();
+        let FunctionKind::Local(f) = &m.funcs.get(fid).kind else {
+            return Err(anyhow!("_start is not local"));
+        };
+        let instrs = &f.block(f.entry_block()).instrs;
+        assert_eq!(instrs.len(), 3);
+        assert!(matches!(instrs[2].0, Instr::Unreachable(_)));
+
+        // The module still validates once emitted
+        Module::from_buffer(&m.emit_wasm())?;
+
+        Ok(())
+    }
 }